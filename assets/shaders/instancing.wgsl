@@ -6,6 +6,27 @@ struct InstanceUniforms {
 //@group(3) @binding(0) var<uniform> instance_uniforms: InstanceUniforms;
 // @group(0) @binding(100) var<uniform> instance_uniforms: InstanceUniforms;
 
+// gates centred outside the box or in front of any plane are dropped
+struct Clip {
+    min: vec3<f32>,
+    max: vec3<f32>,
+    planes: array<vec4<f32>, 4>,
+    plane_count: u32,
+};
+@group(2) @binding(0) var<uniform> clip: Clip;
+
+fn clipped(center: vec3<f32>) -> bool {
+    if any(center < clip.min) || any(center > clip.max) {
+        return true;
+    }
+    for (var i = 0u; i < clip.plane_count; i++) {
+        if dot(clip.planes[i].xyz, center) > clip.planes[i].w {
+            return true;
+        }
+    }
+    return false;
+}
+
 
 
 struct Vertex {
@@ -34,8 +55,17 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     let position = vertex.position;// * vertex.i_pos_scale.w + vertex.i_pos_scale.xyz;
     var out: VertexOutput;
 
+    let model = transform * get_model_matrix(0u);
+    let center = (model * vec4<f32>(0.0, 0.0, 0.0, 1.0)).xyz;
+    if clipped(center) {
+        // every vertex outside the view volume, so the gate is culled whole
+        out.clip_position = vec4<f32>(0.0, 0.0, 2.0, 1.0);
+        out.color = vec4<f32>(0.0);
+        return out;
+    }
+
     out.clip_position = mesh_position_local_to_clip(
-        transform * get_model_matrix(0u),
+        model,
         vec4<f32>(vertex.position, 1.0)
     );
     out.color = vec4(vertex.i_color.rgb, pow(vertex.i_color.a, vertex.i_alpha));
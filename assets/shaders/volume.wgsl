@@ -0,0 +1,59 @@
+#import bevy_pbr::forward_io::VertexOutput
+#import bevy_pbr::mesh_view_bindings::view
+
+struct VolumeSettings {
+    bounds_min: vec3<f32>,
+    alpha_power: f32,
+    bounds_max: vec3<f32>,
+    steps: u32,
+    reference_step: f32,
+};
+
+@group(2) @binding(0) var<uniform> settings: VolumeSettings;
+@group(2) @binding(1) var volume_texture: texture_3d<f32>;
+@group(2) @binding(2) var volume_sampler: sampler;
+@group(2) @binding(3) var transfer_texture: texture_2d<f32>;
+@group(2) @binding(4) var transfer_sampler: sampler;
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let origin = view.world_position;
+    let direction = normalize(in.world_position.xyz - origin);
+
+    // slab test against the volume bounds
+    let inverse = 1.0 / direction;
+    let t0 = (settings.bounds_min - origin) * inverse;
+    let t1 = (settings.bounds_max - origin) * inverse;
+    let near = min(t0, t1);
+    let far = max(t0, t1);
+    let t_start = max(max(max(near.x, near.y), near.z), 0.0);
+    let t_end = min(min(far.x, far.y), far.z);
+    if t_end <= t_start {
+        discard;
+    }
+
+    let step_length = (t_end - t_start) / f32(settings.steps);
+    let extent = settings.bounds_max - settings.bounds_min;
+    var color = vec3<f32>(0.0);
+    var alpha = 0.0;
+    for (var i = 0u; i < settings.steps; i++) {
+        let p = origin + direction * (t_start + (f32(i) + 0.5) * step_length);
+        let voxel = textureSampleLevel(volume_texture, volume_sampler, (p - settings.bounds_min) / extent, 0.0);
+        let transfer = textureSampleLevel(transfer_texture, transfer_sampler, vec2<f32>(voxel.r, 0.5), 0.0);
+
+        // same shaping as the instanced gates, corrected for the step length
+        let a = pow(max(transfer.a, 1e-6), settings.alpha_power) * voxel.g;
+        let a_step = 1.0 - pow(1.0 - a, step_length / settings.reference_step);
+
+        color += (1.0 - alpha) * a_step * transfer.rgb;
+        alpha += (1.0 - alpha) * a_step;
+        if alpha > 0.99 {
+            break;
+        }
+    }
+
+    if alpha <= 0.001 {
+        discard;
+    }
+    return vec4<f32>(color / alpha, alpha);
+}
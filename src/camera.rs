@@ -0,0 +1,325 @@
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+use bevy::prelude::*;
+use bevy_panorbit_camera::PanOrbitCamera;
+use serde::{Deserialize, Serialize};
+use crate::input::{Action, Actions};
+use crate::scale::SceneScale;
+use crate::session::Session;
+use crate::views::ViewPanel;
+
+/// Seconds taken to fly to a preset or bookmark.
+const FLIGHT_SECONDS: f32 = 2.0;
+/// Seconds spent on each bookmark when touring them without a recorded path.
+const TOUR_SECONDS: f32 = 4.0;
+/// Seconds between keyframes recorded one after another.
+const KEYFRAME_SECONDS: f32 = 4.0;
+
+/// Where an orbit camera looks from, in `PanOrbitCamera` terms: yaw about up from the +z (east) side,
+/// pitch above the focus, and distance from it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CameraPose {
+    pub focus: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub radius: f32,
+}
+
+impl CameraPose {
+    pub fn of(camera: &PanOrbitCamera) -> Self {
+        Self {
+            focus: camera.target_focus,
+            yaw: camera.target_yaw,
+            pitch: camera.target_pitch,
+            radius: camera.target_radius,
+        }
+    }
+
+    /// Move the camera straight there, skipping its own smoothing so paths play back exactly.
+    pub fn apply(&self, camera: &mut PanOrbitCamera) {
+        camera.focus = self.focus;
+        camera.yaw = Some(self.yaw);
+        camera.pitch = Some(self.pitch);
+        camera.radius = Some(self.radius);
+        camera.target_focus = self.focus;
+        camera.target_yaw = self.yaw;
+        camera.target_pitch = self.pitch;
+        camera.target_radius = self.radius;
+        camera.force_update = true;
+    }
+
+    /// Send the camera towards the pose, leaving its own smoothing to get there.
+    pub fn aim(&self, camera: &mut PanOrbitCamera) {
+        camera.target_focus = self.focus;
+        camera.target_yaw = self.yaw;
+        camera.target_pitch = self.pitch;
+        camera.target_radius = self.radius;
+    }
+
+    /// Part way to `other`, turning the shorter way round and zooming evenly in scale.
+    pub fn lerp(&self, other: &CameraPose, t: f32) -> Self {
+        let turn = (other.yaw - self.yaw + PI).rem_euclid(TAU) - PI;
+        Self {
+            focus: self.focus.lerp(other.focus, t),
+            yaw: self.yaw + turn * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+            radius: self.radius * (other.radius / self.radius).powf(t),
+        }
+    }
+
+    /// Looking straight down on the radar with north up.
+    pub fn top_down() -> Self {
+        Self {
+            focus: Vec3::ZERO,
+            yaw: -FRAC_PI_2,
+            pitch: FRAC_PI_2 - 0.001,
+            radius: 120_000.0,
+        }
+    }
+
+    /// Looking north at the radar from just above the ground to the south of it.
+    pub fn from_south() -> Self {
+        Self {
+            focus: Vec3::new(0.0, 3_000.0, 0.0),
+            yaw: -FRAC_PI_2,
+            pitch: 0.05,
+            radius: 100_000.0,
+        }
+    }
+
+    /// From the radar out along the beam at `azimuth` (clockwise from north) and `elevation`, in radians.
+    /// Poses are in the stretched space of the camera rig, so the elevation is the apparent one.
+    pub fn up_beam(azimuth: f32, elevation: f32) -> Self {
+        let radius = 60_000.0;
+        let beam = Vec3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin());
+        Self {
+            focus: beam * radius,
+            yaw: (-azimuth.cos()).atan2(-azimuth.sin()),
+            pitch: -elevation,
+            radius,
+        }
+    }
+
+    /// The same view once heights are stretched `exaggeration` times: the focus rises and the camera keeps
+    /// its place above it, so the pitch steepens and the radius grows. `1 / exaggeration` undoes it.
+    pub fn stretched(&self, exaggeration: f32) -> Self {
+        let (sin, cos) = self.pitch.sin_cos();
+        Self {
+            focus: Vec3::new(self.focus.x, self.focus.y * exaggeration, self.focus.z),
+            yaw: self.yaw,
+            pitch: (sin * exaggeration).atan2(cos),
+            radius: self.radius * (cos * cos + sin * sin * exaggeration * exaggeration).sqrt(),
+        }
+    }
+
+    /// Azimuth the camera faces, clockwise from north.
+    pub fn azimuth(&self) -> f32 {
+        (-self.yaw.cos()).atan2(-self.yaw.sin())
+    }
+}
+
+/// A named pose, in real metres so it holds whatever the exaggeration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub pose: CameraPose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Seconds from the start of the path.
+    pub time: f32,
+    /// In real metres when saved with the session, in the stretched space of the rig when played.
+    pub pose: CameraPose,
+}
+
+/// The pose along `keyframes` at `time`, eased in and out between neighbours and held past either end.
+pub fn sample(keyframes: &[Keyframe], time: f32) -> Option<CameraPose> {
+    let i = keyframes.partition_point(|keyframe| keyframe.time <= time);
+    match i {
+        _ if keyframes.is_empty() => None,
+        0 => Some(keyframes[0].pose),
+        i if i == keyframes.len() => Some(keyframes[i - 1].pose),
+        i => {
+            let (a, b) = (keyframes[i - 1], keyframes[i]);
+            let t = ((time - a.time) / (b.time - a.time)).clamp(0.0, 1.0);
+            Some(a.pose.lerp(&b.pose, t * t * (3.0 - 2.0 * t)))
+        }
+    }
+}
+
+/// Keyframes the camera is following, from a fly-to or a recorded path. Driven by frame time, so a
+/// headless capture stepping `Time` by a fixed amount per frame follows the same path.
+#[derive(Resource, Default)]
+pub struct CameraAnimation {
+    pub keyframes: Vec<Keyframe>,
+    pub elapsed: f32,
+}
+
+impl CameraAnimation {
+    pub fn fly(&mut self, from: CameraPose, to: CameraPose) {
+        self.play(vec![
+            Keyframe { time: 0.0, pose: from },
+            Keyframe { time: FLIGHT_SECONDS, pose: to },
+        ]);
+    }
+
+    pub fn play(&mut self, keyframes: Vec<Keyframe>) {
+        self.keyframes = keyframes;
+        self.elapsed = 0.0;
+    }
+}
+
+/// Add `pose`, seen with heights stretched `exaggeration` times, to the end of the session's camera path,
+/// `KEYFRAME_SECONDS` after the last keyframe.
+pub fn record_keyframe(session: &mut Session, pose: CameraPose, exaggeration: f32) {
+    let time = session.camera_path.last().map_or(0.0, |keyframe| keyframe.time + KEYFRAME_SECONDS);
+    session.camera_path.push(Keyframe { time, pose: pose.stretched(1.0 / exaggeration) });
+}
+
+/// The recorded path from the session, or a tour of the bookmarks from `from` when there isn't one,
+/// stretched for playback at `exaggeration`.
+pub fn session_path(session: &Session, from: CameraPose, exaggeration: f32) -> Vec<Keyframe> {
+    let stretched = |time: f32, pose: CameraPose| Keyframe { time, pose: pose.stretched(exaggeration) };
+    if !session.camera_path.is_empty() {
+        return session.camera_path.iter().map(|keyframe| stretched(keyframe.time, keyframe.pose)).collect();
+    }
+    let mut keyframes = vec![Keyframe { time: 0.0, pose: from }];
+    for (i, bookmark) in session.bookmarks.iter().enumerate() {
+        let time = (i + 1) as f32 * TOUR_SECONDS;
+        keyframes.push(stretched(time - FLIGHT_SECONDS, bookmark.pose));
+        keyframes.push(stretched(time, bookmark.pose));
+    }
+    keyframes
+}
+
+pub fn animate_camera(
+    time: Res<Time>,
+    mut animation: ResMut<CameraAnimation>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+) {
+    let Some(end) = animation.keyframes.last().map(|keyframe| keyframe.time) else {
+        return;
+    };
+    animation.elapsed += time.delta_seconds();
+    if let Some(pose) = sample(&animation.keyframes, animation.elapsed) {
+        for mut camera in cameras.iter_mut() {
+            pose.apply(&mut camera);
+        }
+    }
+    if animation.elapsed >= end {
+        animation.keyframes.clear();
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut next: Local<usize>,
+    mut animation: ResMut<CameraAnimation>,
+    mut session: ResMut<Session>,
+    scale: Res<SceneScale>,
+    cameras: Query<(&PanOrbitCamera, &ViewPanel)>,
+) {
+    let Some((camera, _)) = cameras.iter().find(|(_, panel)| panel.0 == 0) else {
+        return;
+    };
+    let current = CameraPose::of(camera);
+
+    if actions.just_pressed(Action::CameraTopDown) {
+        animation.fly(current, CameraPose::top_down());
+    }
+    if actions.just_pressed(Action::CameraFromSouth) {
+        animation.fly(current, CameraPose::from_south());
+    }
+    if actions.just_pressed(Action::CameraUpBeam) {
+        animation.fly(current, CameraPose::up_beam(current.azimuth(), scale.apparent_elevation(0.5f32.to_radians())));
+    }
+    if actions.just_pressed(Action::AddBookmark) {
+        let name = format!("Bookmark {}", session.bookmarks.len() + 1);
+        session.bookmarks.push(Bookmark { name, pose: current.stretched(1.0 / scale.exaggeration) });
+    }
+    if actions.just_pressed(Action::NextBookmark) && !session.bookmarks.is_empty() {
+        *next %= session.bookmarks.len();
+        animation.fly(current, session.bookmarks[*next].pose.stretched(scale.exaggeration));
+        *next += 1;
+    }
+    if actions.just_pressed(Action::PlayCameraPath) {
+        animation.play(session_path(&session, current, scale.exaggeration));
+    }
+    if actions.just_pressed(Action::RecordKeyframe) {
+        record_keyframe(&mut session, current, scale.exaggeration);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::PI;
+    use std::time::Duration;
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::math::Vec3;
+    use bevy::prelude::{Time, World};
+    use bevy_panorbit_camera::PanOrbitCamera;
+    use crate::camera::{animate_camera, record_keyframe, sample, CameraAnimation, CameraPose, Keyframe};
+    use crate::session::Session;
+
+    #[test]
+    fn test_path_eases_between_keyframes_and_turns_the_short_way() {
+        let pose = |yaw: f32, radius: f32| CameraPose { focus: Vec3::ZERO, yaw, pitch: 0.0, radius };
+        let keyframes = [
+            Keyframe { time: 1.0, pose: pose(PI - 0.1, 1000.0) },
+            Keyframe { time: 3.0, pose: pose(-PI + 0.1, 4000.0) },
+        ];
+        assert_eq!(sample(&keyframes, 0.0), Some(keyframes[0].pose));
+        assert_eq!(sample(&keyframes, 5.0), Some(keyframes[1].pose));
+
+        let middle = sample(&keyframes, 2.0).unwrap();
+        assert!((middle.yaw - PI).abs() < 1e-4);
+        assert!((middle.radius - 2000.0).abs() < 1e-1);
+        assert!(sample(&[], 0.0).is_none());
+    }
+
+    #[test]
+    fn test_animation_follows_fixed_time_steps() {
+        let pose = |radius: f32| CameraPose { focus: Vec3::ZERO, yaw: 0.0, pitch: 0.0, radius };
+        let mut session = Session::default();
+        record_keyframe(&mut session, pose(1000.0), 1.0);
+        record_keyframe(&mut session, pose(4000.0), 1.0);
+        assert_eq!(session.camera_path.iter().map(|keyframe| keyframe.time).collect::<Vec<_>>(), vec![0.0, 4.0]);
+
+        let mut world = World::new();
+        let camera = world.spawn(PanOrbitCamera::default()).id();
+        world.insert_resource(Time::<()>::default());
+        let mut animation = CameraAnimation::default();
+        animation.play(session.camera_path.clone());
+        world.insert_resource(animation);
+
+        // half way along in eight steps of a quarter second
+        let radii: Vec<_> = (0..8).map(|_| {
+            world.resource_mut::<Time>().advance_by(Duration::from_millis(250));
+            world.run_system_once(animate_camera);
+            world.get::<PanOrbitCamera>(camera).unwrap().radius.unwrap()
+        }).collect();
+        assert!(radii.windows(2).all(|pair| pair[1] > pair[0]));
+        assert!((radii[7] - 2000.0).abs() < 1.0);
+
+        // the path is dropped once played through, leaving the camera at its end
+        for _ in 0..8 {
+            world.resource_mut::<Time>().advance_by(Duration::from_millis(250));
+            world.run_system_once(animate_camera);
+        }
+        assert!(world.resource::<CameraAnimation>().keyframes.is_empty());
+        assert_eq!(world.get::<PanOrbitCamera>(camera).unwrap().radius, Some(4000.0));
+    }
+
+    #[test]
+    fn test_stretching_keeps_the_camera_over_the_same_point() {
+        let pose = CameraPose { focus: Vec3::new(5000.0, 2000.0, -3000.0), yaw: 0.3, pitch: 0.4, radius: 50_000.0 };
+        let eye = |pose: &CameraPose| pose.focus + pose.radius * Vec3::new(pose.pitch.cos() * pose.yaw.sin(), pose.pitch.sin(), pose.pitch.cos() * pose.yaw.cos());
+
+        let stretched = pose.stretched(4.0);
+        let (real, seen) = (eye(&pose), eye(&stretched));
+        assert!((seen - Vec3::new(real.x, real.y * 4.0, real.z)).length() < 0.1);
+
+        let back = stretched.stretched(0.25);
+        assert!((back.focus - pose.focus).length() < 1e-2 && (back.pitch - pose.pitch).abs() < 1e-5 && (back.radius - pose.radius).abs() < 1e-2);
+    }
+}
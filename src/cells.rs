@@ -0,0 +1,460 @@
+use std::collections::BTreeMap;
+use std::f32::consts::TAU;
+use bevy::prelude::*;
+use chrono::{DateTime, TimeDelta, Utc};
+use crate::colortable::ColorTables;
+use crate::input::{Action, Actions};
+use crate::radar::Scan;
+use crate::scan::{ScanInfo, ScanType};
+use crate::volume::{Volume, VolumeLoaded, Volumes};
+
+/// Reflectivity is capped here before integrating VIL so hail doesn't dominate.
+const VIL_MAX_DBZ: f32 = 56.0;
+
+#[derive(Resource, Debug, Clone)]
+pub struct CellSettings {
+    pub enabled: bool,
+    pub threshold: f32,
+    /// Smallest 2-D component kept, in gates.
+    pub min_gates: usize,
+    /// Components on consecutive sweeps closer than this horizontally belong to the same cell.
+    pub vertical_distance: f32,
+    pub min_components: usize,
+    /// Fastest storm motion considered when matching cells between volumes, in m/s.
+    pub max_speed: f32,
+    /// Matching distance allowed on top of `max_speed`, for centroid jitter.
+    pub match_slack: f32,
+    /// Number of past positions used for the motion estimate.
+    pub motion_history: usize,
+    pub forecast_minutes: Vec<i64>,
+}
+
+impl Default for CellSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 40.0,
+            min_gates: 20,
+            vertical_distance: 5000.0,
+            min_components: 2,
+            max_speed: 40.0,
+            match_slack: 3000.0,
+            motion_history: 5,
+            forecast_minutes: vec![15, 30, 45, 60],
+        }
+    }
+}
+
+/// A 2-D reflectivity component on one sweep.
+#[derive(Debug, Clone)]
+struct Component2d {
+    centroid: Vec3,
+    mass: f32,
+    max_dbz: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct StormCell {
+    pub time: DateTime<Utc>,
+    /// Reflectivity-mass weighted centroid.
+    pub centroid: Vec3,
+    pub base: f32,
+    pub top: f32,
+    pub max_dbz: f32,
+    /// Vertically integrated liquid, kg m^-2.
+    pub vil: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CellTrack {
+    pub id: usize,
+    pub cells: Vec<StormCell>,
+    /// Horizontal motion in m/s, zero until the cell has been seen twice.
+    pub motion: Vec3,
+}
+
+impl CellTrack {
+    /// The latest cell observed at or before `time`.
+    pub fn at(&self, time: DateTime<Utc>) -> Option<&StormCell> {
+        self.cells.iter().rev().find(|cell| cell.time <= time)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct StormCells {
+    pub cells: BTreeMap<DateTime<Utc>, Vec<StormCell>>,
+    pub tracks: Vec<CellTrack>,
+}
+
+impl StormCells {
+    /// Mean motion of the tracked cells present in the latest volume at or before `time`.
+    pub fn mean_motion(&self, time: DateTime<Utc>) -> Option<Vec3> {
+        let (latest, _) = self.cells.range(..=time).next_back()?;
+        let moving: Vec<_> = self.tracks.iter()
+            .filter(|track| track.motion != Vec3::ZERO && track.at(time).is_some_and(|cell| cell.time == *latest))
+            .map(|track| track.motion)
+            .collect();
+        if moving.is_empty() {
+            return None;
+        }
+        Some(moving.iter().sum::<Vec3>() / moving.len() as f32)
+    }
+}
+
+/// Label 8-connected gates at or above `threshold`, wrapping in azimuth when the sweep is a full circle.
+fn components(scan: &Scan, settings: &CellSettings) -> Vec<Component2d> {
+    let rays = scan.meta.ray_count;
+    let bins = scan.meta.bin_count;
+    let full_circle = scan.meta.angular_resolution.abs() * rays as f32 >= TAU * 0.95;
+    let mut labels = vec![usize::MAX; rays * bins];
+    let mut result = Vec::new();
+
+    for start in 0..rays * bins {
+        if labels[start] != usize::MAX || !(scan.gates[start].reflectivity >= settings.threshold) {
+            continue;
+        }
+
+        let label = result.len();
+        labels[start] = label;
+        let mut stack = vec![start];
+        let mut count = 0;
+        let mut weighted = Vec3::ZERO;
+        let mut mass = 0.0;
+        let mut max_dbz = f32::MIN;
+        while let Some(index) = stack.pop() {
+            let gate = &scan.gates[index];
+            let z = 10f32.powf(gate.reflectivity / 10.0);
+            weighted += gate.as_cart() * z;
+            mass += z;
+            max_dbz = max_dbz.max(gate.reflectivity);
+            count += 1;
+
+            let (ray, bin) = ((index / bins) as isize, (index % bins) as isize);
+            for dr in -1..=1 {
+                for db in -1..=1 {
+                    let (mut r, b) = (ray + dr, bin + db);
+                    if b < 0 || b >= bins as isize {
+                        continue;
+                    }
+                    if r < 0 || r >= rays as isize {
+                        if !full_circle {
+                            continue;
+                        }
+                        r = r.rem_euclid(rays as isize);
+                    }
+                    let neighbour = r as usize * bins + b as usize;
+                    if labels[neighbour] == usize::MAX && scan.gates[neighbour].reflectivity >= settings.threshold {
+                        labels[neighbour] = label;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+
+        // keep the label slot even for rejected components so labels stay unique
+        result.push((count >= settings.min_gates).then(|| Component2d {
+            centroid: weighted / mass,
+            mass,
+            max_dbz,
+        }));
+    }
+
+    result.into_iter().flatten().collect()
+}
+
+/// Stack per-sweep components into 3-D cells, lowest sweep first.
+pub fn identify(volume: &Volume, settings: &CellSettings) -> Vec<StormCell> {
+    let mut sweeps: Vec<_> = volume.scans.iter().collect();
+    sweeps.sort_by(|a, b| a.elevation().total_cmp(&b.elevation()));
+    let time = sweeps.first().map(|scan| scan.meta.start_time).unwrap_or(volume.start_time);
+
+    let mut stacks: Vec<Vec<Component2d>> = Vec::new();
+    for scan in sweeps {
+        for component in components(scan, settings) {
+            let horizontal = |c: &Component2d| Vec2::new(c.centroid.x, c.centroid.z);
+            let nearest = stacks.iter_mut()
+                .map(|stack| {
+                    let top = stack.last().unwrap();
+                    (horizontal(top).distance(horizontal(&component)), stack)
+                })
+                .filter(|(distance, _)| *distance <= settings.vertical_distance)
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            match nearest {
+                Some((_, stack)) => stack.push(component),
+                None => stacks.push(vec![component]),
+            }
+        }
+    }
+
+    stacks.into_iter()
+        .filter(|stack| stack.len() >= settings.min_components)
+        .map(|mut stack| {
+            stack.sort_by(|a, b| a.centroid.y.total_cmp(&b.centroid.y));
+            let mass: f32 = stack.iter().map(|c| c.mass).sum();
+            let centroid = stack.iter().map(|c| c.centroid * c.mass).sum::<Vec3>() / mass;
+
+            let vil = stack.windows(2)
+                .map(|pair| {
+                    let z = |c: &Component2d| 10f32.powf(c.max_dbz.min(VIL_MAX_DBZ) / 10.0);
+                    let mean = (z(&pair[0]) + z(&pair[1])) / 2.0;
+                    3.44e-6 * mean.powf(4.0 / 7.0) * (pair[1].centroid.y - pair[0].centroid.y)
+                })
+                .sum();
+
+            StormCell {
+                time,
+                centroid,
+                base: stack.first().unwrap().centroid.y,
+                top: stack.last().unwrap().centroid.y,
+                max_dbz: stack.iter().map(|c| c.max_dbz).fold(f32::MIN, f32::max),
+                vil,
+            }
+        })
+        .collect()
+}
+
+/// Link cells between successive volumes by nearest predicted position.
+pub fn track(cells: &BTreeMap<DateTime<Utc>, Vec<StormCell>>, settings: &CellSettings) -> Vec<CellTrack> {
+    let mut tracks: Vec<CellTrack> = Vec::new();
+    let mut active: Vec<usize> = Vec::new();
+    let mut previous_time: Option<DateTime<Utc>> = None;
+
+    for (time, volume_cells) in cells.iter() {
+        let dt = previous_time.map(|t| (*time - t).num_milliseconds() as f32 / 1000.0).unwrap_or(0.0);
+        let reach = settings.max_speed * dt + settings.match_slack;
+
+        let mut pairs = Vec::new();
+        for (c, cell) in volume_cells.iter().enumerate() {
+            for &t in active.iter() {
+                let track = &tracks[t];
+                let last = track.cells.last().unwrap();
+                let predicted = last.centroid + track.motion * dt;
+                let distance = Vec2::new(predicted.x - cell.centroid.x, predicted.z - cell.centroid.z).length();
+                if distance <= reach {
+                    pairs.push((distance, c, t));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut cell_used = vec![false; volume_cells.len()];
+        let mut track_used = vec![false; tracks.len()];
+        let mut next_active = Vec::new();
+        for (_, c, t) in pairs {
+            if cell_used[c] || track_used[t] {
+                continue;
+            }
+            cell_used[c] = true;
+            track_used[t] = true;
+            tracks[t].cells.push(volume_cells[c].clone());
+            tracks[t].motion = motion(&tracks[t].cells, settings.motion_history);
+            next_active.push(t);
+        }
+
+        for (c, cell) in volume_cells.iter().enumerate() {
+            if cell_used[c] {
+                continue;
+            }
+            next_active.push(tracks.len());
+            tracks.push(CellTrack {
+                id: tracks.len(),
+                cells: vec![cell.clone()],
+                motion: Vec3::ZERO,
+            });
+        }
+
+        active = next_active;
+        previous_time = Some(*time);
+    }
+
+    tracks
+}
+
+/// Least-squares horizontal velocity over the last `history` positions.
+fn motion(cells: &[StormCell], history: usize) -> Vec3 {
+    let recent = &cells[cells.len().saturating_sub(history)..];
+    if recent.len() < 2 {
+        return Vec3::ZERO;
+    }
+
+    let t0 = recent[0].time;
+    let times: Vec<f32> = recent.iter().map(|c| (c.time - t0).num_milliseconds() as f32 / 1000.0).collect();
+    let mean_t = times.iter().sum::<f32>() / times.len() as f32;
+    let mean_p = recent.iter().map(|c| c.centroid).sum::<Vec3>() / recent.len() as f32;
+    let mut numerator = Vec3::ZERO;
+    let mut denominator = 0.0;
+    for (t, cell) in times.iter().zip(recent) {
+        numerator += (cell.centroid - mean_p) * (t - mean_t);
+        denominator += (t - mean_t) * (t - mean_t);
+    }
+    if denominator <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let velocity = numerator / denominator;
+    Vec3::new(velocity.x, 0.0, velocity.z)
+}
+
+/// Identify cells in newly loaded volumes, then re-track the whole sequence.
+pub fn update_cells(
+    mut events: EventReader<VolumeLoaded>,
+    mut storm_cells: ResMut<StormCells>,
+    volumes: Res<Volumes>,
+    settings: Res<CellSettings>,
+) {
+    let starts: Vec<_> = if settings.is_changed() && !settings.is_added() {
+        events.clear();
+        storm_cells.cells.clear();
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+    if starts.is_empty() {
+        return;
+    }
+
+    for start in starts {
+        if let Some(volume) = volumes.0.get(&start) {
+            let cells = identify(volume, &settings);
+            let time = cells.first().map(|c| c.time).unwrap_or(volume.start_time);
+            storm_cells.cells.insert(time, cells);
+        }
+    }
+    storm_cells.tracks = track(&storm_cells.cells, &settings);
+}
+
+/// Past track, current centroid and forecast positions of every cell present in the current volume.
+pub fn draw_cell_tracks(
+    mut gizmos: Gizmos,
+    info: Res<ScanInfo>,
+    settings: Res<CellSettings>,
+    storm_cells: Res<StormCells>,
+    tables: Res<ColorTables>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+    if !settings.enabled {
+        return;
+    }
+    let Some((&current, _)) = storm_cells.cells.range(..=time).next_back() else {
+        return;
+    };
+
+    for track in storm_cells.tracks.iter() {
+        let Some(cell) = track.at(time) else {
+            continue;
+        };
+        if cell.time != current {
+            continue;
+        }
+
+        let past: Vec<_> = track.cells.iter().take_while(|c| c.time <= time).map(|c| c.centroid).collect();
+        gizmos.linestrip(past, Color::WHITE);
+
+        let color = tables.get(ScanType::Reflectivity).color(cell.max_dbz).with_a(1.0);
+        gizmos.sphere(cell.centroid, Quat::IDENTITY, 1000.0, color);
+        gizmos.line(Vec3::new(cell.centroid.x, cell.base, cell.centroid.z), Vec3::new(cell.centroid.x, cell.top, cell.centroid.z), color);
+
+        if track.cells.len() > 1 {
+            let forecast: Vec<_> = settings.forecast_minutes.iter()
+                .map(|minutes| cell.centroid + track.motion * TimeDelta::minutes(*minutes).num_seconds() as f32)
+                .collect();
+            gizmos.linestrip(std::iter::once(cell.centroid).chain(forecast.iter().copied()), Color::CYAN);
+            for position in forecast {
+                gizmos.circle(position, Direction3d::Y, 500.0, Color::CYAN);
+            }
+        }
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<CellSettings>,
+) {
+    // toggling the overlay shouldn't re-identify every cell
+    if actions.just_pressed(Action::ToggleCells) {
+        let settings = settings.bypass_change_detection();
+        settings.enabled = !settings.enabled;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use bevy::math::Vec3;
+    use chrono::{DateTime, TimeDelta, Utc};
+    use crate::cells::{identify, track, CellSettings, StormCell};
+    use crate::radar::{Gate, Scan};
+    use crate::volume::Volume;
+
+    /// A sweep with a 6x6 gate echo of 45 dBz, a 2x2 one of 50 dBz and a wide 35 dBz one.
+    fn sweep(elevation: f32, time: DateTime<Utc>) -> Scan {
+        let (rays, bins) = (360, 40);
+        let mut gates = Vec::new();
+        for ray in 0..rays {
+            for bin in 0..bins {
+                let reflectivity = match (ray, bin) {
+                    (10..=15, 10..=15) => 45.0,
+                    (100..=101, 20..=21) => 50.0,
+                    (200..=230, 5..=35) => 35.0,
+                    _ => f32::NAN,
+                };
+                gates.push(Gate {
+                    reflectivity,
+                    azimuth: (ray as f32).to_radians(),
+                    elevation: elevation.to_radians(),
+                    range: 10_000.0 + bin as f32 * 250.0,
+                    ..Default::default()
+                });
+            }
+        }
+        Scan::ppi(gates, bins, time)
+    }
+
+    fn cell(time: DateTime<Utc>, x: f32, z: f32) -> StormCell {
+        StormCell { time, centroid: Vec3::new(x, 2_000.0, z), base: 1_000.0, top: 3_000.0, max_dbz: 50.0, vil: 10.0 }
+    }
+
+    #[test]
+    fn test_identify_keeps_large_echoes_above_the_threshold() {
+        let time = DateTime::from_timestamp(0, 0).unwrap();
+        let volume = Volume {
+            start_time: time,
+            end_time: time,
+            scans: vec![Arc::new(sweep(0.5, time)), Arc::new(sweep(1.5, time))],
+            rhis: Vec::new(),
+        };
+
+        let cells = identify(&volume, &CellSettings::default());
+        assert_eq!(cells.len(), 1, "{cells:?}");
+        assert_eq!(cells[0].max_dbz, 45.0);
+        let azimuth = cells[0].centroid.z.atan2(cells[0].centroid.x).to_degrees();
+        assert!((azimuth - 12.5).abs() < 0.5, "azimuth {azimuth}");
+        assert!(cells[0].top > cells[0].base);
+
+        // a single sweep is too shallow to make a cell
+        let shallow = Volume { scans: volume.scans[..1].to_vec(), ..volume };
+        assert!(identify(&shallow, &CellSettings::default()).is_empty());
+    }
+
+    #[test]
+    fn test_track_links_nearest_cells_between_volumes() {
+        let first = DateTime::from_timestamp(0, 0).unwrap();
+        let second = first + TimeDelta::minutes(5);
+        let cells = BTreeMap::from([
+            (first, vec![cell(first, 0.0, 0.0), cell(first, 20_000.0, 0.0)]),
+            (second, vec![cell(second, 20_000.0, 3_000.0), cell(second, 3_000.0, 0.0), cell(second, -50_000.0, 0.0)]),
+        ]);
+
+        let tracks = track(&cells, &CellSettings::default());
+        assert_eq!(tracks.len(), 3);
+        let centroids = |i: usize| tracks[i].cells.iter().map(|c| c.centroid.x).collect::<Vec<_>>();
+        assert_eq!(centroids(0), vec![0.0, 3_000.0]);
+        assert_eq!(centroids(1), vec![20_000.0, 20_000.0]);
+        assert_eq!(centroids(2), vec![-50_000.0]);
+        // 3 km north in 5 minutes
+        assert!((tracks[0].motion - Vec3::new(10.0, 0.0, 0.0)).length() < 1e-3, "{:?}", tracks[0].motion);
+        assert_eq!(tracks[2].motion, Vec3::ZERO);
+    }
+}
@@ -0,0 +1,227 @@
+use bevy::prelude::*;
+use bevy::render::extract_resource::ExtractResource;
+use bevy::render::render_resource::ShaderType;
+use bevy::window::PrimaryWindow;
+use bevy_egui::EguiContexts;
+use bevy_panorbit_camera::PanOrbitCamera;
+use serde::{Deserialize, Serialize};
+use crate::input::{Action, Actions};
+
+/// Planes the instancing shader has room for.
+pub const MAX_CLIP_PLANES: usize = 4;
+/// How close the cursor has to be to a face handle to grab it, in logical pixels.
+const HANDLE_PIXELS: f32 = 12.0;
+/// Radius of the face handles, in m.
+const HANDLE_RADIUS: f32 = 1_000.0;
+/// Faces can't be dragged closer together than this, in m.
+const MIN_SIZE: f32 = 500.0;
+
+/// A slicing plane through the scene, hiding the gates on the side its normal points to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClipPlane {
+    /// Direction of the normal clockwise from north, in degrees.
+    pub azimuth: f32,
+    /// Tilt of the normal above the horizon, in degrees.
+    pub elevation: f32,
+    /// Distance of the plane from the radar along its normal, in m.
+    pub offset: f32,
+}
+
+impl ClipPlane {
+    pub fn normal(&self) -> Vec3 {
+        let (azimuth, elevation) = (self.azimuth.to_radians(), self.elevation.to_radians());
+        Vec3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin())
+    }
+}
+
+/// Region of the instanced gates left drawn: inside the box and behind every plane.
+#[derive(Resource, Debug, Clone, PartialEq, ExtractResource, Serialize, Deserialize)]
+pub struct Clipping {
+    pub enabled: bool,
+    pub min: Vec3,
+    pub max: Vec3,
+    pub planes: Vec<ClipPlane>,
+}
+
+impl Default for Clipping {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min: Vec3::new(-100_000.0, 0.0, -100_000.0),
+            max: Vec3::new(100_000.0, 20_000.0, 100_000.0),
+            planes: Vec::new(),
+        }
+    }
+}
+
+/// `Clipping` as the instancing shader reads it. Planes are a normal and offset each.
+#[derive(Debug, Clone, Default, ShaderType)]
+pub struct ClipUniform {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub planes: [Vec4; MAX_CLIP_PLANES],
+    pub plane_count: u32,
+}
+
+impl Clipping {
+    pub fn uniform(&self) -> ClipUniform {
+        if !self.enabled {
+            return ClipUniform {
+                min: Vec3::splat(-f32::MAX),
+                max: Vec3::splat(f32::MAX),
+                ..default()
+            };
+        }
+        let mut planes = [Vec4::ZERO; MAX_CLIP_PLANES];
+        for (plane, uniform) in self.planes.iter().zip(planes.iter_mut()) {
+            *uniform = plane.normal().extend(plane.offset);
+        }
+        ClipUniform {
+            min: self.min,
+            max: self.max,
+            planes,
+            plane_count: self.planes.len().min(MAX_CLIP_PLANES) as u32,
+        }
+    }
+
+    /// The centre of each face with its axis and whether it is the far one.
+    pub fn handles(&self) -> [((usize, bool), Vec3); 6] {
+        let center = (self.min + self.max) / 2.0;
+        let handle = |axis: usize, far: bool| {
+            let mut point = center;
+            point[axis] = if far { self.max[axis] } else { self.min[axis] };
+            ((axis, far), point)
+        };
+        [handle(0, false), handle(0, true), handle(1, false), handle(1, true), handle(2, false), handle(2, true)]
+    }
+
+    fn set_face(&mut self, (axis, far): (usize, bool), value: f32) {
+        if far {
+            self.max[axis] = value.max(self.min[axis] + MIN_SIZE);
+        } else {
+            self.min[axis] = value.min(self.max[axis] - MIN_SIZE);
+        }
+    }
+}
+
+/// How far along `axis` from `point` the line through them passes closest to the ray, or `None` when they
+/// are parallel.
+pub fn along_axis(point: Vec3, axis: Vec3, origin: Vec3, direction: Vec3) -> Option<f32> {
+    let w = point - origin;
+    let (b, d, e) = (axis.dot(direction), axis.dot(w), direction.dot(w));
+    let denominator = axis.length_squared() * direction.length_squared() - b * b;
+    (denominator.abs() > 1e-6).then(|| (b * e - direction.length_squared() * d) / denominator)
+}
+
+/// Grab a face handle of the box with the left mouse button and drag it along its axis. The camera
+/// ignores the mouse meanwhile.
+pub fn drag_clip_box(
+    mut dragging: Local<Option<(usize, bool)>>,
+    mut contexts: EguiContexts,
+    mut clipping: ResMut<Clipping>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    views: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+) {
+    if !clipping.enabled || !buttons.pressed(MouseButton::Left) {
+        *dragging = None;
+        return;
+    }
+    let Some(cursor) = windows.get_single().ok().and_then(|window| window.cursor_position()) else {
+        return;
+    };
+    let view = views.iter().find_map(|(camera, transform)| {
+        let viewport = camera.logical_viewport_rect()?;
+        viewport.contains(cursor).then_some((camera, transform, cursor - viewport.min))
+    });
+    let Some((camera, transform, cursor)) = view else {
+        return;
+    };
+
+    if buttons.just_pressed(MouseButton::Left) && !contexts.ctx_mut().is_pointer_over_area() {
+        *dragging = clipping.handles().into_iter()
+            .find(|(_, point)| camera.world_to_viewport(transform, *point).is_some_and(|handle| handle.distance(cursor) < HANDLE_PIXELS))
+            .map(|(face, _)| face);
+    }
+    let Some(face) = *dragging else {
+        return;
+    };
+    for mut camera in cameras.iter_mut() {
+        camera.enabled = false;
+    }
+
+    let Some(ray) = camera.viewport_to_world(transform, cursor) else {
+        return;
+    };
+    let Some((_, handle)) = clipping.handles().into_iter().find(|(f, _)| *f == face) else {
+        return;
+    };
+    let axis = Vec3::AXES[face.0];
+    if let Some(t) = along_axis(handle, axis, ray.origin, *ray.direction) {
+        let value = handle[face.0] + t;
+        if value != handle[face.0] {
+            clipping.set_face(face, value);
+        }
+    }
+}
+
+pub fn draw_clipping(
+    mut gizmos: Gizmos,
+    clipping: Res<Clipping>,
+) {
+    if !clipping.enabled {
+        return;
+    }
+    let center = (clipping.min + clipping.max) / 2.0;
+    gizmos.cuboid(Transform::from_translation(center).with_scale(clipping.max - clipping.min), Color::CYAN);
+    for (_, point) in clipping.handles() {
+        gizmos.sphere(point, Quat::IDENTITY, HANDLE_RADIUS, Color::CYAN);
+    }
+    for plane in clipping.planes.iter().take(MAX_CLIP_PLANES) {
+        let normal = plane.normal();
+        gizmos.rect(normal * plane.offset, Quat::from_rotation_arc(Vec3::Z, normal), Vec2::splat(200_000.0), Color::ORANGE);
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut clipping: ResMut<Clipping>,
+) {
+    if actions.just_pressed(Action::ToggleClipping) {
+        clipping.enabled = !clipping.enabled;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::math::Vec3;
+    use crate::clip::{along_axis, ClipPlane, Clipping};
+
+    /// Whether a gate centred on `point` is drawn, as the shader decides it.
+    fn keeps(clipping: &Clipping, point: Vec3) -> bool {
+        let uniform = clipping.uniform();
+        point.cmpge(uniform.min).all() && point.cmple(uniform.max).all()
+            && uniform.planes[..uniform.plane_count as usize].iter().all(|plane| plane.truncate().dot(point) <= plane.w)
+    }
+
+    #[test]
+    fn test_box_and_planes_clip_gates_and_faces_follow_the_cursor() {
+        let mut clipping = Clipping {
+            enabled: true,
+            min: Vec3::new(-10.0, 0.0, -10.0),
+            max: Vec3::new(10.0, 5.0, 10.0),
+            planes: vec![ClipPlane { azimuth: 0.0, elevation: 0.0, offset: 2.0 }],
+        };
+        assert!(keeps(&clipping, Vec3::new(1.0, 1.0, 9.0)));
+        assert!(!keeps(&clipping, Vec3::new(3.0, 1.0, 0.0)));
+        assert!(!keeps(&clipping, Vec3::new(0.0, 6.0, 0.0)));
+        clipping.enabled = false;
+        assert!(keeps(&clipping, Vec3::new(0.0, 60_000.0, 0.0)));
+
+        // a ray looking down the z axis from above passes closest to the x axis at x = 4
+        let t = along_axis(Vec3::ZERO, Vec3::X, Vec3::new(4.0, 10.0, 0.0), Vec3::NEG_Y).unwrap();
+        assert!((t - 4.0).abs() < 1e-5);
+        assert!(along_axis(Vec3::ZERO, Vec3::X, Vec3::Y, Vec3::X).is_none());
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::hca::HydrometeorClass;
+use crate::scan::{ScanInfo, ScanType};
+
+pub const REFLECTIVITY_COLORS: [Color; 11] = [
+    Color::BLACK,
+    Color::CYAN,
+    Color::BLUE,
+    Color::MIDNIGHT_BLUE,
+    Color::DARK_GREEN,
+    Color::GREEN,
+    Color::YELLOW,
+    Color::YELLOW_GREEN,
+    Color::ORANGE,
+    Color::ORANGE_RED,
+    Color::RED,
+];
+
+/// Maps moment values to colours. `stops` must be sorted by value; `opacity` is a separate
+/// piecewise-linear value to alpha ramp so that tables can share colours but not transparency.
+/// Categorical tables name each stop in `labels` and are always stepped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColorTable {
+    pub name: String,
+    pub stops: Vec<(f32, Color)>,
+    pub stepped: bool,
+    pub opacity: Vec<(f32, f32)>,
+    pub labels: Vec<String>,
+    /// The palette picked for this moment in the control panel, when it isn't the moment's own.
+    #[serde(default)]
+    pub palette: Option<String>,
+}
+
+impl ColorTable {
+    pub fn reflectivity() -> Self {
+        Self {
+            name: String::from("Reflectivity"),
+            stops: REFLECTIVITY_COLORS.iter().enumerate().map(|(i, c)| (i as f32 * 5.0, *c)).collect(),
+            stepped: true,
+            opacity: vec![(0.0, 0.0), (50.0, 1.0)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    pub fn velocity() -> Self {
+        Self {
+            name: String::from("Velocity"),
+            stops: vec![
+                (-40.0, Color::RED),
+                (0.0, Color::BLACK),
+                (40.0, Color::GREEN),
+            ],
+            stepped: false,
+            opacity: vec![(-40.0, 1.0), (0.0, 0.0), (40.0, 1.0)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    /// Velocity with the storm motion removed, stretched over a smaller range than ground-relative velocity.
+    pub fn storm_relative_velocity() -> Self {
+        Self {
+            name: String::from("Storm-Relative Velocity"),
+            stops: vec![
+                (-30.0, Color::rgb(1.0, 0.2, 0.6)),
+                (-10.0, Color::rgb(0.5, 0.0, 0.1)),
+                (0.0, Color::BLACK),
+                (10.0, Color::rgb(0.0, 0.4, 0.4)),
+                (30.0, Color::rgb(0.3, 1.0, 0.9)),
+            ],
+            stepped: false,
+            opacity: vec![(-30.0, 1.0), (0.0, 0.0), (30.0, 1.0)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    /// Diverging table for velocity derivatives, in s^-1.
+    pub fn shear(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            stops: vec![
+                (-0.02, Color::CYAN),
+                (-0.005, Color::BLUE),
+                (0.0, Color::BLACK),
+                (0.005, Color::ORANGE),
+                (0.02, Color::YELLOW),
+            ],
+            stepped: false,
+            opacity: vec![(-0.02, 1.0), (0.0, 0.0), (0.02, 1.0)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    /// Differential reflectivity in dB, from negative (vertically oriented) through to large drops.
+    pub fn differential_reflectivity(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            stops: vec![
+                (-2.0, Color::GRAY),
+                (0.0, Color::BLUE),
+                (1.0, Color::CYAN),
+                (2.0, Color::GREEN),
+                (3.0, Color::YELLOW),
+                (4.0, Color::ORANGE),
+                (6.0, Color::RED),
+            ],
+            stepped: false,
+            opacity: vec![(-2.0, 1.0), (6.0, 1.0)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    /// A sequential table for moments without a natural zero, such as PHIDP, RHOHV and KDP.
+    pub fn sequential(name: &str, min: f32, max: f32) -> Self {
+        let step = (max - min) / 4.0;
+        Self {
+            name: String::from(name),
+            stops: [Color::MIDNIGHT_BLUE, Color::BLUE, Color::GREEN, Color::YELLOW, Color::RED]
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (min + step * i as f32, *c))
+                .collect(),
+            stepped: false,
+            opacity: vec![(min, 0.2), (max, 1.0)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    /// Rainfall accumulation in mm; amounts under a quarter of a millimetre are transparent.
+    pub fn accumulation() -> Self {
+        Self {
+            name: String::from("Accumulation (mm)"),
+            stops: vec![
+                (0.25, Color::SEA_GREEN),
+                (1.0, Color::CYAN),
+                (2.5, Color::BLUE),
+                (5.0, Color::GREEN),
+                (10.0, Color::YELLOW),
+                (25.0, Color::ORANGE),
+                (50.0, Color::RED),
+                (100.0, Color::FUCHSIA),
+            ],
+            stepped: true,
+            opacity: vec![(0.2, 0.0), (0.25, 0.8)],
+            labels: Vec::new(),
+            palette: None,
+        }
+    }
+
+    /// One colour per class, indexed from zero.
+    pub fn categorical(name: &str, classes: &[(String, Color)]) -> Self {
+        Self {
+            name: String::from(name),
+            stops: classes.iter().enumerate().map(|(i, (_, c))| (i as f32, *c)).collect(),
+            stepped: true,
+            opacity: vec![(0.0, 1.0)],
+            labels: classes.iter().map(|(label, _)| label.clone()).collect(),
+            palette: None,
+        }
+    }
+
+    pub fn hydrometeor_class() -> Self {
+        let classes: Vec<_> = HydrometeorClass::ALL.iter().map(|c| (format!("{:?}", c), c.color())).collect();
+        Self::categorical("Hydrometeor Class", &classes)
+    }
+
+    /// Legend entries: class names for categorical tables, otherwise the value at each stop.
+    pub fn legend(&self) -> Vec<(String, Color)> {
+        self.stops.iter()
+            .enumerate()
+            .map(|(i, (value, color))| {
+                let label = self.labels.get(i).cloned().unwrap_or_else(|| format!("{}", value));
+                (label, color.with_a(1.0))
+            })
+            .collect()
+    }
+
+    /// The same colours and opacity stretched over `(min, max)`, so one moment's palette can be used for another.
+    pub fn rescaled(&self, (min, max): (f32, f32)) -> Self {
+        let (from_min, from_max) = self.range();
+        let scale = |value: f32| min + (value - from_min) / (from_max - from_min).max(f32::EPSILON) * (max - min);
+        Self {
+            name: self.name.clone(),
+            stops: self.stops.iter().map(|(value, color)| (scale(*value), *color)).collect(),
+            stepped: self.stepped,
+            opacity: self.opacity.iter().map(|(value, alpha)| (scale(*value), *alpha)).collect(),
+            labels: Vec::new(),
+            palette: self.palette.clone(),
+        }
+    }
+
+    /// The name the control panel lists this table's colours under.
+    pub fn palette_name(&self) -> &str {
+        self.palette.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn range(&self) -> (f32, f32) {
+        let min = self.stops.first().map(|s| s.0).unwrap_or(0.0);
+        let max = self.stops.last().map(|s| s.0).unwrap_or(1.0);
+        (min, max)
+    }
+
+    pub fn color(&self, value: f32) -> Color {
+        let rgb = self.rgb(value);
+        rgb.with_a(interpolate(&self.opacity, value))
+    }
+
+    fn rgb(&self, value: f32) -> Color {
+        let i = self.stops.partition_point(|(v, _)| *v <= value);
+        match i {
+            0 => self.stops[0].1,
+            i if i == self.stops.len() || self.stepped => self.stops[i - 1].1,
+            i => {
+                let (v0, c0) = self.stops[i - 1];
+                let (v1, c1) = self.stops[i];
+                let t = (value - v0) / (v1 - v0);
+                let (a, b) = (c0.as_rgba_f32(), c1.as_rgba_f32());
+                Color::rgba(
+                    a[0] + (b[0] - a[0]) * t,
+                    a[1] + (b[1] - a[1]) * t,
+                    a[2] + (b[2] - a[2]) * t,
+                    a[3] + (b[3] - a[3]) * t,
+                )
+            }
+        }
+    }
+
+    /// Sample the table evenly between `min` and `max`, for use as a GPU transfer function.
+    pub fn sample(&self, (min, max): (f32, f32), count: usize) -> Vec<Color> {
+        (0..count)
+            .map(|i| self.color(min + (max - min) * i as f32 / (count - 1) as f32))
+            .collect()
+    }
+}
+
+fn interpolate(ramp: &[(f32, f32)], value: f32) -> f32 {
+    let i = ramp.partition_point(|(v, _)| *v <= value);
+    match i {
+        0 => ramp.first().map(|r| r.1).unwrap_or(1.0),
+        i if i == ramp.len() => ramp[i - 1].1,
+        i => {
+            let (v0, a0) = ramp[i - 1];
+            let (v1, a1) = ramp[i];
+            a0 + (a1 - a0) * (value - v0) / (v1 - v0)
+        }
+    }
+}
+
+/// The active colour table for each moment.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColorTables(pub HashMap<ScanType, ColorTable>);
+
+impl Default for ColorTables {
+    fn default() -> Self {
+        Self(HashMap::from([
+            (ScanType::Reflectivity, ColorTable::reflectivity()),
+            (ScanType::Velocity, ColorTable::velocity()),
+            (ScanType::AzimuthalShear, ColorTable::shear("Azimuthal Shear")),
+            (ScanType::Divergence, ColorTable::shear("Divergence")),
+            (ScanType::DifferentialReflectivity, ColorTable::differential_reflectivity("Differential Reflectivity")),
+            (ScanType::DifferentialPhase, ColorTable::sequential("Differential Phase", 0.0, 180.0)),
+            (ScanType::CrossCorrelation, ColorTable::sequential("Cross Correlation", 0.7, 1.0)),
+            (ScanType::SpecificDifferentialPhase, ColorTable::sequential("Specific Differential Phase", -1.0, 6.0)),
+            (ScanType::CorrectedReflectivity, ColorTable { name: String::from("Corrected Reflectivity"), ..ColorTable::reflectivity() }),
+            (ScanType::CorrectedDifferentialReflectivity, ColorTable::differential_reflectivity("Corrected Differential Reflectivity")),
+            (ScanType::HydrometeorClass, ColorTable::hydrometeor_class()),
+            (ScanType::StormRelativeVelocity, ColorTable::storm_relative_velocity()),
+        ]))
+    }
+}
+
+impl ColorTables {
+    pub fn get(&self, scan_type: ScanType) -> &ColorTable {
+        &self.0[&scan_type]
+    }
+}
+
+#[derive(Component)]
+pub struct Legend;
+
+pub fn setup_legend(
+    mut commands: Commands,
+) {
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                right: Val::Px(10.0),
+                top: Val::Px(10.0),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(2.0),
+                padding: UiRect::all(Val::Px(6.0)),
+                ..default()
+            },
+            background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+            ..default()
+        },
+        Legend,
+    ));
+}
+
+/// Rebuild the legend whenever the selected moment or its colour table changes.
+pub fn update_legend(
+    mut commands: Commands,
+    mut shown: Local<Option<ScanType>>,
+    info: Res<ScanInfo>,
+    tables: Res<ColorTables>,
+    legend: Query<Entity, With<Legend>>,
+) {
+    if *shown == Some(info.scan_type) && !tables.is_changed() {
+        return;
+    }
+    let Ok(legend) = legend.get_single() else {
+        return;
+    };
+    *shown = Some(info.scan_type);
+
+    let table = tables.get(info.scan_type);
+    commands.entity(legend).despawn_descendants().with_children(|parent| {
+        parent.spawn(TextBundle::from_section(table.name.clone(), TextStyle { font_size: 18.0, ..default() }));
+        for (label, color) in table.legend().into_iter().rev() {
+            parent.spawn(NodeBundle {
+                style: Style {
+                    flex_direction: FlexDirection::Row,
+                    column_gap: Val::Px(6.0),
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            }).with_children(|row| {
+                row.spawn(NodeBundle {
+                    style: Style {
+                        width: Val::Px(16.0),
+                        height: Val::Px(16.0),
+                        ..default()
+                    },
+                    background_color: color.into(),
+                    ..default()
+                });
+                row.spawn(TextBundle::from_section(label, TextStyle { font_size: 16.0, ..default() }));
+            });
+        }
+    });
+}
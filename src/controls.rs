@@ -0,0 +1,374 @@
+use std::ops::RangeInclusive;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use bevy_panorbit_camera::PanOrbitCamera;
+use chrono::TimeDelta;
+use crate::camera::{self, Bookmark, CameraAnimation, CameraPose};
+use crate::clip::{ClipPlane, Clipping, MAX_CLIP_PLANES};
+use crate::colortable::{ColorTable, ColorTables};
+use crate::input::{Action, Actions, InputMap};
+use crate::panel::LeftInset;
+use crate::playback::{self, PendingLoop, PlaybackAction};
+use crate::scan::{RenderMode, ScanInfo, ScanType, Thresholds};
+use crate::scale::SceneScale;
+use crate::session::{Session, SessionRequest, SESSION_PATH};
+use crate::views::ViewLayout;
+use crate::volume::Volumes;
+
+#[derive(Resource)]
+pub struct ControlPanel {
+    pub visible: bool,
+    /// Width the panel takes from the left of the window, 0 while it is hidden.
+    pub width: f32,
+}
+
+impl Default for ControlPanel {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            width: 0.0,
+        }
+    }
+}
+
+/// Slider range for the threshold of a moment, in its own units.
+fn threshold_range(scan_type: ScanType) -> RangeInclusive<f32> {
+    match scan_type {
+        ScanType::Reflectivity | ScanType::CorrectedReflectivity | ScanType::HydrometeorClass => -10.0..=70.0,
+        ScanType::Velocity | ScanType::StormRelativeVelocity => 0.0..=60.0,
+        _ => 0.0..=0.02,
+    }
+}
+
+/// Palettes that can be put on any continuous moment: the default table of each one.
+fn palettes() -> Vec<ColorTable> {
+    let mut palettes: Vec<_> = ColorTables::default().0.into_values().filter(|table| table.labels.is_empty()).collect();
+    palettes.sort_by(|a, b| a.name.cmp(&b.name));
+    palettes.dedup_by(|a, b| a.name == b.name);
+    palettes
+}
+
+/// Side panel holding the HUD and everything in `ScanInfo`, with the key bindings for reference.
+/// Threshold sliders only rebuild the gates once they are let go of.
+pub fn control_panel(
+    mut contexts: EguiContexts,
+    mut editing: Local<Option<Thresholds>>,
+    mut bookmark_name: Local<String>,
+    mut panel: ResMut<ControlPanel>,
+    mut info: ResMut<ScanInfo>,
+    mut pending: ResMut<PendingLoop>,
+    mut tables: ResMut<ColorTables>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+    mut animation: ResMut<CameraAnimation>,
+    (mut session, mut session_path, mut requests): (ResMut<Session>, Local<String>, EventWriter<SessionRequest>),
+    mut layout: ResMut<ViewLayout>,
+    mut clipping: ResMut<Clipping>,
+    mut scale: ResMut<SceneScale>,
+    volumes: Res<Volumes>,
+    input_map: Res<InputMap>,
+) {
+    if !panel.visible {
+        if panel.width != 0.0 {
+            panel.width = 0.0;
+        }
+        return;
+    }
+    let ctx = contexts.ctx_mut();
+    let pose = cameras.iter().next().map(CameraPose::of);
+
+    egui::SidePanel::left("controls").resizable(true).default_width(300.0).show(ctx, |ui| {
+        egui::ScrollArea::vertical().id_source("controls").show(ui, |ui| {
+            if let Some(summary) = info.summary() {
+                ui.label(summary);
+            }
+            ui.label(info.status.as_str());
+
+            ui.separator();
+            ui.heading("Moment");
+            let mut scan_type = info.scan_type;
+            egui::ComboBox::from_id_source("moment").selected_text(format!("{scan_type:?}")).show_ui(ui, |ui| {
+                for option in ScanType::ALL {
+                    ui.selectable_value(&mut scan_type, option, format!("{option:?}"));
+                }
+            });
+            let mut render_mode = info.render_mode;
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut render_mode, RenderMode::Instanced, "Gates");
+                ui.radio_value(&mut render_mode, RenderMode::Volume, "Volume");
+                ui.radio_value(&mut render_mode, RenderMode::Plan, "Plan");
+            });
+            let mut interpolate = info.interpolate;
+            ui.checkbox(&mut interpolate, "Interpolate between sweeps");
+            let mut filter = info.filter;
+            ui.add(egui::Slider::new(&mut filter, 0.0..=40.0).text("filter"));
+            if (scan_type, render_mode, interpolate, filter) != (info.scan_type, info.render_mode, info.interpolate, info.filter) {
+                info.scan_type = scan_type;
+                info.render_mode = render_mode;
+                info.interpolate = interpolate;
+                info.filter = filter;
+            }
+
+            // categorical moments keep their class colours
+            if tables.get(scan_type).labels.is_empty() {
+                let current = tables.get(scan_type).palette_name().to_string();
+                egui::ComboBox::from_label("colour table").selected_text(current.as_str()).show_ui(ui, |ui| {
+                    let default = ColorTables::default().get(scan_type).clone();
+                    for palette in palettes() {
+                        if !ui.selectable_label(palette.name == current, palette.name.as_str()).clicked() {
+                            continue;
+                        }
+                        let table = if palette.name == default.name {
+                            default.clone()
+                        } else {
+                            ColorTable {
+                                name: default.name.clone(),
+                                palette: Some(palette.name.clone()),
+                                ..palette.rescaled(default.range())
+                            }
+                        };
+                        tables.0.insert(scan_type, table);
+                    }
+                });
+            }
+
+            ui.separator();
+            ui.heading("Views");
+            let (mut panels, mut moments, mut link_cameras) = (layout.panels, layout.moments, layout.link_cameras);
+            ui.horizontal(|ui| {
+                for (count, label) in [(1, "Single"), (2, "Side by side"), (4, "2x2")] {
+                    ui.radio_value(&mut panels, count, label);
+                }
+            });
+            for (i, moment) in moments.iter_mut().enumerate().take(panels - 1) {
+                egui::ComboBox::from_label(format!("panel {}", i + 2)).selected_text(format!("{moment:?}")).show_ui(ui, |ui| {
+                    for option in ScanType::ALL {
+                        ui.selectable_value(moment, option, format!("{option:?}"));
+                    }
+                });
+            }
+            ui.checkbox(&mut link_cameras, "Link cameras");
+            if (panels, moments, link_cameras) != (layout.panels, layout.moments, layout.link_cameras) {
+                layout.panels = panels;
+                layout.moments = moments;
+                layout.link_cameras = link_cameras;
+            }
+
+            ui.separator();
+            ui.heading("Clipping");
+            let mut edited = clipping.clone();
+            ui.checkbox(&mut edited.enabled, "Clip gates (drag the box handles)");
+            for (axis, name, range) in [(0, "north", -250.0..=250.0), (2, "east", -250.0..=250.0), (1, "height", 0.0..=25.0)] {
+                let (mut min, mut max) = (edited.min[axis] / 1000.0, edited.max[axis] / 1000.0);
+                if ui.add(egui::Slider::new(&mut min, range.clone()).text(format!("{name} from (km)"))).changed() {
+                    edited.min[axis] = min * 1000.0;
+                }
+                if ui.add(egui::Slider::new(&mut max, range).text(format!("{name} to (km)"))).changed() {
+                    edited.max[axis] = max * 1000.0;
+                }
+            }
+            let mut removed = None;
+            for (i, plane) in edited.planes.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Plane {}", i + 1));
+                    if ui.button("x").clicked() {
+                        removed = Some(i);
+                    }
+                });
+                ui.add(egui::Slider::new(&mut plane.azimuth, 0.0..=360.0).text("azimuth (°)"));
+                ui.add(egui::Slider::new(&mut plane.elevation, -90.0..=90.0).text("tilt (°)"));
+                let mut offset = plane.offset / 1000.0;
+                if ui.add(egui::Slider::new(&mut offset, -250.0..=250.0).text("offset (km)")).changed() {
+                    plane.offset = offset * 1000.0;
+                }
+            }
+            if let Some(i) = removed {
+                edited.planes.remove(i);
+            }
+            if edited.planes.len() < MAX_CLIP_PLANES && ui.button("Add plane").clicked() {
+                edited.planes.push(ClipPlane { azimuth: 0.0, elevation: 0.0, offset: 0.0 });
+            }
+            if edited != *clipping {
+                *clipping = edited;
+            }
+
+            ui.separator();
+            ui.heading("Thresholds");
+            let was_dragging = editing.is_some();
+            let mut thresholds = editing.take().unwrap_or_else(|| info.thresholds.clone());
+            let (mut dragging, mut changed) = (false, false);
+            for moment in ScanType::ALL.into_iter().filter(|moment| moment.threshold_moment() == *moment) {
+                let value = thresholds.0.entry(moment).or_insert(moment.threshold());
+                let response = ui.add(egui::Slider::new(value, threshold_range(moment)).text(format!("{moment:?}")));
+                dragging |= response.dragged();
+                changed |= response.changed();
+            }
+            if dragging {
+                *editing = Some(thresholds);
+            } else if changed || was_dragging {
+                info.thresholds = thresholds;
+            }
+
+            ui.separator();
+            ui.heading("Time");
+            let seconds = |delta: TimeDelta| delta.num_milliseconds() as f32 / 1000.0;
+            let (mut window, mut step) = (seconds(info.visible_window), seconds(info.step_size));
+            ui.add(egui::Slider::new(&mut window, 0.2..=900.0).logarithmic(true).text("visible window (s)"));
+            ui.add(egui::Slider::new(&mut step, 0.2..=900.0).logarithmic(true).text("step (s)"));
+            if window != seconds(info.visible_window) {
+                info.visible_window = TimeDelta::milliseconds((window * 1000.0) as i64);
+            }
+            if step != seconds(info.step_size) {
+                info.step_size = TimeDelta::milliseconds((step * 1000.0) as i64);
+            }
+
+            ui.horizontal(|ui| {
+                for action in PlaybackAction::ALL {
+                    if ui.button(action.label()).clicked() {
+                        playback::apply(action, &mut info, &mut pending, &volumes);
+                    }
+                }
+            });
+            let mut dwell = info.dwell_seconds;
+            ui.add(egui::Slider::new(&mut dwell, 0.0..=10.0).text("loop dwell (s)"));
+            if dwell != info.dwell_seconds {
+                info.dwell_seconds = dwell;
+            }
+
+            ui.separator();
+            ui.heading("Camera");
+            let mut exaggeration = scale.exaggeration;
+            ui.add(egui::Slider::new(&mut exaggeration, 1.0..=20.0).logarithmic(true).text("vertical exaggeration"));
+            if exaggeration != scale.exaggeration {
+                scale.exaggeration = exaggeration;
+            }
+            if let Some(pose) = pose {
+                ui.horizontal(|ui| {
+                    let presets = [
+                        ("Top down", CameraPose::top_down()),
+                        ("From south", CameraPose::from_south()),
+                        ("Up beam", CameraPose::up_beam(pose.azimuth(), scale.apparent_elevation(0.5f32.to_radians()))),
+                    ];
+                    for (label, preset) in presets {
+                        if ui.button(label).clicked() {
+                            animation.fly(pose, preset);
+                        }
+                    }
+                    if ui.button("Play path").clicked() {
+                        animation.play(camera::session_path(&session, pose, scale.exaggeration));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(format!("Path: {} keyframes", session.camera_path.len()));
+                    if ui.button("Record keyframe").clicked() {
+                        camera::record_keyframe(&mut session, pose, scale.exaggeration);
+                    }
+                    if ui.button("Clear path").clicked() {
+                        session.camera_path.clear();
+                    }
+                });
+                let mut removed = None;
+                for (i, bookmark) in session.bookmarks.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.button(bookmark.name.as_str()).clicked() {
+                            animation.fly(pose, bookmark.pose.stretched(scale.exaggeration));
+                        }
+                        if ui.button("x").clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = removed {
+                    session.bookmarks.remove(i);
+                }
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut *bookmark_name);
+                    if ui.button("Bookmark").clicked() {
+                        let name = if bookmark_name.is_empty() {
+                            format!("Bookmark {}", session.bookmarks.len() + 1)
+                        } else {
+                            std::mem::take(&mut *bookmark_name)
+                        };
+                        session.bookmarks.push(Bookmark { name, pose: pose.stretched(1.0 / scale.exaggeration) });
+                    }
+                });
+            }
+
+            ui.separator();
+            ui.heading("Session");
+            // an empty path means the one kept between runs
+            ui.horizontal(|ui| {
+                ui.label("file");
+                ui.text_edit_singleline(&mut *session_path);
+            });
+            let path = if session_path.is_empty() { SESSION_PATH.to_string() } else { session_path.clone() };
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    requests.send(SessionRequest::Save(path.clone()));
+                }
+                if ui.button("Load").clicked() {
+                    requests.send(SessionRequest::Load(path));
+                }
+            });
+
+            ui.separator();
+            egui::CollapsingHeader::new(format!("Scans ({})", info.loaded_scans)).id_source("scans").show(ui, |ui| {
+                egui::ScrollArea::vertical().id_source("scan list").max_height(300.0).show(ui, |ui| {
+                    for (start, volume) in volumes.0.iter() {
+                        ui.label(start.format("Volume %H:%M:%S").to_string());
+                        for scan in volume.scans.iter().chain(volume.rhis.iter()) {
+                            let key = (*start, scan.meta.sweep_index);
+                            let mut shown = !info.hidden_scans.contains(&key);
+                            let label = format!("{} {:.1}°", scan.meta.name, scan.meta.fixed_angle.to_degrees());
+                            if ui.checkbox(&mut shown, label).changed() {
+                                if shown {
+                                    info.hidden_scans.remove(&key);
+                                } else {
+                                    info.hidden_scans.insert(key);
+                                }
+                            }
+                        }
+                    }
+                });
+            });
+
+            egui::CollapsingHeader::new("Key bindings").show(ui, |ui| {
+                for line in input_map.lines() {
+                    ui.label(line);
+                }
+            });
+        });
+    });
+
+    let width = ctx.available_rect().left();
+    if panel.width != width {
+        panel.width = width;
+    }
+
+    // the timeline turns the camera back on once the pointer leaves both
+    if ctx.is_pointer_over_area() || ctx.wants_pointer_input() {
+        for mut camera in cameras.iter_mut() {
+            camera.enabled = false;
+        }
+    }
+}
+
+/// Move the UI nodes placed from the left edge along with the right edge of the panel.
+pub fn place_beside_controls(
+    panel: Res<ControlPanel>,
+    mut nodes: Query<(Ref<LeftInset>, &mut Style)>,
+) {
+    for (inset, mut style) in nodes.iter_mut() {
+        if panel.is_changed() || inset.is_added() {
+            style.left = Val::Px(panel.width + inset.0);
+        }
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut panel: ResMut<ControlPanel>,
+) {
+    if actions.just_pressed(Action::ToggleControls) {
+        panel.visible = !panel.visible;
+    }
+}
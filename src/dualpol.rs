@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use crate::radar::Gate;
+
+/// Length of the moving average applied to PHIDP along the beam, in metres.
+const PHIDP_FILTER_LENGTH: f32 = 2000.0;
+/// Length of the least-squares window KDP is fitted over, in metres.
+const KDP_WINDOW_LENGTH: f32 = 3000.0;
+const KDP_MIN_POINTS: usize = 3;
+/// Gates with a lower cross-correlation are treated as non-meteorological and skipped.
+const MIN_CROSS_CORRELATION: f32 = 0.85;
+/// Number of leading valid gates whose median is taken as the system differential phase.
+const SYSTEM_PHASE_GATES: usize = 10;
+
+/// X-band ratio of specific attenuation to KDP, in dB per degree.
+const ALPHA: f32 = 0.28;
+/// X-band ratio of specific differential attenuation to KDP, in dB per degree.
+const BETA: f32 = 0.04;
+/// Exponent of the X-band `A = a Z^b` power law used by ZPHI.
+const ZPHI_B: f32 = 0.78;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+pub enum Attenuation {
+    /// Path integrated attenuation proportional to the accumulated differential phase.
+    LinearPhidp,
+    /// ZPHI (Testud et al., 2000): attenuation distributed along the path following reflectivity and
+    /// constrained by the total differential phase shift.
+    #[default]
+    Zphi,
+}
+
+/// Fill `Gate::specific_differential_phase` and the attenuation corrected reflectivity and differential
+/// reflectivity from `Gate::differential_phase`. `gates` is ray-major with `bins` gates per ray.
+pub fn process(gates: &mut [Gate], bins: usize, attenuation: Attenuation) {
+    if bins < 2 {
+        return;
+    }
+    let range_step = ((gates[bins - 1].range - gates[0].range) / (bins - 1) as f32).abs().max(1.0);
+    let filter_half = (PHIDP_FILTER_LENGTH / range_step / 2.0).round().max(1.0) as usize;
+    let kdp_half = (KDP_WINDOW_LENGTH / range_step / 2.0).round().max(1.0) as usize;
+
+    for ray in gates.chunks_mut(bins) {
+        let valid: Vec<bool> = ray.iter()
+            .map(|g| !g.differential_phase.is_nan() && !g.reflectivity.is_nan() && !(g.cross_correlation < MIN_CROSS_CORRELATION))
+            .collect();
+        let phase = filter_phase(ray, &valid, filter_half);
+
+        for (bin, gate) in ray.iter_mut().enumerate() {
+            gate.specific_differential_phase = specific_differential_phase(&phase, &valid, bin, kdp_half, range_step);
+        }
+
+        let Some(first) = valid.iter().position(|v| *v) else {
+            for gate in ray.iter_mut() {
+                gate.corrected_reflectivity = gate.reflectivity;
+                gate.corrected_differential_reflectivity = gate.differential_reflectivity;
+            }
+            continue;
+        };
+        let last = valid.iter().rposition(|v| *v).unwrap();
+        let delta_phase = accumulated_phase(&phase, &valid, first);
+
+        let attenuation = match attenuation {
+            Attenuation::LinearPhidp => delta_phase.iter().map(|dp| ALPHA * dp).collect(),
+            Attenuation::Zphi => zphi(ray, first, last, delta_phase[last], range_step),
+        };
+
+        for (gate, pia) in ray.iter_mut().zip(attenuation) {
+            gate.corrected_reflectivity = gate.reflectivity + pia;
+            gate.corrected_differential_reflectivity = gate.differential_reflectivity + pia * BETA / ALPHA;
+        }
+    }
+}
+
+/// Moving average of the valid PHIDP gates within `half` gates of each valid gate.
+fn filter_phase(ray: &[Gate], valid: &[bool], half: usize) -> Vec<f32> {
+    (0..ray.len())
+        .map(|bin| {
+            if !valid[bin] {
+                return f32::NAN;
+            }
+            let window = bin.saturating_sub(half)..(bin + half + 1).min(ray.len());
+            let (sum, count) = window
+                .filter(|j| valid[*j])
+                .fold((0.0, 0), |(sum, count), j| (sum + ray[j].differential_phase, count + 1));
+            sum / count as f32
+        })
+        .collect()
+}
+
+/// Half the least-squares slope of the filtered phase, in degrees per kilometre.
+fn specific_differential_phase(phase: &[f32], valid: &[bool], bin: usize, half: usize, range_step: f32) -> f32 {
+    if !valid[bin] {
+        return f32::NAN;
+    }
+
+    let (mut sx, mut sy, mut sxx, mut sxy, mut n) = (0.0, 0.0, 0.0, 0.0, 0);
+    for j in bin.saturating_sub(half)..(bin + half + 1).min(phase.len()) {
+        if !valid[j] {
+            continue;
+        }
+        let x = (j as f32 - bin as f32) * range_step / 1000.0;
+        sx += x;
+        sy += phase[j];
+        sxx += x * x;
+        sxy += x * phase[j];
+        n += 1;
+    }
+    if n < KDP_MIN_POINTS {
+        return f32::NAN;
+    }
+
+    let n = n as f32;
+    let denominator = n * sxx - sx * sx;
+    if denominator.abs() < 1e-6 {
+        return f32::NAN;
+    }
+    0.5 * (n * sxy - sx * sy) / denominator
+}
+
+/// Differential phase accumulated since the start of the path, made non-negative and non-decreasing so
+/// that backscatter phase and noise cannot remove attenuation that has already happened.
+fn accumulated_phase(phase: &[f32], valid: &[bool], first: usize) -> Vec<f32> {
+    let mut system_phase: Vec<f32> = (first..phase.len())
+        .filter(|j| valid[*j])
+        .take(SYSTEM_PHASE_GATES)
+        .map(|j| phase[j])
+        .collect();
+    system_phase.sort_by(f32::total_cmp);
+    let system_phase = system_phase[system_phase.len() / 2];
+
+    let mut accumulated = 0.0f32;
+    phase.iter()
+        .zip(valid)
+        .map(|(phase, valid)| {
+            if *valid {
+                accumulated = accumulated.max(phase - system_phase);
+            }
+            accumulated
+        })
+        .collect()
+}
+
+/// Two-way path integrated attenuation at every gate, in dB.
+fn zphi(ray: &[Gate], first: usize, last: usize, delta_phase: f32, range_step: f32) -> Vec<f32> {
+    let mut pia = vec![0.0; ray.len()];
+    if delta_phase <= 0.0 {
+        return pia;
+    }
+
+    let ds = range_step / 1000.0;
+    let z_b: Vec<f32> = ray.iter()
+        .map(|g| if g.reflectivity.is_nan() { 0.0 } else { 10f32.powf(0.1 * ZPHI_B * g.reflectivity) })
+        .collect();
+
+    // I(r, rm) = 0.46 b ∫ Z^b ds, accumulated back from the end of the path
+    let mut tail = vec![0.0f32; ray.len() + 1];
+    for bin in (first..=last).rev() {
+        tail[bin] = tail[bin + 1] + 0.46 * ZPHI_B * z_b[bin] * ds;
+    }
+    if tail[first] <= 0.0 {
+        return pia;
+    }
+
+    // A(r) = Z^b(r) C / (I(r0, rm) + C I(r, rm)), integrated exactly across each gate
+    let c = 10f32.powf(0.1 * ZPHI_B * ALPHA * delta_phase) - 1.0;
+    let mut total = 0.0;
+    for bin in first..ray.len() {
+        if bin <= last {
+            let ratio = (tail[first] + c * tail[bin]) / (tail[first] + c * tail[bin + 1]);
+            total += 2.0 * ratio.ln() / (0.46 * ZPHI_B);
+        }
+        pia[bin] = total;
+    }
+    pia
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dualpol::{process, Attenuation};
+    use crate::radar::Gate;
+
+    fn ray() -> Vec<Gate> {
+        // PHIDP rises 2 deg/km from a system phase of 10 deg
+        (0..80)
+            .map(|bin| {
+                let range = 1000.0 + bin as f32 * 250.0;
+                Gate {
+                    reflectivity: 30.0,
+                    doppler_velocity: 0.0,
+                    azimuth: 0.0,
+                    elevation: 0.0,
+                    range,
+                    differential_reflectivity: 1.0,
+                    differential_phase: 10.0 + 2.0 * (range - 1000.0) / 1000.0,
+                    cross_correlation: 0.99,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kdp_of_linear_phase() {
+        let mut gates = ray();
+        process(&mut gates, 80, Attenuation::LinearPhidp);
+        let kdp = gates[40].specific_differential_phase;
+        assert!((kdp - 1.0).abs() < 0.01, "kdp {}", kdp);
+    }
+
+    #[test]
+    fn test_zphi_matches_total_phase_constraint() {
+        let mut linear = ray();
+        process(&mut linear, 80, Attenuation::LinearPhidp);
+        let mut zphi = ray();
+        process(&mut zphi, 80, Attenuation::Zphi);
+
+        let (linear, zphi) = (linear.last().unwrap(), zphi.last().unwrap());
+        assert!(linear.corrected_reflectivity > 33.0);
+        assert!((linear.corrected_reflectivity - zphi.corrected_reflectivity).abs() < 0.05, "{} {}", linear.corrected_reflectivity, zphi.corrected_reflectivity);
+        assert!(zphi.corrected_differential_reflectivity > 1.0);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::sync::Arc;
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use rayon::prelude::*;
+use crate::radar::{Gate, Scan};
+use crate::scan::ScanType;
+use crate::volume::Volume;
+
+const AZIMUTH_BINS: usize = 1440;
+const ELEVATION_TOLERANCE: f32 = 0.0175;
+/// Most grids kept at once. Each is around 10 MB at the default spacing.
+pub const MAX_GRIDS: usize = 24;
+
+#[derive(Resource, Debug, Clone)]
+pub struct GridSettings {
+    pub spacing: f32,
+    pub vertical_spacing: f32,
+    pub height: f32,
+}
+
+impl Default for GridSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 500.0,
+            vertical_spacing: 250.0,
+            height: 15_000.0,
+        }
+    }
+}
+
+/// A regular cartesian grid centred on the radar, `x` varying fastest, then `y` (up), then `z`.
+/// Samples with no radar coverage are NaN.
+#[derive(Clone)]
+pub struct Grid {
+    pub origin: Vec3,
+    pub spacing: Vec3,
+    pub dims: UVec3,
+    pub data: Vec<f32>,
+}
+
+impl Grid {
+    /// Resample every sweep of `volume` onto a grid by inverting the beam geometry of `Gate::as_cart`,
+    /// interpolating linearly in elevation between the two sweeps either side of each sample.
+    pub fn from_volume(volume: &Volume, scan_type: ScanType, settings: &GridSettings) -> Self {
+        let sweeps = sweeps(volume);
+
+        let radius = volume.scans.iter().map(|scan| scan.meta.max.range).fold(0.0, f32::max);
+        let nx = (2.0 * radius / settings.spacing).ceil() as u32 + 1;
+        let ny = (settings.height / settings.vertical_spacing).ceil() as u32 + 1;
+        let dims = UVec3::new(nx, ny, nx);
+        let origin = Vec3::new(-radius, 0.0, -radius);
+        let spacing = Vec3::new(settings.spacing, settings.vertical_spacing, settings.spacing);
+
+        let mut data = vec![f32::NAN; (nx * ny * nx) as usize];
+        data.par_chunks_mut(nx as usize).enumerate().for_each(|(row, values)| {
+            let j = row as u32 % ny;
+            let k = row as u32 / ny;
+            for (i, value) in values.iter_mut().enumerate() {
+                let p = origin + spacing * Vec3::new(i as f32, j as f32, k as f32);
+                *value = sample_sweeps(&sweeps, p, scan_type, Vec3::ZERO);
+            }
+        });
+
+        Self { origin, spacing, dims, data }
+    }
+}
+
+/// Index every sweep of `volume`, sorted by elevation for `sample_sweeps`.
+pub fn sweeps(volume: &Volume) -> Vec<PolarIndex<'_>> {
+    let mut sweeps: Vec<_> = volume.scans.iter().map(|scan| PolarIndex::new(scan)).collect();
+    sweeps.sort_by(|a, b| a.elevation.total_cmp(&b.elevation));
+    sweeps
+}
+
+/// Sample a point from sweeps sorted by elevation, storm-relative velocity against `storm`. Categorical
+/// moments take the nearer sweep instead of interpolating.
+pub fn sample_sweeps(sweeps: &[PolarIndex], p: Vec3, scan_type: ScanType, storm: Vec3) -> f32 {
+    let horizontal = (p.x * p.x + p.z * p.z).sqrt();
+    let range = p.length();
+    if horizontal <= 0.0 {
+        return f32::NAN;
+    }
+    let azimuth = p.z.atan2(p.x).rem_euclid(TAU);
+    let elevation = p.y.atan2(horizontal);
+
+    let above = sweeps.partition_point(|s| s.elevation <= elevation);
+    let lower = above.checked_sub(1).map(|i| &sweeps[i]);
+    let upper = sweeps.get(above);
+
+    let near = |sweep: &PolarIndex| (elevation - sweep.elevation).abs() <= ELEVATION_TOLERANCE;
+    match (lower, upper) {
+        (Some(lower), Some(upper)) => {
+            let a = lower.sample_relative(azimuth, range, scan_type, storm);
+            let b = upper.sample_relative(azimuth, range, scan_type, storm);
+            match (a.is_nan(), b.is_nan()) {
+                (false, false) => {
+                    let t = (elevation - lower.elevation) / (upper.elevation - lower.elevation);
+                    if scan_type.categorical() {
+                        if t < 0.5 { a } else { b }
+                    } else {
+                        a + (b - a) * t
+                    }
+                }
+                (false, true) if near(lower) => a,
+                (true, false) if near(upper) => b,
+                _ => f32::NAN,
+            }
+        }
+        (Some(sweep), None) | (None, Some(sweep)) if near(sweep) => sweep.sample_relative(azimuth, range, scan_type, storm),
+        _ => f32::NAN,
+    }
+}
+
+/// Nearest-gate lookup into one sweep by azimuth and range.
+pub struct PolarIndex<'a> {
+    scan: &'a Scan,
+    pub elevation: f32,
+    first_range: f32,
+    range_step: f32,
+    rays: Vec<Option<u32>>,
+}
+
+impl<'a> PolarIndex<'a> {
+    pub fn new(scan: &'a Scan) -> Self {
+        let meta = &scan.meta;
+        let first_range = scan.gates.first().map(|g| g.range).unwrap_or(0.0);
+        let range_step = (meta.max.range - meta.min.range) / (meta.bin_count.max(2) - 1) as f32;
+
+        // Each azimuth bin points at the closest ray, as long as that ray is within one and a half beams.
+        let tolerance = meta.angular_resolution.abs().max(TAU / AZIMUTH_BINS as f32) * 1.5;
+        let mut rays = vec![None; AZIMUTH_BINS];
+        let mut best = vec![f32::MAX; AZIMUTH_BINS];
+        for ray in 0..meta.ray_count {
+            let azimuth = scan.gate(ray, 0).azimuth.rem_euclid(TAU);
+            let reach = (tolerance / TAU * AZIMUTH_BINS as f32).ceil() as isize;
+            let centre = (azimuth / TAU * AZIMUTH_BINS as f32) as isize;
+            for offset in -reach..=reach {
+                let bin = (centre + offset).rem_euclid(AZIMUTH_BINS as isize) as usize;
+                let bin_azimuth = (bin as f32 + 0.5) / AZIMUTH_BINS as f32 * TAU;
+                let distance = angular_distance(bin_azimuth, azimuth);
+                if distance <= tolerance && distance < best[bin] {
+                    best[bin] = distance;
+                    rays[bin] = Some(ray as u32);
+                }
+            }
+        }
+
+        Self {
+            scan,
+            elevation: scan.elevation(),
+            first_range,
+            range_step,
+            rays,
+        }
+    }
+
+    pub fn ray(&self, azimuth: f32) -> Option<usize> {
+        let bin = (azimuth.rem_euclid(TAU) / TAU * AZIMUTH_BINS as f32) as usize;
+        self.rays[bin.min(AZIMUTH_BINS - 1)].map(|ray| ray as usize)
+    }
+
+    pub fn bin(&self, range: f32) -> Option<usize> {
+        let bin = ((range - self.first_range) / self.range_step).round();
+        if bin < 0.0 || bin as usize >= self.scan.meta.bin_count {
+            return None;
+        }
+        Some(bin as usize)
+    }
+
+    pub fn gate(&self, azimuth: f32, range: f32) -> Option<&'a Gate> {
+        Some(self.scan.gate(self.ray(azimuth)?, self.bin(range)?))
+    }
+
+    pub fn sample(&self, azimuth: f32, range: f32, scan_type: ScanType) -> f32 {
+        self.gate(azimuth, range).map_or(f32::NAN, |gate| scan_type.value(gate))
+    }
+
+    pub fn sample_relative(&self, azimuth: f32, range: f32, scan_type: ScanType, storm: Vec3) -> f32 {
+        self.gate(azimuth, range).map_or(f32::NAN, |gate| scan_type.relative_value(gate, storm))
+    }
+}
+
+fn angular_distance(a: f32, b: f32) -> f32 {
+    let d = (a - b).rem_euclid(TAU);
+    d.min(TAU - d)
+}
+
+/// Grids built so far, keyed by volume start time and moment, with the sweeps left out of each and when
+/// it was last used. Past `MAX_GRIDS` the least recently used is dropped.
+#[derive(Resource, Default)]
+pub struct GridCache {
+    grids: HashMap<(DateTime<Utc>, ScanType), (Arc<Grid>, Vec<usize>, u64)>,
+    uses: u64,
+}
+
+impl GridCache {
+    /// The grid of the volume starting at `start` without the sweep indices in `hidden`, if one was kept.
+    pub fn cached(&mut self, start: DateTime<Utc>, scan_type: ScanType, hidden: &[usize]) -> Option<Arc<Grid>> {
+        self.uses += 1;
+        let uses = self.uses;
+        self.grids.get_mut(&(start, scan_type)).filter(|(_, built, _)| built == hidden).map(|(grid, _, used)| {
+            *used = uses;
+            grid.clone()
+        })
+    }
+
+    /// Keep a grid built elsewhere, e.g. by a meshing task.
+    pub fn insert(&mut self, start: DateTime<Utc>, scan_type: ScanType, hidden: &[usize], grid: Arc<Grid>) {
+        self.uses += 1;
+        self.grids.insert((start, scan_type), (grid, hidden.to_vec(), self.uses));
+        while self.grids.len() > MAX_GRIDS {
+            let Some(oldest) = self.grids.iter().min_by_key(|(_, (_, _, used))| *used).map(|(key, _)| *key) else {
+                break;
+            };
+            self.grids.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use bevy::math::{UVec3, Vec3};
+    use chrono::{DateTime, TimeDelta};
+    use crate::grid::{Grid, GridCache, MAX_GRIDS};
+    use crate::scan::ScanType;
+
+    #[test]
+    fn test_cache_drops_the_least_recently_used_grid() {
+        let grid = Arc::new(Grid { origin: Vec3::ZERO, spacing: Vec3::ONE, dims: UVec3::ONE, data: vec![0.0] });
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let at = |i: usize| start + TimeDelta::minutes(i as i64);
+
+        let mut cache = GridCache::default();
+        for i in 0..MAX_GRIDS {
+            cache.insert(at(i), ScanType::Reflectivity, &[], grid.clone());
+        }
+        // using the oldest keeps it over the second oldest
+        assert!(cache.cached(at(0), ScanType::Reflectivity, &[]).is_some());
+        cache.insert(at(MAX_GRIDS), ScanType::Reflectivity, &[], grid.clone());
+        assert!(cache.cached(at(1), ScanType::Reflectivity, &[]).is_none());
+        assert!((2..=MAX_GRIDS).chain([0]).all(|i| cache.cached(at(i), ScanType::Reflectivity, &[]).is_some()));
+        assert!(cache.cached(at(0), ScanType::Velocity, &[]).is_none());
+        // a grid built with other sweeps hidden doesn't stand in
+        assert!(cache.cached(at(0), ScanType::Reflectivity, &[2]).is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use bevy::prelude::Color;
+use crate::radar::Gate;
+
+/// Aggregated memberships below this leave a gate unclassified.
+const MIN_SCORE: f32 = 0.5;
+/// Half depth of the melting layer, in metres.
+const MELTING_LAYER_DEPTH: f32 = 500.0;
+
+const REFLECTIVITY_WEIGHT: f32 = 1.0;
+const DIFFERENTIAL_REFLECTIVITY_WEIGHT: f32 = 0.8;
+const CROSS_CORRELATION_WEIGHT: f32 = 0.8;
+const SPECIFIC_DIFFERENTIAL_PHASE_WEIGHT: f32 = 0.6;
+const VELOCITY_WEIGHT: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HydrometeorClass {
+    Rain,
+    Hail,
+    Graupel,
+    Snow,
+    IceCrystals,
+    Biological,
+    GroundClutter,
+    TornadoDebris,
+}
+
+/// Trapezoidal membership: 0 below `x1` and above `x4`, 1 between `x2` and `x3`.
+type Trapezoid = (f32, f32, f32, f32);
+
+struct Membership {
+    class: HydrometeorClass,
+    reflectivity: Trapezoid,
+    differential_reflectivity: Trapezoid,
+    cross_correlation: Trapezoid,
+    specific_differential_phase: Trapezoid,
+    /// Only ground clutter is told apart by its velocity.
+    velocity: Option<Trapezoid>,
+}
+
+/// X-band membership functions, loosely after Park et al. (2009) and Snyder et al. (2010).
+const MEMBERSHIPS: [Membership; 8] = [
+    Membership {
+        class: HydrometeorClass::Rain,
+        reflectivity: (5.0, 10.0, 45.0, 60.0),
+        differential_reflectivity: (0.0, 0.5, 4.0, 6.0),
+        cross_correlation: (0.95, 0.97, 1.0, 1.01),
+        specific_differential_phase: (-1.0, 0.0, 6.0, 10.0),
+        velocity: None,
+    },
+    Membership {
+        class: HydrometeorClass::Hail,
+        reflectivity: (45.0, 55.0, 75.0, 80.0),
+        differential_reflectivity: (-0.5, -0.3, 0.5, 1.0),
+        cross_correlation: (0.75, 0.8, 0.95, 0.97),
+        specific_differential_phase: (-10.0, -4.0, 1.0, 8.0),
+        velocity: None,
+    },
+    Membership {
+        class: HydrometeorClass::Graupel,
+        reflectivity: (25.0, 35.0, 50.0, 55.0),
+        differential_reflectivity: (-0.5, 0.0, 1.0, 2.0),
+        cross_correlation: (0.9, 0.97, 1.0, 1.01),
+        specific_differential_phase: (-2.0, -1.0, 1.5, 2.5),
+        velocity: None,
+    },
+    Membership {
+        class: HydrometeorClass::Snow,
+        reflectivity: (5.0, 10.0, 35.0, 40.0),
+        differential_reflectivity: (-0.3, 0.0, 0.3, 0.6),
+        cross_correlation: (0.95, 0.98, 1.0, 1.01),
+        specific_differential_phase: (-1.0, -0.5, 0.5, 1.0),
+        velocity: None,
+    },
+    Membership {
+        class: HydrometeorClass::IceCrystals,
+        reflectivity: (-10.0, 0.0, 20.0, 25.0),
+        differential_reflectivity: (0.1, 0.4, 3.0, 3.3),
+        cross_correlation: (0.95, 0.98, 1.0, 1.01),
+        specific_differential_phase: (-0.5, 0.0, 0.5, 1.0),
+        velocity: None,
+    },
+    Membership {
+        class: HydrometeorClass::Biological,
+        reflectivity: (5.0, 10.0, 20.0, 30.0),
+        differential_reflectivity: (0.0, 2.0, 10.0, 12.0),
+        cross_correlation: (0.3, 0.5, 0.8, 0.83),
+        specific_differential_phase: (-30.0, -1.0, 1.0, 30.0),
+        velocity: None,
+    },
+    Membership {
+        class: HydrometeorClass::GroundClutter,
+        reflectivity: (15.0, 20.0, 70.0, 80.0),
+        differential_reflectivity: (-4.0, -2.0, 1.0, 2.0),
+        cross_correlation: (0.5, 0.6, 0.9, 0.95),
+        specific_differential_phase: (-30.0, -4.0, 4.0, 30.0),
+        velocity: Some((-2.0, -1.0, 1.0, 2.0)),
+    },
+    Membership {
+        class: HydrometeorClass::TornadoDebris,
+        reflectivity: (35.0, 45.0, 70.0, 80.0),
+        differential_reflectivity: (-4.0, -2.0, 1.0, 2.0),
+        cross_correlation: (0.2, 0.3, 0.7, 0.82),
+        specific_differential_phase: (-30.0, -2.0, 2.0, 30.0),
+        velocity: None,
+    },
+];
+
+impl HydrometeorClass {
+    pub const ALL: [HydrometeorClass; 8] = [
+        HydrometeorClass::Rain,
+        HydrometeorClass::Hail,
+        HydrometeorClass::Graupel,
+        HydrometeorClass::Snow,
+        HydrometeorClass::IceCrystals,
+        HydrometeorClass::Biological,
+        HydrometeorClass::GroundClutter,
+        HydrometeorClass::TornadoDebris,
+    ];
+
+    /// The value stored in `Gate::hydrometeor_class`.
+    pub fn index(&self) -> f32 {
+        HydrometeorClass::ALL.iter().position(|c| c == self).unwrap() as f32
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            HydrometeorClass::Rain => Color::GREEN,
+            HydrometeorClass::Hail => Color::RED,
+            HydrometeorClass::Graupel => Color::FUCHSIA,
+            HydrometeorClass::Snow => Color::CYAN,
+            HydrometeorClass::IceCrystals => Color::ALICE_BLUE,
+            HydrometeorClass::Biological => Color::OLIVE,
+            HydrometeorClass::GroundClutter => Color::GRAY,
+            HydrometeorClass::TornadoDebris => Color::YELLOW,
+        }
+    }
+
+    /// Whether the class can exist above and below the melting layer respectively.
+    fn allowed(&self) -> (bool, bool) {
+        match self {
+            HydrometeorClass::Rain => (false, true),
+            HydrometeorClass::Snow | HydrometeorClass::IceCrystals => (true, false),
+            _ => (true, true),
+        }
+    }
+}
+
+fn trapezoid((x1, x2, x3, x4): Trapezoid, value: f32) -> f32 {
+    if value <= x1 || value >= x4 {
+        0.0
+    } else if value < x2 {
+        (value - x1) / (x2 - x1)
+    } else if value <= x3 {
+        1.0
+    } else {
+        (x4 - value) / (x4 - x3)
+    }
+}
+
+/// Classify a single gate from its attenuation corrected moments. `height` and `melting_layer` are in
+/// metres above the radar; without a melting layer every class is allowed at every height.
+pub fn classify(gate: &Gate, height: f32, melting_layer: Option<f32>) -> Option<HydrometeorClass> {
+    if gate.corrected_reflectivity.is_nan() {
+        return None;
+    }
+    let polarimetric = [
+        gate.corrected_differential_reflectivity,
+        gate.cross_correlation,
+        gate.specific_differential_phase,
+    ];
+    if polarimetric.iter().all(|value| value.is_nan()) {
+        return None;
+    }
+
+    let above = melting_layer.map_or(true, |ml| height > ml - MELTING_LAYER_DEPTH);
+    let below = melting_layer.map_or(true, |ml| height < ml + MELTING_LAYER_DEPTH);
+
+    MEMBERSHIPS.iter()
+        .filter(|m| {
+            let (allowed_above, allowed_below) = m.class.allowed();
+            (above && allowed_above) || (below && allowed_below)
+        })
+        .map(|m| {
+            let memberships = [
+                (trapezoid(m.reflectivity, gate.corrected_reflectivity), REFLECTIVITY_WEIGHT),
+                (trapezoid(m.differential_reflectivity, gate.corrected_differential_reflectivity), DIFFERENTIAL_REFLECTIVITY_WEIGHT),
+                (trapezoid(m.cross_correlation, gate.cross_correlation), CROSS_CORRELATION_WEIGHT),
+                (trapezoid(m.specific_differential_phase, gate.specific_differential_phase), SPECIFIC_DIFFERENTIAL_PHASE_WEIGHT),
+                (m.velocity.map_or(f32::NAN, |v| trapezoid(v, gate.doppler_velocity)), VELOCITY_WEIGHT),
+            ];
+            // missing moments are left out of the weighted mean
+            let (score, weight) = memberships.iter()
+                .filter(|(m, _)| !m.is_nan())
+                .fold((0.0, 0.0), |(score, weight), (m, w)| (score + m * w, weight + w));
+            (m.class, score / weight)
+        })
+        .filter(|(_, score)| *score >= MIN_SCORE)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(class, _)| class)
+}
+
+/// Fill `Gate::hydrometeor_class` for every gate.
+pub fn process(gates: &mut [Gate], melting_layer: Option<f32>) {
+    for gate in gates.iter_mut() {
+        let height = gate.range * gate.elevation.sin();
+        gate.hydrometeor_class = classify(gate, height, melting_layer).map_or(f32::NAN, |c| c.index());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hca::{classify, HydrometeorClass};
+    use crate::radar::Gate;
+
+    fn gate(dbz: f32, zdr: f32, rhohv: f32, kdp: f32) -> Gate {
+        Gate {
+            reflectivity: dbz,
+            doppler_velocity: 10.0,
+            azimuth: 0.0,
+            elevation: 0.0,
+            range: 10_000.0,
+            differential_reflectivity: zdr,
+            cross_correlation: rhohv,
+            specific_differential_phase: kdp,
+            corrected_reflectivity: dbz,
+            corrected_differential_reflectivity: zdr,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_classify_typical_echoes() {
+        let mut clutter = gate(45.0, 0.5, 0.85, 0.0);
+        clutter.doppler_velocity = 0.0;
+        assert_eq!(classify(&clutter, 50.0, None), Some(HydrometeorClass::GroundClutter));
+        assert_eq!(classify(&gate(40.0, 2.0, 0.99, 2.0), 1000.0, None), Some(HydrometeorClass::Rain));
+        assert_eq!(classify(&gate(62.0, 0.0, 0.9, 0.0), 1000.0, None), Some(HydrometeorClass::Hail));
+        assert_eq!(classify(&gate(55.0, 0.0, 0.5, 0.0), 1000.0, None), Some(HydrometeorClass::TornadoDebris));
+        assert_eq!(classify(&gate(15.0, 6.0, 0.6, 0.0), 1000.0, None), Some(HydrometeorClass::Biological));
+    }
+
+    #[test]
+    fn test_melting_layer_excludes_rain_aloft() {
+        let g = gate(30.0, 0.4, 0.99, 0.2);
+        assert_ne!(classify(&g, 6000.0, Some(3000.0)), Some(HydrometeorClass::Rain));
+        assert_ne!(classify(&g, 500.0, Some(3000.0)), Some(HydrometeorClass::Snow));
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use bevy_egui::EguiContext;
+use serde::{Deserialize, Serialize};
+use crate::panel::LeftInset;
+use crate::radar::Processing;
+use crate::rotation::RotationSettings;
+
+/// Everything the viewer can be told to do from a key, mouse or gamepad button.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    Reflectivity,
+    Velocity,
+    StormRelativeVelocity,
+    AzimuthalShear,
+    Divergence,
+    HydrometeorClass,
+    NextMoment,
+    PreviousMoment,
+    /// Held to shrink the visible window to a single sweep.
+    ShortWindow,
+    WindowBack,
+    WindowForward,
+    TogglePause,
+    ToggleRenderMode,
+    ToggleInterpolation,
+    RaiseFilter,
+    LowerFilter,
+    ToggleIsosurfaces,
+    ToggleRotation,
+    ToggleCells,
+    ToggleWindProfile,
+    CycleAccumulation,
+    CycleZrRelation,
+    ToggleQvp,
+    ToggleNowcast,
+    CycleStormMotionSource,
+    TurnStormMotionLeft,
+    TurnStormMotionRight,
+    StormMotionFaster,
+    StormMotionSlower,
+    ToggleStatistics,
+    PickSectionPoint,
+    PickStatsRegion,
+    ClearPicks,
+    Slower,
+    Faster,
+    Reverse,
+    PreviousScan,
+    NextScan,
+    Loop,
+    ToggleHelp,
+    ToggleControls,
+    CameraTopDown,
+    CameraFromSouth,
+    CameraUpBeam,
+    AddBookmark,
+    NextBookmark,
+    PlayCameraPath,
+    RecordKeyframe,
+    TogglePlanView,
+    PlanSweepUp,
+    PlanSweepDown,
+    CycleLayout,
+    LinkCameras,
+    ToggleClipping,
+    RaiseExaggeration,
+    LowerExaggeration,
+    SaveSession,
+    LoadSession,
+}
+
+/// A button, optionally with modifiers held: `Shift(Key(KeyV))` in the config file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    Gamepad(GamepadButtonType),
+    Shift(Box<Binding>),
+    Ctrl(Box<Binding>),
+    Alt(Box<Binding>),
+}
+
+impl Binding {
+    /// The button underneath any modifiers, and which of shift, ctrl and alt it needs.
+    fn parts(&self) -> (&Binding, [bool; 3]) {
+        match self {
+            Binding::Shift(inner) | Binding::Ctrl(inner) | Binding::Alt(inner) => {
+                let (button, mut modifiers) = inner.parts();
+                match self {
+                    Binding::Shift(_) => modifiers[0] = true,
+                    Binding::Ctrl(_) => modifiers[1] = true,
+                    _ => modifiers[2] = true,
+                }
+                (button, modifiers)
+            }
+            button => (button, [false; 3]),
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            Binding::Key(key) => format!("{key:?}").trim_start_matches("Key").to_string(),
+            Binding::Mouse(button) => format!("Mouse {button:?}"),
+            Binding::Gamepad(button) => format!("Pad {button:?}"),
+            Binding::Shift(inner) => format!("Shift+{}", inner.label()),
+            Binding::Ctrl(inner) => format!("Ctrl+{}", inner.label()),
+            Binding::Alt(inner) => format!("Alt+{}", inner.label()),
+        }
+    }
+}
+
+/// The bindings of every action.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputMap(pub HashMap<Action, Vec<Binding>>);
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use Action::*;
+        use Binding::{Gamepad, Key, Mouse};
+        let shift = |binding: Binding| Binding::Shift(Box::new(binding));
+        let ctrl = |binding: Binding| Binding::Ctrl(Box::new(binding));
+        let alt = |binding: Binding| Binding::Alt(Box::new(binding));
+        Self(HashMap::from([
+            (Reflectivity, vec![Key(KeyCode::KeyR)]),
+            (Velocity, vec![Key(KeyCode::KeyV)]),
+            (StormRelativeVelocity, vec![shift(Key(KeyCode::KeyV))]),
+            (AzimuthalShear, vec![Key(KeyCode::KeyS)]),
+            (Divergence, vec![Key(KeyCode::KeyD)]),
+            (HydrometeorClass, vec![Key(KeyCode::KeyH)]),
+            (NextMoment, vec![Key(KeyCode::Tab), Gamepad(GamepadButtonType::RightTrigger)]),
+            (PreviousMoment, vec![shift(Key(KeyCode::Tab)), Gamepad(GamepadButtonType::LeftTrigger)]),
+            (ShortWindow, vec![Key(KeyCode::ControlLeft), Key(KeyCode::ControlRight)]),
+            (WindowBack, vec![Key(KeyCode::ArrowLeft)]),
+            (WindowForward, vec![Key(KeyCode::ArrowRight)]),
+            (TogglePause, vec![Key(KeyCode::Space), Gamepad(GamepadButtonType::South)]),
+            (ToggleRenderMode, vec![Key(KeyCode::KeyM)]),
+            (ToggleInterpolation, vec![Key(KeyCode::KeyF)]),
+            (RaiseFilter, vec![Key(KeyCode::ArrowUp)]),
+            (LowerFilter, vec![Key(KeyCode::ArrowDown)]),
+            (ToggleIsosurfaces, vec![Key(KeyCode::KeyI)]),
+            (ToggleRotation, vec![Key(KeyCode::KeyT)]),
+            (ToggleCells, vec![Key(KeyCode::KeyC)]),
+            (ToggleWindProfile, vec![Key(KeyCode::KeyW)]),
+            (CycleAccumulation, vec![Key(KeyCode::KeyA)]),
+            (CycleZrRelation, vec![Key(KeyCode::KeyZ)]),
+            (ToggleQvp, vec![Key(KeyCode::KeyQ)]),
+            (ToggleNowcast, vec![Key(KeyCode::KeyN)]),
+            (CycleStormMotionSource, vec![Key(KeyCode::KeyG)]),
+            (TurnStormMotionLeft, vec![Key(KeyCode::BracketLeft)]),
+            (TurnStormMotionRight, vec![Key(KeyCode::BracketRight)]),
+            (StormMotionFaster, vec![Key(KeyCode::Equal)]),
+            (StormMotionSlower, vec![Key(KeyCode::Minus)]),
+            (ToggleStatistics, vec![Key(KeyCode::KeyX)]),
+            (PickSectionPoint, vec![shift(Mouse(MouseButton::Left))]),
+            (PickStatsRegion, vec![alt(Mouse(MouseButton::Left))]),
+            (ClearPicks, vec![Key(KeyCode::Escape)]),
+            (Slower, vec![Key(KeyCode::PageDown)]),
+            (Faster, vec![Key(KeyCode::PageUp)]),
+            (Reverse, vec![Key(KeyCode::KeyB)]),
+            (PreviousScan, vec![Key(KeyCode::Comma), Gamepad(GamepadButtonType::DPadLeft)]),
+            (NextScan, vec![Key(KeyCode::Period), Gamepad(GamepadButtonType::DPadRight)]),
+            (Loop, vec![Key(KeyCode::KeyL)]),
+            (ToggleHelp, vec![Key(KeyCode::F1)]),
+            (ToggleControls, vec![Key(KeyCode::KeyP)]),
+            (CameraTopDown, vec![Key(KeyCode::Digit1)]),
+            (CameraFromSouth, vec![Key(KeyCode::Digit2)]),
+            (CameraUpBeam, vec![Key(KeyCode::Digit3)]),
+            (AddBookmark, vec![shift(Key(KeyCode::KeyK))]),
+            (NextBookmark, vec![Key(KeyCode::KeyK)]),
+            (PlayCameraPath, vec![Key(KeyCode::KeyJ)]),
+            (RecordKeyframe, vec![shift(Key(KeyCode::KeyJ))]),
+            (TogglePlanView, vec![Key(KeyCode::KeyO)]),
+            (PlanSweepUp, vec![shift(Key(KeyCode::ArrowUp))]),
+            (PlanSweepDown, vec![shift(Key(KeyCode::ArrowDown))]),
+            (CycleLayout, vec![Key(KeyCode::KeyY)]),
+            (LinkCameras, vec![shift(Key(KeyCode::KeyY))]),
+            (ToggleClipping, vec![Key(KeyCode::KeyE)]),
+            (RaiseExaggeration, vec![shift(Key(KeyCode::Equal))]),
+            (LowerExaggeration, vec![shift(Key(KeyCode::Minus))]),
+            (SaveSession, vec![ctrl(Key(KeyCode::KeyS))]),
+            (LoadSession, vec![ctrl(Key(KeyCode::KeyO))]),
+        ]))
+    }
+}
+
+/// The parts of the config file read at startup. Anything missing keeps its default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub bindings: HashMap<Action, Vec<Binding>>,
+    pub processing: Processing,
+    pub rotation: RotationSettings,
+}
+
+impl Config {
+    /// The config file at `path`, or the defaults when there is none or it can't be read.
+    pub fn load(path: &str) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => ron::from_str::<Config>(&text).unwrap_or_else(|error| {
+                warn!("ignoring {path}: {error}");
+                Config::default()
+            }),
+            Err(_) => Config::default(),
+        }
+    }
+}
+
+impl InputMap {
+    /// The default bindings, with those of any action `bindings` lists replaced.
+    pub fn with_bindings(bindings: HashMap<Action, Vec<Binding>>) -> Self {
+        let mut map = Self::default();
+        map.0.extend(bindings);
+        map
+    }
+
+    /// One line per action naming its bindings, sorted by action.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<_> = self.0.iter()
+            .map(|(action, bindings)| {
+                let labels: Vec<_> = bindings.iter().map(|binding| binding.label()).collect();
+                format!("{action:?}: {}", labels.join(", "))
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+}
+
+/// Action state for systems, read from the keyboard, mouse and every connected gamepad through `InputMap`.
+/// Keys typed into a text field and clicks on the control panel go to egui instead.
+#[derive(SystemParam)]
+pub struct Actions<'w, 's> {
+    map: Res<'w, InputMap>,
+    keys: Res<'w, ButtonInput<KeyCode>>,
+    mouse: Res<'w, ButtonInput<MouseButton>>,
+    gamepad_buttons: Res<'w, ButtonInput<GamepadButton>>,
+    gamepads: Res<'w, Gamepads>,
+    egui: Query<'w, 's, &'static EguiContext, With<PrimaryWindow>>,
+}
+
+impl Actions<'_, '_> {
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.check(action, true)
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.check(action, false)
+    }
+
+    fn check(&self, action: Action, just: bool) -> bool {
+        let held = [
+            self.keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]),
+            self.keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]),
+            self.keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]),
+        ];
+        let satisfied = |modifiers: [bool; 3]| modifiers.iter().zip(held.iter()).all(|(needed, held)| !needed || *held);
+
+        self.map.0.get(&action).into_iter().flatten().any(|binding| {
+            let (button, modifiers) = binding.parts();
+            // a binding gives way to one on the same button that needs all of its modifiers and more, so
+            // Tab and Shift+Tab can do different things while Shift+click and Alt+click don't cancel out
+            let shadowed = self.map.0.values().flatten().any(|other| {
+                let (other_button, other_modifiers) = other.parts();
+                other_button == button
+                    && satisfied(other_modifiers)
+                    && other_modifiers != modifiers
+                    && modifiers.iter().zip(other_modifiers.iter()).all(|(this, other)| !this || *other)
+            });
+            satisfied(modifiers) && !shadowed && self.button(button, just)
+        })
+    }
+
+    fn button(&self, button: &Binding, just: bool) -> bool {
+        let egui = self.egui.get_single().ok().map(EguiContext::get);
+        match button {
+            Binding::Key(_) if egui.is_some_and(|ctx| ctx.wants_keyboard_input()) => false,
+            Binding::Mouse(_) if egui.is_some_and(|ctx| ctx.is_pointer_over_area() || ctx.wants_pointer_input()) => false,
+            Binding::Key(key) => if just { self.keys.just_pressed(*key) } else { self.keys.pressed(*key) },
+            Binding::Mouse(mouse) => if just { self.mouse.just_pressed(*mouse) } else { self.mouse.pressed(*mouse) },
+            Binding::Gamepad(button_type) => self.gamepads.iter().any(|gamepad| {
+                let button = GamepadButton::new(gamepad, *button_type);
+                if just { self.gamepad_buttons.just_pressed(button) } else { self.gamepad_buttons.pressed(button) }
+            }),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct HelpOverlay;
+
+pub fn setup_help(
+    mut commands: Commands,
+) {
+    commands.spawn((
+        TextBundle::from_section("", TextStyle { font_size: 16.0, ..default() })
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                top: Val::Px(160.0),
+                padding: UiRect::all(Val::Px(6.0)),
+                ..default()
+            })
+            .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.8)),
+        HelpOverlay,
+        LeftInset(10.0),
+    )).insert(Visibility::Hidden);
+}
+
+/// Show or hide the list of current bindings.
+pub fn toggle_help(
+    actions: Actions,
+    mut query: Query<(&mut Text, &mut Visibility), With<HelpOverlay>>,
+) {
+    let Ok((mut text, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    if actions.map.is_changed() {
+        text.sections[0].value = actions.map.lines().join("\n");
+    }
+    if actions.just_pressed(Action::ToggleHelp) {
+        *visibility = if *visibility == Visibility::Hidden { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::ecs::system::SystemState;
+    use bevy::input::ButtonInput;
+    use bevy::input::gamepad::{GamepadButton, Gamepads};
+    use bevy::input::keyboard::KeyCode;
+    use bevy::input::mouse::MouseButton;
+    use bevy::prelude::World;
+    use crate::dualpol::Attenuation;
+    use crate::input::{Action, Actions, Binding, Config, InputMap};
+    use crate::rotation::RotationSettings;
+
+    /// Which of `actions` are just pressed with `keys` and `buttons` held, under the default bindings.
+    fn fired(keys: &[KeyCode], buttons: &[MouseButton], actions: &[Action]) -> Vec<Action> {
+        let mut world = World::new();
+        world.insert_resource(InputMap::default());
+        let mut key_input = ButtonInput::<KeyCode>::default();
+        keys.iter().for_each(|key| key_input.press(*key));
+        world.insert_resource(key_input);
+        let mut mouse_input = ButtonInput::<MouseButton>::default();
+        buttons.iter().for_each(|button| mouse_input.press(*button));
+        world.insert_resource(mouse_input);
+        world.insert_resource(ButtonInput::<GamepadButton>::default());
+        world.insert_resource(Gamepads::default());
+
+        let mut state = SystemState::<Actions>::new(&mut world);
+        let checked = state.get(&world);
+        actions.iter().copied().filter(|action| checked.just_pressed(*action)).collect()
+    }
+
+    #[test]
+    fn test_more_modifiers_shadow_fewer_on_the_same_button() {
+        let moments = [Action::NextMoment, Action::PreviousMoment];
+        assert_eq!(fired(&[KeyCode::Tab], &[], &moments), vec![Action::NextMoment]);
+        assert_eq!(fired(&[KeyCode::ShiftLeft, KeyCode::Tab], &[], &moments), vec![Action::PreviousMoment]);
+    }
+
+    #[test]
+    fn test_unrelated_modifiers_do_not_shadow_each_other() {
+        let picks = [Action::PickSectionPoint, Action::PickStatsRegion];
+        assert_eq!(fired(&[KeyCode::ShiftLeft], &[MouseButton::Left], &picks), vec![Action::PickSectionPoint]);
+        assert_eq!(fired(&[KeyCode::AltLeft], &[MouseButton::Left], &picks), vec![Action::PickStatsRegion]);
+        // neither of Shift+click and Alt+click needs everything the other does, so both go through
+        assert_eq!(fired(&[KeyCode::ShiftLeft, KeyCode::AltLeft], &[MouseButton::Left], &picks), picks.to_vec());
+    }
+
+    #[test]
+    fn test_config_file_parses() {
+        // only the shape is checked: the bindings in it are the user's to change
+        ron::from_str::<Config>(include_str!("../config.ron")).unwrap();
+
+        let velocity = &InputMap::default().0[&Action::StormRelativeVelocity][0];
+        assert_eq!(*velocity, Binding::Shift(Box::new(Binding::Key(KeyCode::KeyV))));
+        assert_eq!(velocity.label(), "Shift+V");
+    }
+
+    #[test]
+    fn test_processing_is_read_from_the_config() {
+        let config: Config = ron::from_str("(processing: (attenuation: LinearPhidp, melting_layer: Some(3200.0)))").unwrap();
+        assert_eq!(config.processing.attenuation, Attenuation::LinearPhidp);
+        assert_eq!(config.processing.melting_layer, Some(3200.0));
+        assert!(config.bindings.is_empty());
+    }
+
+    #[test]
+    fn test_rotation_thresholds_are_read_from_the_config() {
+        let config: Config = ron::from_str("(rotation: (tvs_delta_v: 40.0, meso_shear: 0.01))").unwrap();
+        assert_eq!(config.rotation.tvs_delta_v, 40.0);
+        assert_eq!(config.rotation.meso_shear, 0.01);
+        assert_eq!(config.rotation.meso_delta_v, RotationSettings::default().meso_delta_v);
+    }
+}
@@ -17,8 +17,8 @@ use bevy::{
             RenderPhase, SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
-        view::{ExtractedView, NoFrustumCulling},
+        renderer::{RenderDevice, RenderQueue},
+        view::{ExtractedView, NoFrustumCulling, VisibleEntities},
         Render, RenderApp, RenderSet,
     },
 };
@@ -26,6 +26,8 @@ use bevy::ecs::query::ROQueryItem;
 use bevy::render::extract_component::{ComponentUniforms, DynamicUniformIndex, UniformComponentPlugin};
 use bevy::render::render_resource::binding_types::uniform_buffer;
 use bytemuck::{Pod, Zeroable};
+use bevy::render::extract_resource::ExtractResourcePlugin;
+use crate::clip::{ClipUniform, Clipping};
 use crate::radar::Gate;
 use crate::scan::{InfoChanged, ScanInfo};
 use crate::uniform::{InstanceUniforms, queue_bind_groups, SetInstanceUniformBindGroup, UniformBindGroups, UniformPlugin};
@@ -50,14 +52,17 @@ impl Plugin for CustomMaterialPlugin {
         app.add_plugins(UniformComponentPlugin::<InstanceUniforms>::default());
         app.add_plugins(ExtractComponentPlugin::<InstanceUniforms>::default());
         app.add_plugins(ExtractComponentPlugin::<InstanceMaterialData>::default());
+        app.add_plugins(ExtractResourcePlugin::<Clipping>::default());
         app.sub_app_mut(RenderApp)
             .add_render_command::<Transparent3d, DrawCustom>()
             .init_resource::<SpecializedMeshPipelines<InstancePipeline>>()
             .init_resource::<UniformBindGroups>()
+            .init_resource::<ClipBindGroup>()
             .add_systems(
                 Render,
                 (
                     queue_custom.in_set(RenderSet::QueueMeshes),
+                    prepare_clip_bind_group.in_set(RenderSet::PrepareBindGroups),
                     prepare_instance_buffers.in_set(RenderSet::PrepareResources),
                     queue_bind_groups.in_set(RenderSet::QueueMeshes),
                 ),
@@ -90,16 +95,17 @@ fn queue_custom(
     meshes: Res<RenderAssets<Mesh>>,
     render_mesh_instances: Res<RenderMeshInstances>,
     material_meshes: Query<Entity, With<InstanceMaterialData>>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    mut views: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<Transparent3d>)>,
 ) {
     let draw_custom = transparent_3d_draw_functions.read().id::<DrawCustom>();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
 
-    for (view, mut transparent_phase) in &mut views {
+    for (view, visible_entities, mut transparent_phase) in &mut views {
         let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
         let rangefinder = view.rangefinder3d();
-        for entity in &material_meshes {
+        // only what this view's render layers can see, so each panel draws its own moment
+        for &entity in visible_entities.entities.iter().filter(|entity| material_meshes.contains(**entity)) {
             let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
                 continue;
             };
@@ -152,6 +158,7 @@ pub struct InstancePipeline {
     shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
     pub uniform_layout: BindGroupLayout,
+    pub clip_layout: BindGroupLayout,
 }
 
 impl FromWorld for InstancePipeline {
@@ -170,10 +177,16 @@ impl FromWorld for InstancePipeline {
             )),
         );
 
+        let clip_layout = render_device.create_bind_group_layout(
+            "instance_clip_layout",
+            &BindGroupLayoutEntries::single(ShaderStages::VERTEX, uniform_buffer::<ClipUniform>(false)),
+        );
+
         InstancePipeline {
             shader,
             mesh_pipeline: mesh_pipeline.clone(),
             uniform_layout,
+            clip_layout,
         }
     }
 }
@@ -188,6 +201,7 @@ impl SpecializedMeshPipeline for InstancePipeline {
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
         let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
         //descriptor.layout.push(self.uniform_layout.clone());
+        descriptor.layout.push(self.clip_layout.clone());
         //dbg!(&descriptor.layout);
 
         descriptor.vertex.shader = self.shader.clone();
@@ -244,9 +258,58 @@ type DrawCustom = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
     SetMeshBindGroup<1>,
+    SetClipBindGroup<2>,
     DrawMeshInstanced,
 );
 
+/// The clipping box and planes shared by every instanced draw.
+#[derive(Resource, Default)]
+struct ClipBindGroup {
+    buffer: UniformBuffer<ClipUniform>,
+    bind_group: Option<BindGroup>,
+}
+
+fn prepare_clip_bind_group(
+    mut clip: ResMut<ClipBindGroup>,
+    clipping: Res<Clipping>,
+    pipeline: Res<InstancePipeline>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if clip.bind_group.is_some() && !clipping.is_changed() {
+        return;
+    }
+    clip.buffer.set(clipping.uniform());
+    clip.buffer.write_buffer(&render_device, &render_queue);
+    let Some(binding) = clip.buffer.binding() else {
+        return;
+    };
+    let bind_group = render_device.create_bind_group("instance_clip_bind_group", &pipeline.clip_layout, &BindGroupEntries::single(binding));
+    clip.bind_group = Some(bind_group);
+}
+
+struct SetClipBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetClipBindGroup<I> {
+    type Param = SRes<ClipBindGroup>;
+    type ViewQuery = ();
+    type ItemQuery = ();
+
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        _entity: Option<()>,
+        clip: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(bind_group) = clip.into_inner().bind_group.as_ref() else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
 
 struct DrawMeshInstanced;
 
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::view::RenderLayers;
+use bevy::tasks::{block_on, poll_once, AsyncComputeTaskPool, Task};
+use chrono::{DateTime, Utc};
+use crate::grid::{Grid, GridCache, GridSettings};
+use crate::input::{Action, Actions};
+use crate::scan::{ScanInfo, ScanType};
+use crate::views::{self, ViewLayout};
+use crate::volume::{VolumeLoaded, Volumes};
+
+/// How far below the iso level samples without radar coverage are placed.
+const EMPTY_MARGIN: f32 = 10.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IsoLevel {
+    pub scan_type: ScanType,
+    /// Negative thresholds enclose values at or below the threshold, e.g. inbound velocities.
+    pub threshold: f32,
+    pub color: Color,
+}
+
+#[derive(Resource, Debug)]
+pub struct IsosurfaceSettings {
+    pub enabled: bool,
+    pub levels: Vec<IsoLevel>,
+}
+
+impl Default for IsosurfaceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            levels: vec![
+                IsoLevel { scan_type: ScanType::Reflectivity, threshold: 40.0, color: Color::rgba(1.0, 1.0, 0.0, 0.15) },
+                IsoLevel { scan_type: ScanType::Reflectivity, threshold: 50.0, color: Color::rgba(1.0, 0.4, 0.0, 0.3) },
+                IsoLevel { scan_type: ScanType::Reflectivity, threshold: 60.0, color: Color::rgba(1.0, 0.0, 1.0, 0.6) },
+                IsoLevel { scan_type: ScanType::Velocity, threshold: 30.0, color: Color::rgba(0.0, 1.0, 0.0, 0.3) },
+                IsoLevel { scan_type: ScanType::Velocity, threshold: -30.0, color: Color::rgba(1.0, 0.0, 0.0, 0.3) },
+            ],
+        }
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct Isosurface {
+    pub scan_type: ScanType,
+    pub threshold: f32,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<IsosurfaceSettings>,
+) {
+    // toggling only hides the shells, it shouldn't mesh them all again
+    if actions.just_pressed(Action::ToggleIsosurfaces) {
+        let settings = settings.bypass_change_detection();
+        settings.enabled = !settings.enabled;
+    }
+}
+
+/// Gridding and meshing of the levels of one moment of a volume, running on the async compute pool.
+#[derive(Component)]
+pub struct MeshingIsosurfaces {
+    scan_type: ScanType,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    hidden: Vec<usize>,
+    task: Task<(Arc<Grid>, Vec<(IsoLevel, Option<Mesh>)>)>,
+}
+
+/// Start meshing every configured level for each loaded volume without shells yet, one task per moment so
+/// each grid is only built once. Changing the levels drops the shells and tasks so far and starts again, and
+/// hiding or showing a sweep does the same for its volume. Nothing is meshed while the shells are switched
+/// off; switching them on meshes whatever is missing.
+pub fn build_isosurfaces(
+    mut commands: Commands,
+    mut built: Local<Vec<IsoLevel>>,
+    mut meshed: Local<HashMap<DateTime<Utc>, Vec<usize>>>,
+    mut events: EventReader<VolumeLoaded>,
+    mut grids: ResMut<GridCache>,
+    volumes: Res<Volumes>,
+    info: Res<ScanInfo>,
+    settings: Res<IsosurfaceSettings>,
+    grid_settings: Res<GridSettings>,
+    existing: Query<(Entity, AnyOf<(&Isosurface, &MeshingIsosurfaces)>)>,
+) {
+    if !settings.enabled {
+        for VolumeLoaded(start) in events.read() {
+            meshed.remove(start);
+        }
+        return;
+    }
+
+    let starts: Vec<_> = if settings.levels != *built {
+        *built = settings.levels.clone();
+        events.clear();
+        meshed.clear();
+        existing.iter().for_each(|(entity, _)| commands.entity(entity).despawn());
+        volumes.0.keys().copied().collect()
+    } else {
+        let mut starts: Vec<_> = events.read().map(|event| event.0)
+            .chain(volumes.0.keys().filter(|start| !meshed.contains_key(start)).copied())
+            .collect();
+        starts.sort();
+        starts.dedup();
+        if info.is_changed() {
+            let changed: Vec<_> = meshed.iter()
+                .filter(|(start, hidden)| volumes.0.get(*start).is_some_and(|volume| volume.hidden_sweeps(&info.hidden_scans) != **hidden))
+                .map(|(start, _)| *start)
+                .collect();
+            for (entity, (surface, meshing)) in existing.iter() {
+                let start = surface.map(|surface| surface.start_time).or(meshing.map(|meshing| meshing.start_time));
+                if start.is_some_and(|start| changed.contains(&start)) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            starts.extend(changed);
+        }
+        starts
+    };
+
+    let pool = AsyncComputeTaskPool::get();
+    for start in starts {
+        let Some(volume) = volumes.0.get(&start) else {
+            continue;
+        };
+        let hidden = volume.hidden_sweeps(&info.hidden_scans);
+        meshed.insert(start, hidden.clone());
+
+        for scan_type in ScanType::ALL {
+            let levels: Vec<_> = settings.levels.iter().filter(|level| level.scan_type == scan_type).cloned().collect();
+            if levels.is_empty() {
+                continue;
+            }
+            let cached = grids.cached(start, scan_type, &hidden);
+            let (end_time, volume, grid_settings) = (volume.end_time, volume.without(&hidden), grid_settings.clone());
+            let task = pool.spawn(async move {
+                let grid = cached.unwrap_or_else(|| Arc::new(Grid::from_volume(&volume, scan_type, &grid_settings)));
+                let meshes = levels.into_iter().map(|level| {
+                    let mesh = surface_nets(&grid, level.threshold);
+                    (level, mesh)
+                }).collect();
+                (grid, meshes)
+            });
+            commands.spawn(MeshingIsosurfaces {
+                scan_type,
+                start_time: start,
+                end_time,
+                hidden: hidden.clone(),
+                task,
+            });
+        }
+    }
+}
+
+/// Add the shells of each finished meshing task, keeping its grid for the other gridded products.
+pub fn finish_isosurfaces(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut grids: ResMut<GridCache>,
+    mut tasks: Query<(Entity, &mut MeshingIsosurfaces)>,
+) {
+    for (entity, mut meshing) in tasks.iter_mut() {
+        let Some((grid, shells)) = block_on(poll_once(&mut meshing.task)) else {
+            continue;
+        };
+        commands.entity(entity).despawn();
+        grids.insert(meshing.start_time, meshing.scan_type, &meshing.hidden, grid);
+
+        for (level, mesh) in shells {
+            let Some(mesh) = mesh else {
+                continue;
+            };
+            commands.spawn((
+                PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: materials.add(StandardMaterial {
+                        base_color: level.color,
+                        alpha_mode: AlphaMode::Blend,
+                        double_sided: true,
+                        cull_mode: None,
+                        perceptual_roughness: 0.6,
+                        ..default()
+                    }),
+                    visibility: Visibility::Hidden,
+                    ..default()
+                },
+                Isosurface {
+                    scan_type: level.scan_type,
+                    threshold: level.threshold,
+                    start_time: meshing.start_time,
+                    end_time: meshing.end_time,
+                },
+                RenderLayers::layer(views::moment_layer(level.scan_type)),
+            ));
+        }
+    }
+}
+
+pub fn visible_isosurfaces(
+    info: Res<ScanInfo>,
+    layout: Res<ViewLayout>,
+    settings: Res<IsosurfaceSettings>,
+    mut query: Query<(&Isosurface, &mut Visibility)>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+
+    let window_start = time - info.window();
+    let moments = layout.moments(&info);
+    for (surface, mut visibility) in query.iter_mut() {
+        let visible = settings.enabled
+            && moments.contains(&surface.scan_type)
+            && surface.start_time <= time
+            && surface.end_time > window_start;
+        let wanted = if visible { Visibility::Visible } else { Visibility::Hidden };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
+
+/// Naive surface nets: one vertex per cell that straddles the level, placed at the mean of its edge
+/// crossings, and one quad per grid edge that crosses the level.
+pub fn surface_nets(grid: &Grid, threshold: f32) -> Option<Mesh> {
+    let sign = if threshold < 0.0 { -1.0 } else { 1.0 };
+    let level = threshold * sign;
+    let field: Vec<f32> = grid.data.iter()
+        .map(|v| if v.is_nan() { level - EMPTY_MARGIN } else { v * sign })
+        .collect();
+
+    let dims = grid.dims.as_ivec3();
+    let cells = dims - IVec3::ONE;
+    if cells.min_element() < 1 {
+        return None;
+    }
+    let sample = |p: IVec3| field[(p.x + dims.x * (p.y + dims.y * p.z)) as usize];
+    let cell_index = |c: IVec3| (c.x + cells.x * (c.y + cells.y * c.z)) as usize;
+
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+    let mut cell_vertex = vec![u32::MAX; (cells.x * cells.y * cells.z) as usize];
+
+    for z in 0..cells.z {
+        for y in 0..cells.y {
+            for x in 0..cells.x {
+                let cell = IVec3::new(x, y, z);
+                let corners: [f32; 8] = std::array::from_fn(|c| sample(cell + corner(c)));
+                let inside = corners.iter().filter(|v| **v >= level).count();
+                if inside == 0 || inside == 8 {
+                    continue;
+                }
+
+                let mut sum = Vec3::ZERO;
+                let mut crossings = 0;
+                for (a, b) in EDGES {
+                    let (va, vb) = (corners[a], corners[b]);
+                    if (va >= level) != (vb >= level) {
+                        let t = (level - va) / (vb - va);
+                        sum += corner(a).as_vec3().lerp(corner(b).as_vec3(), t);
+                        crossings += 1;
+                    }
+                }
+
+                let mut gradient = Vec3::ZERO;
+                for (c, v) in corners.iter().enumerate() {
+                    gradient += (corner(c).as_vec3() * 2.0 - Vec3::ONE) * *v;
+                }
+                let normal = -(gradient / grid.spacing).normalize_or_zero();
+
+                cell_vertex[cell_index(cell)] = positions.len() as u32;
+                positions.push((grid.origin + grid.spacing * (cell.as_vec3() + sum / crossings as f32)).to_array());
+                normals.push(normal.to_array());
+            }
+        }
+    }
+
+    let mut indices = Vec::new();
+    for axis in 0..3 {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let step = IVec3::from_array(std::array::from_fn(|i| (i == axis) as i32));
+        for z in 0..dims.z {
+            for y in 0..dims.y {
+                for x in 0..dims.x {
+                    // The four cells around this edge sit at p[u] - 1..=p[u] and p[v] - 1..=p[v].
+                    let p = IVec3::new(x, y, z);
+                    if p[axis] >= cells[axis] || p[u] < 1 || p[v] < 1 || p[u] >= cells[u] || p[v] >= cells[v] {
+                        continue;
+                    }
+                    let a = sample(p) >= level;
+                    if a == (sample(p + step) >= level) {
+                        continue;
+                    }
+
+                    let quad: [u32; 4] = std::array::from_fn(|q| {
+                        let mut c = p;
+                        c[u] -= (q == 0 || q == 3) as i32;
+                        c[v] -= (q == 0 || q == 1) as i32;
+                        cell_vertex[cell_index(c)]
+                    });
+                    if quad.contains(&u32::MAX) {
+                        continue;
+                    }
+
+                    if a {
+                        indices.extend([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+                    } else {
+                        indices.extend([quad[0], quad[2], quad[1], quad[0], quad[3], quad[2]]);
+                    }
+                }
+            }
+        }
+    }
+
+    if indices.is_empty() {
+        return None;
+    }
+
+    Some(
+        Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+            .with_inserted_indices(Indices::U32(indices)),
+    )
+}
+
+const EDGES: [(usize, usize); 12] = [
+    (0, 1), (2, 3), (4, 5), (6, 7),
+    (0, 2), (1, 3), (4, 6), (5, 7),
+    (0, 4), (1, 5), (2, 6), (3, 7),
+];
+
+fn corner(c: usize) -> IVec3 {
+    IVec3::new((c & 1) as i32, ((c >> 1) & 1) as i32, ((c >> 2) & 1) as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::prelude::*;
+    use bevy::render::mesh::VertexAttributeValues;
+    use crate::grid::Grid;
+    use crate::isosurface::surface_nets;
+
+    fn sphere_grid() -> Grid {
+        let dims = UVec3::splat(21);
+        let spacing = Vec3::splat(1.0);
+        let origin = Vec3::splat(-10.0);
+        let mut data = Vec::new();
+        for k in 0..dims.z {
+            for j in 0..dims.y {
+                for i in 0..dims.x {
+                    let p = origin + spacing * Vec3::new(i as f32, j as f32, k as f32);
+                    data.push(60.0 - p.length() * 5.0);
+                }
+            }
+        }
+        Grid { origin, spacing, dims, data }
+    }
+
+    #[test]
+    fn test_sphere_vertices_on_level() {
+        // 60 - 5r = 35 at r = 5
+        let mesh = surface_nets(&sphere_grid(), 35.0).unwrap();
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            panic!("no positions");
+        };
+        assert!(!positions.is_empty());
+        for p in positions {
+            let r = Vec3::from_array(*p).length();
+            assert!((r - 5.0).abs() < 0.5, "vertex at radius {}", r);
+        }
+    }
+
+    #[test]
+    fn test_negative_threshold_encloses_low_values() {
+        let mut grid = sphere_grid();
+        grid.data.iter_mut().for_each(|v| *v = -*v);
+        assert!(surface_nets(&grid, -35.0).is_some());
+        assert!(surface_nets(&grid, -80.0).is_none());
+    }
+}
@@ -0,0 +1,147 @@
+use std::f32::consts::{PI, TAU};
+use crate::radar::Gate;
+
+/// Half length of the LLSD kernel across the beam, in metres.
+const KERNEL_AZIMUTH_LENGTH: f32 = 750.0;
+/// Half length of the LLSD kernel along the beam, in metres.
+const KERNEL_RANGE_LENGTH: f32 = 750.0;
+const KERNEL_MAX_RAYS: usize = 8;
+const KERNEL_MIN_POINTS: usize = 5;
+
+/// Fill `Gate::azimuthal_shear` and `Gate::divergence` with linear least-squares derivatives of
+/// `Gate::doppler_velocity` (Smith and Elmore, 2004). `gates` is ray-major with `bins` gates per ray.
+pub fn llsd(gates: &mut [Gate], bins: usize) {
+    if bins == 0 {
+        return;
+    }
+    let rays = gates.len() / bins;
+    let range_step = if bins > 1 { (gates[bins - 1].range - gates[0].range) / (bins - 1) as f32 } else { 1.0 };
+    let range_half = ((KERNEL_RANGE_LENGTH / range_step.abs().max(1.0)).ceil() as isize).max(1);
+
+    let mut derivatives = vec![(f32::NAN, f32::NAN); gates.len()];
+    for ray in 0..rays {
+        for bin in 0..bins {
+            let centre = &gates[ray * bins + bin];
+            if centre.doppler_velocity.is_nan() || centre.range <= 0.0 {
+                continue;
+            }
+
+            let azimuth_step = azimuth_difference(gates[((ray + 1) % rays) * bins].azimuth, centre.azimuth).abs().max(1e-4);
+            let ray_half = ((KERNEL_AZIMUTH_LENGTH / (centre.range * azimuth_step)).ceil() as usize).clamp(1, KERNEL_MAX_RAYS) as isize;
+
+            // normal equations for v = a + b * s + c * dr
+            let mut m = [[0.0f64; 3]; 3];
+            let mut y = [0.0f64; 3];
+            let mut count = 0;
+            for di in -ray_half..=ray_half {
+                let other_ray = (ray as isize + di).rem_euclid(rays as isize) as usize;
+                for dj in -range_half..=range_half {
+                    let other_bin = bin as isize + dj;
+                    if other_bin < 0 || other_bin >= bins as isize {
+                        continue;
+                    }
+                    let gate = &gates[other_ray * bins + other_bin as usize];
+                    if gate.doppler_velocity.is_nan() {
+                        continue;
+                    }
+
+                    let s = (centre.range * azimuth_difference(gate.azimuth, centre.azimuth)) as f64;
+                    if s.abs() > 2.0 * KERNEL_AZIMUTH_LENGTH as f64 {
+                        continue;
+                    }
+                    let dr = (gate.range - centre.range) as f64;
+                    let row = [1.0, s, dr];
+                    for a in 0..3 {
+                        for b in 0..3 {
+                            m[a][b] += row[a] * row[b];
+                        }
+                        y[a] += row[a] * gate.doppler_velocity as f64;
+                    }
+                    count += 1;
+                }
+            }
+
+            if count < KERNEL_MIN_POINTS {
+                continue;
+            }
+            if let Some([_, shear, divergence]) = solve3(m, y) {
+                derivatives[ray * bins + bin] = (shear as f32, divergence as f32);
+            }
+        }
+    }
+
+    for (gate, (shear, divergence)) in gates.iter_mut().zip(derivatives) {
+        gate.azimuthal_shear = shear;
+        gate.divergence = divergence;
+    }
+}
+
+/// Signed difference `a - b` wrapped into (-PI, PI].
+pub fn azimuth_difference(a: f32, b: f32) -> f32 {
+    let d = (a - b).rem_euclid(TAU);
+    if d > PI { d - TAU } else { d }
+}
+
+pub fn solve3(m: [[f64; 3]; 3], y: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: &[[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(&m);
+    if d.abs() < 1e-9 {
+        return None;
+    }
+
+    // Cramer's rule
+    Some(std::array::from_fn(|column| {
+        let mut mc = m;
+        for row in 0..3 {
+            mc[row][column] = y[row];
+        }
+        det(&mc) / d
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::radar::Gate;
+    use crate::llsd::llsd;
+
+    fn gate(azimuth: f32, range: f32, doppler_velocity: f32) -> Gate {
+        Gate {
+            reflectivity: 0.0,
+            doppler_velocity,
+            azimuth,
+            range,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_llsd_recovers_linear_field() {
+        // v = 0.01 * arc distance + 0.002 * range
+        let (rays, bins) = (360, 40);
+        let mut gates = Vec::new();
+        for ray in 0..rays {
+            let azimuth = (ray as f32).to_radians();
+            for bin in 0..bins {
+                let range = 20_000.0 + bin as f32 * 250.0;
+                let arc = 25_000.0 * (azimuth - 90f32.to_radians());
+                gates.push(gate(azimuth, range, 0.01 * arc + 0.002 * range));
+            }
+        }
+
+        llsd(&mut gates, bins);
+        let g = &gates[90 * bins + 20];
+        assert!((g.divergence - 0.002).abs() < 1e-4, "divergence {}", g.divergence);
+        assert!((g.azimuthal_shear - 0.01).abs() < 1e-4, "shear {}", g.azimuthal_shear);
+    }
+
+    #[test]
+    fn test_llsd_skips_missing() {
+        let mut gates: Vec<_> = (0..10).map(|ray| gate((ray as f32).to_radians(), 10_000.0, f32::NAN)).collect();
+        llsd(&mut gates, 1);
+        assert!(gates.iter().all(|g| g.azimuthal_shear.is_nan()));
+    }
+}
@@ -2,28 +2,194 @@ mod radar;
 mod scan;
 mod instance;
 mod uniform;
+mod volume;
+mod grid;
+mod isosurface;
+mod colortable;
+mod volume_render;
+mod llsd;
+mod rotation;
+mod cells;
+mod dualpol;
+mod hca;
+mod panel;
+mod vad;
+mod rain;
+mod qvp;
+mod section;
+mod rhi;
+mod motion;
+mod nowcast;
+mod srv;
+mod stats;
+mod timeline;
+mod playback;
+mod input;
+mod controls;
+mod camera;
+mod session;
+mod plan;
+mod views;
+mod clip;
+mod scale;
 
 use bevy::prelude::*;
+use bevy::render::view::RenderLayers;
+use bevy_egui::EguiPlugin;
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
 
 use crate::instance::{CustomMaterialPlugin, InstanceData, InstanceMaterialData};
 
 fn main() {
     rayon::ThreadPoolBuilder::new().num_threads(6).build_global().unwrap();
+    let config = input::Config::load("config.ron");
+    // a session handed over on the command line opens its view in place of the last one, keeping the
+    // viewer's own bookmarks and camera path
+    let mut session = session::Session::load(session::SESSION_PATH);
+    if let Some(path) = std::env::args().nth(1) {
+        session.view = session::Session::load(&path).view;
+    }
 
     App::new()
         .insert_resource(ClearColor(Color::BLACK))//(0.52, 0.8, 0.92)))
         .add_plugins((DefaultPlugins, CustomMaterialPlugin))
         .add_plugins(PanOrbitCameraPlugin)
+        .add_plugins(EguiPlugin)
+        .add_plugins(MaterialPlugin::<volume_render::VolumeMaterial>::default())
+        .insert_resource(input::InputMap::with_bindings(config.bindings))
+        .insert_resource(config.processing)
+        .insert_resource(config.rotation)
+        .insert_resource(session)
+        .init_resource::<radar::DataSources>()
+        .init_resource::<volume::Volumes>()
+        .init_resource::<grid::GridSettings>()
+        .init_resource::<grid::GridCache>()
+        .init_resource::<isosurface::IsosurfaceSettings>()
+        .init_resource::<colortable::ColorTables>()
+        .init_resource::<volume_render::TransferFunctions>()
+        .init_resource::<cells::CellSettings>()
+        .init_resource::<cells::StormCells>()
+        .init_resource::<vad::VadSettings>()
+        .init_resource::<vad::WindProfiles>()
+        .init_resource::<rain::RainSettings>()
+        .init_resource::<rain::RainRates>()
+        .init_resource::<qvp::QvpSettings>()
+        .init_resource::<qvp::Qvps>()
+        .init_resource::<section::CrossSection>()
+        .init_resource::<rhi::RhiSettings>()
+        .init_resource::<motion::MotionSettings>()
+        .init_resource::<motion::MotionFields>()
+        .init_resource::<nowcast::NowcastSettings>()
+        .init_resource::<nowcast::Nowcasts>()
+        .init_resource::<srv::StormMotion>()
+        .init_resource::<stats::StatsSettings>()
+        .init_resource::<timeline::Timeline>()
+        .init_resource::<playback::PendingLoop>()
+        .init_resource::<controls::ControlPanel>()
+        .init_resource::<camera::CameraAnimation>()
+        .init_resource::<plan::PlanSettings>()
+        .init_resource::<views::ViewLayout>()
+        .init_resource::<clip::Clipping>()
+        .init_resource::<scale::SceneScale>()
+        .add_event::<volume::VolumeLoaded>()
+        .add_event::<session::SessionRequest>()
         .add_systems(Startup, setup)
         .add_systems(Startup, scan::setup_ui)
         .add_systems(Startup, scan::load_scans)
+        .add_systems(Startup, colortable::setup_legend)
+        .add_systems(Startup, vad::setup_hodograph)
+        .add_systems(Startup, rain::setup_accumulation_map)
+        .add_systems(Startup, qvp::setup_qvp_panel)
+        .add_systems(Startup, section::setup_cross_section)
+        .add_systems(Startup, rhi::setup_rhi_panel)
+        .add_systems(Startup, motion::setup_morph)
+        .add_systems(Startup, nowcast::setup_nowcast)
+        .add_systems(Startup, stats::setup_stats_panel)
+        .add_systems(Startup, timeline::setup_timeline)
+        .add_systems(Startup, playback::setup_playback_controls)
+        .add_systems(Startup, input::setup_help)
+        .add_systems(Startup, plan::setup_plan_view)
+        .add_systems(Startup, views::setup_views)
+        .add_systems(Startup, scale::setup_scale_bar)
         .add_systems(Update, scan::scan_loaded)
-        .add_systems(Update, scan::text_update_system)
+        .add_systems(Update, scan::rebuild_moments)
         .add_systems(Update, scan::keyboard_input)
         .add_systems(Update, scan::update_filter_system)
         .add_systems(Update, scan::visible_scans)
         .add_systems(Update, scan::move_time)
+        .add_systems(Update, isosurface::keyboard_input)
+        .add_systems(Update, isosurface::build_isosurfaces)
+        .add_systems(Update, isosurface::finish_isosurfaces)
+        .add_systems(Update, isosurface::visible_isosurfaces)
+        .add_systems(Update, volume_render::build_volumes)
+        .add_systems(Update, volume_render::finish_volumes)
+        .add_systems(Update, volume_render::update_transfer_functions)
+        .add_systems(Update, volume_render::update_alpha_power)
+        .add_systems(Update, volume_render::visible_volumes)
+        .add_systems(Update, rotation::keyboard_input)
+        .add_systems(Update, rotation::detect_rotation)
+        .add_systems(Update, rotation::visible_markers)
+        .add_systems(Update, cells::keyboard_input)
+        .add_systems(Update, cells::update_cells)
+        .add_systems(Update, cells::draw_cell_tracks)
+        .add_systems(Update, colortable::update_legend)
+        .add_systems(Update, vad::keyboard_input)
+        .add_systems(Update, vad::update_profiles)
+        .add_systems(Update, vad::draw_wind_barbs)
+        .add_systems(Update, vad::update_hodograph)
+        .add_systems(Update, rain::keyboard_input)
+        .add_systems(Update, rain::update_rain_rates)
+        .add_systems(Update, rain::update_accumulation_map)
+        .add_systems(Update, qvp::keyboard_input)
+        .add_systems(Update, qvp::update_qvps)
+        .add_systems(Update, qvp::update_qvp_panel)
+        .add_systems(Update, section::pick_points)
+        .add_systems(Update, section::draw_points)
+        .add_systems(Update, section::update_cross_section)
+        .add_systems(Update, rhi::update_rhi_panel)
+        .add_systems(Update, rhi::draw_rhi_azimuth)
+        .add_systems(Update, motion::update_motion)
+        .add_systems(Update, motion::morph_scans)
+        .add_systems(Update, nowcast::keyboard_input)
+        .add_systems(Update, nowcast::update_nowcasts)
+        .add_systems(Update, nowcast::update_forecast_map)
+        .add_systems(Update, nowcast::update_verification_text)
+        .add_systems(Update, srv::keyboard_input)
+        .add_systems(Update, srv::update_storm_relative)
+        .add_systems(Update, srv::draw_storm_motion)
+        .add_systems(Update, stats::keyboard_input)
+        .add_systems(Update, stats::pick_region)
+        .add_systems(Update, stats::draw_region)
+        .add_systems(Update, stats::update_stats_panel)
+        .add_systems(Update, timeline::update_timeline)
+        .add_systems(Update, timeline::timeline_input)
+        .add_systems(Update, timeline::draw_timeline)
+        .add_systems(Update, playback::keyboard_input)
+        .add_systems(Update, playback::playback_buttons)
+        .add_systems(Update, input::toggle_help)
+        .add_systems(Update, controls::keyboard_input)
+        .add_systems(Update, controls::control_panel.after(timeline::timeline_input))
+        .add_systems(Update, controls::place_beside_controls.after(controls::control_panel))
+        .add_systems(Update, camera::keyboard_input)
+        .add_systems(Update, camera::animate_camera)
+        .add_systems(Update, session::save_session)
+        .add_systems(Update, session::keyboard_input)
+        .add_systems(Update, session::handle_session_requests)
+        .add_systems(Update, plan::keyboard_input)
+        .add_systems(Update, plan::switch_plan_camera)
+        .add_systems(Update, plan::plan_pan_zoom)
+        .add_systems(Update, plan::update_plan_map)
+        .add_systems(Update, plan::update_plan_readout)
+        .add_systems(Update, views::keyboard_input)
+        .add_systems(Update, views::arrange_views)
+        .add_systems(Update, views::link_cameras)
+        .add_systems(Update, clip::keyboard_input)
+        .add_systems(Update, clip::drag_clip_box.after(timeline::timeline_input))
+        .add_systems(Update, clip::draw_clipping)
+        .add_systems(Update, scale::keyboard_input)
+        .add_systems(Update, scale::apply_exaggeration)
+        .add_systems(Update, scale::update_scale_bar)
+        .add_systems(Update, scale::draw_height_axis)
         .run();
 }
 
@@ -34,17 +200,31 @@ fn setup(
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut ambient: ResMut<AmbientLight>,
 ) {
-    // camera
-    commands.spawn((
-        Camera3dBundle {
-            transform: Transform::from_xyz(0.0,25_000.0,0.0).looking_at(Vec3::new(-25_000.0, -25_000.0, -25_000.0), Vec3::Y),
-            ..default()
-        },
-        PanOrbitCamera::default(),
-    ));
+    // camera, on a rig that is squashed for the vertical exaggeration
+    commands.spawn((SpatialBundle::default(), scale::CameraRig)).with_children(|rig| {
+        rig.spawn((
+            Camera3dBundle {
+                transform: Transform::from_xyz(0.0,25_000.0,0.0).looking_at(Vec3::new(-25_000.0, -25_000.0, -25_000.0), Vec3::Y),
+                ..default()
+            },
+            PanOrbitCamera::default(),
+            views::ViewPanel(0),
+            RenderLayers::layer(0),
+        ));
+    });
 
     ambient.brightness = 1000.0;
 
+    // gives the isosurface shells some shape
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            illuminance: 2_000.0,
+            ..default()
+        },
+        transform: Transform::from_xyz(1.0, 2.0, 0.5).looking_at(Vec3::ZERO, Vec3::Y),
+        ..default()
+    });
+
     commands.spawn(PbrBundle {
         mesh: meshes.add(Circle::new(50_000.0)),
         material: materials.add(Color::rgb(65.0/255.0, 152.0/255.0, 10.0/255.0)),
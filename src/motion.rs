@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use bevy::prelude::*;
+use bevy::render::view::{NoFrustumCulling, RenderLayers};
+use chrono::{DateTime, TimeDelta, Utc};
+use rayon::prelude::*;
+use crate::grid::PolarIndex;
+use crate::instance::{InstanceData, InstanceMaterialData};
+use crate::radar::{Scan, ScanMetadata};
+use crate::scan::{RenderMode, ScanInfo, ScanType};
+use crate::uniform::InstanceUniforms;
+use crate::views;
+use crate::volume::{Volume, VolumeLoaded, Volumes};
+
+/// Sweeps of the same index whose fixed angles differ by more than this are not morphed into each other.
+const FIXED_ANGLE_TOLERANCE: f32 = 0.0087;
+
+#[derive(Resource, Debug, Clone)]
+pub struct MotionSettings {
+    /// Spacing of the reflectivity images that are matched, in m.
+    pub spacing: f32,
+    /// Side of the matched blocks, in image cells.
+    pub block: usize,
+    /// Fastest motion searched for, in m/s.
+    pub max_speed: f32,
+    /// Blocks with echo in fewer than this fraction of their cells take the mean motion instead.
+    pub min_coverage: f32,
+    pub min_reflectivity: f32,
+    /// Sweeps further apart than this are not interpolated across.
+    pub max_gap_minutes: i64,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 1000.0,
+            block: 8,
+            max_speed: 40.0,
+            min_coverage: 0.1,
+            min_reflectivity: 15.0,
+            max_gap_minutes: 10,
+        }
+    }
+}
+
+/// Horizontal motion between two volumes, one vector per block on a square grid centred on the radar.
+#[derive(Debug, Clone)]
+pub struct MotionField {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// Ground position of the first block's corner, `x` then `z`.
+    pub origin: Vec2,
+    pub block_size: f32,
+    pub columns: usize,
+    /// Motion of each block in m/s, `x` varying fastest.
+    pub vectors: Vec<Vec2>,
+}
+
+impl MotionField {
+    /// Motion at a point, bilinearly interpolated between block centres.
+    pub fn at(&self, p: Vec3) -> Vec3 {
+        let rows = self.vectors.len() / self.columns.max(1);
+        if rows == 0 {
+            return Vec3::ZERO;
+        }
+        let cell = (Vec2::new(p.x, p.z) - self.origin) / self.block_size - 0.5;
+        let max = Vec2::new(self.columns as f32 - 1.0, rows as f32 - 1.0);
+        let cell = cell.clamp(Vec2::ZERO, max);
+        let (i, k) = (cell.x as usize, cell.y as usize);
+        let (i1, k1) = ((i + 1).min(self.columns - 1), (k + 1).min(rows - 1));
+        let (tx, tz) = (cell.x - i as f32, cell.y - k as f32);
+
+        let v = |i: usize, k: usize| self.vectors[k * self.columns + i];
+        let motion = v(i, k).lerp(v(i1, k), tx).lerp(v(i, k1).lerp(v(i1, k1), tx), tz);
+        Vec3::new(motion.x, 0.0, motion.y)
+    }
+}
+
+/// Reflectivity of one sweep projected onto an `n` by `n` ground image, as dB above `min_reflectivity`.
+/// Gates without echo are zero so that they still match other empty gates.
+fn image(scan: &Scan, radius: f32, n: usize, settings: &MotionSettings) -> Vec<f32> {
+    let index = PolarIndex::new(scan);
+    let mut data = vec![0.0; n * n];
+    data.par_chunks_mut(n).enumerate().for_each(|(k, row)| {
+        for (i, value) in row.iter_mut().enumerate() {
+            let x = -radius + (i as f32 + 0.5) * settings.spacing;
+            let z = -radius + (k as f32 + 0.5) * settings.spacing;
+            let range = (x * x + z * z).sqrt() / scan.elevation().cos();
+            let dbz = index.sample(z.atan2(x), range, ScanType::Reflectivity);
+            if dbz >= settings.min_reflectivity {
+                *value = dbz - settings.min_reflectivity;
+            }
+        }
+    });
+    data
+}
+
+/// Block matching between two `n` by `n` images taken `seconds` apart: every block of `a` is compared
+/// against every shift of `b` within `max_speed`, and keeps the shift with the smallest absolute difference.
+/// Blocks without enough echo are `None`.
+pub fn estimate(a: &[f32], b: &[f32], n: usize, seconds: f32, settings: &MotionSettings) -> Vec<Option<Vec2>> {
+    let block = settings.block;
+    let blocks = n / block;
+    let search = (settings.max_speed * seconds / settings.spacing).ceil() as isize;
+    let sample = |image: &[f32], i: isize, k: isize| {
+        if i < 0 || k < 0 || i >= n as isize || k >= n as isize { 0.0 } else { image[k as usize * n + i as usize] }
+    };
+
+    (0..blocks * blocks).into_par_iter()
+        .map(|index| {
+            let (bi, bk) = ((index % blocks * block) as isize, (index / blocks * block) as isize);
+            let cells = (0..block as isize).flat_map(|dk| (0..block as isize).map(move |di| (bi + di, bk + dk)));
+            let echo = cells.clone().filter(|&(i, k)| sample(a, i, k) > 0.0).count();
+            if (echo as f32) < settings.min_coverage * (block * block) as f32 {
+                return None;
+            }
+
+            let mut best = (f32::MAX, Vec2::ZERO);
+            for sz in -search..=search {
+                for sx in -search..=search {
+                    let shift = Vec2::new(sx as f32, sz as f32);
+                    // the small penalty on distance settles ties in favour of the slower motion
+                    let cost = cells.clone()
+                        .map(|(i, k)| (sample(a, i, k) - sample(b, i + sx, k + sz)).abs())
+                        .sum::<f32>() + shift.length_squared() * 1e-3;
+                    if cost < best.0 {
+                        best = (cost, shift);
+                    }
+                }
+            }
+            Some(best.1 * settings.spacing / seconds)
+        })
+        .collect()
+}
+
+/// Estimate the motion between the lowest sweeps of two volumes. Blocks without enough echo take the mean
+/// of the others, and the result is smoothed once.
+pub fn motion_field(from: &Volume, to: &Volume, settings: &MotionSettings) -> Option<MotionField> {
+    let lowest = |volume: &Volume| volume.scans.iter().min_by(|a, b| a.elevation().total_cmp(&b.elevation())).cloned();
+    let (a, b) = (lowest(from)?, lowest(to)?);
+    let seconds = (b.meta.start_time - a.meta.start_time).num_milliseconds() as f32 / 1000.0;
+    if seconds <= 0.0 {
+        return None;
+    }
+
+    let radius = a.meta.max.range.max(b.meta.max.range);
+    let n = (2.0 * radius / settings.spacing).ceil() as usize;
+    let estimates = estimate(&image(&a, radius, n, settings), &image(&b, radius, n, settings), n, seconds, settings);
+
+    let found: Vec<_> = estimates.iter().flatten().collect();
+    let mean = if found.is_empty() { Vec2::ZERO } else { found.iter().copied().sum::<Vec2>() / found.len() as f32 };
+    let filled: Vec<_> = estimates.iter().map(|v| v.unwrap_or(mean)).collect();
+
+    let columns = n / settings.block;
+    let vectors = (0..filled.len())
+        .map(|index| {
+            let (i, k) = ((index % columns) as isize, (index / columns) as isize);
+            let neighbours: Vec<_> = (-1..=1)
+                .flat_map(|dk| (-1..=1).map(move |di| (i + di, k + dk)))
+                .filter(|&(i, k)| i >= 0 && k >= 0 && i < columns as isize && k < columns as isize)
+                .map(|(i, k)| filled[k as usize * columns + i as usize])
+                .collect();
+            neighbours.iter().copied().sum::<Vec2>() / neighbours.len() as f32
+        })
+        .collect();
+
+    Some(MotionField {
+        start_time: from.start_time,
+        end_time: to.start_time,
+        origin: Vec2::splat(-radius),
+        block_size: settings.spacing * settings.block as f32,
+        columns,
+        vectors,
+    })
+}
+
+/// Motion fields keyed by the start of the earlier volume of each consecutive pair.
+#[derive(Resource, Default)]
+pub struct MotionFields(pub BTreeMap<DateTime<Utc>, MotionField>);
+
+pub fn update_motion(
+    mut events: EventReader<VolumeLoaded>,
+    mut fields: ResMut<MotionFields>,
+    volumes: Res<Volumes>,
+    settings: Res<MotionSettings>,
+) {
+    let starts: Vec<_> = if settings.is_changed() && !settings.is_added() {
+        events.clear();
+        fields.0.clear();
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+
+    let max_gap = TimeDelta::minutes(settings.max_gap_minutes);
+    for start in starts {
+        let previous = volumes.0.range(..start).next_back().map(|(&time, _)| time);
+        let next = volumes.0.range(start..).nth(1).map(|(&time, _)| time);
+        let pairs = [previous.map(|previous| (previous, start)), next.map(|next| (start, next))];
+        for (from, to) in pairs.into_iter().flatten() {
+            if to - from > max_gap {
+                continue;
+            }
+            if let Some(field) = motion_field(&volumes.0[&from], &volumes.0[&to], &settings) {
+                fields.0.insert(from, field);
+            }
+        }
+    }
+}
+
+/// Gates of the current moment, advected to `ScanInfo::time` while interpolation is on.
+#[derive(Component)]
+pub struct Morph;
+
+pub fn setup_morph(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    commands.spawn((
+        meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
+        SpatialBundle {
+            visibility: Visibility::Hidden,
+            ..SpatialBundle::INHERITED_IDENTITY
+        },
+        InstanceUniforms {
+            alpha_power: 5.0,
+        },
+        NoFrustumCulling,
+        Morph,
+    ));
+}
+
+/// `instance` moved by `offset` with alpha scaled by `weight`.
+fn displaced(instance: &InstanceData, offset: Vec3, weight: f32) -> InstanceData {
+    let mut instance = *instance;
+    instance.position = instance.position + offset;
+    let mut transform = instance.transform;
+    transform[12] += offset.x;
+    transform[13] += offset.y;
+    transform[14] += offset.z;
+    instance.transform = transform;
+    let mut color = instance.color;
+    color[3] *= weight;
+    instance.color = color;
+    instance
+}
+
+/// One sweep index being morphed: the sweep before the current time, and the one after it when there is
+/// one to fade into. `range`s index `MorphCache::base`.
+struct MorphSpan {
+    before_end: DateTime<Utc>,
+    after_end: Option<DateTime<Utc>>,
+    before: std::ops::Range<usize>,
+    after: std::ops::Range<usize>,
+}
+
+/// The sweeps being morphed and the motion of each of their gates, kept while the same sweeps bracket
+/// the current time so playback only redoes the weights and offsets.
+#[derive(Default)]
+pub struct MorphCache {
+    pairs: Vec<(Entity, Option<Entity>)>,
+    spans: Vec<MorphSpan>,
+    base: Vec<InstanceData>,
+    motion: Vec<Vec3>,
+}
+
+/// For each sweep index, cross-fade the sweep before `ScanInfo::time` into the one after it, moving both
+/// along the motion field so echoes travel instead of popping. A sweep with no successor is held as is.
+pub fn morph_scans(
+    mut commands: Commands,
+    mut shown: Local<Option<(DateTime<Utc>, ScanType, usize, HashSet<(DateTime<Utc>, usize)>)>>,
+    mut cache: Local<MorphCache>,
+    mut morph: Query<(Entity, &mut Visibility, Option<&mut InstanceMaterialData>), With<Morph>>,
+    scans: Query<(Entity, &ScanMetadata, &ScanType, &InstanceMaterialData), Without<Morph>>,
+    info: Res<ScanInfo>,
+    fields: Res<MotionFields>,
+    settings: Res<MotionSettings>,
+) {
+    let Ok((entity, mut visibility, morphed)) = morph.get_single_mut() else {
+        return;
+    };
+    let time = match info.time {
+        Some(time) if info.interpolate && info.render_mode == RenderMode::Instanced => time,
+        _ => {
+            if *visibility != Visibility::Hidden {
+                *visibility = Visibility::Hidden;
+            }
+            *shown = None;
+            return;
+        }
+    };
+
+    let key = (time, info.scan_type, info.loaded_scans, info.hidden_scans.clone());
+    if shown.as_ref() == Some(&key) && !fields.is_changed() {
+        return;
+    }
+    *shown = Some(key);
+
+    // pick the sweeps from their metadata alone, only copying gates when the pick changes
+    let mut sweeps: HashMap<usize, (Option<(Entity, &ScanMetadata)>, Option<(Entity, &ScanMetadata)>)> = HashMap::new();
+    for (scan, meta, scan_type, _) in scans.iter() {
+        if *scan_type != info.scan_type || info.hidden_scans.contains(&(meta.volume_start, meta.sweep_index)) {
+            continue;
+        }
+        let (before, after) = sweeps.entry(meta.sweep_index).or_default();
+        if meta.end_time <= time {
+            if before.map_or(true, |(_, b)| meta.end_time > b.end_time) {
+                *before = Some((scan, meta));
+            }
+        } else if after.map_or(true, |(_, a)| meta.end_time < a.end_time) {
+            *after = Some((scan, meta));
+        }
+    }
+
+    let max_gap = TimeDelta::minutes(settings.max_gap_minutes);
+    let mut picked: Vec<_> = sweeps.into_iter()
+        .filter_map(|(index, (before, after))| {
+            let (a, a_meta) = before.filter(|(_, a)| time - a.end_time <= max_gap)?;
+            let after = after
+                .filter(|(_, b)| b.end_time - a_meta.end_time <= max_gap && (b.fixed_angle - a_meta.fixed_angle).abs() <= FIXED_ANGLE_TOLERANCE)
+                .and_then(|(b, b_meta)| {
+                    let field = fields.0.get(&a_meta.volume_start).filter(|field| field.end_time == b_meta.volume_start)?;
+                    Some((b, b_meta, field))
+                });
+            Some((index, a, a_meta, after))
+        })
+        .collect();
+    picked.sort_by_key(|(index, ..)| *index);
+
+    let pairs: Vec<_> = picked.iter().map(|(_, a, _, after)| (*a, after.map(|(b, ..)| b))).collect();
+    if pairs != cache.pairs || fields.is_changed() {
+        let mut rebuilt = MorphCache { pairs, ..default() };
+        for (_, a, a_meta, after) in picked {
+            let Ok((.., a_data)) = scans.get(a) else {
+                continue;
+            };
+            let at = |p: Vec3| after.map_or(Vec3::ZERO, |(_, _, field)| field.at(p));
+            let start = rebuilt.base.len();
+            rebuilt.base.extend(a_data.iter().copied());
+            rebuilt.motion.extend(a_data.iter().map(|instance| at(instance.position)));
+            let middle = rebuilt.base.len();
+            if let Some((.., b_data)) = after.and_then(|(b, ..)| scans.get(b).ok()) {
+                rebuilt.base.extend(b_data.iter().copied());
+                rebuilt.motion.extend(b_data.iter().map(|instance| at(instance.position)));
+            }
+            rebuilt.spans.push(MorphSpan {
+                before_end: a_meta.end_time,
+                after_end: after.map(|(_, b_meta, _)| b_meta.end_time),
+                before: start..middle,
+                after: middle..rebuilt.base.len(),
+            });
+        }
+        *cache = rebuilt;
+    }
+
+    if cache.base.is_empty() {
+        commands.entity(entity).remove::<InstanceMaterialData>();
+        *visibility = Visibility::Hidden;
+        return;
+    }
+
+    let seconds = |delta: TimeDelta| delta.num_milliseconds() as f32 / 1000.0;
+    let mut instances = Vec::with_capacity(cache.base.len());
+    for span in cache.spans.iter() {
+        let before = cache.base[span.before.clone()].iter().zip(&cache.motion[span.before.clone()]);
+        let after = cache.base[span.after.clone()].iter().zip(&cache.motion[span.after.clone()]);
+        match span.after_end {
+            Some(after_end) => {
+                let since = seconds(time - span.before_end);
+                let until = seconds(after_end - time);
+                let f = since / (since + until);
+                instances.extend(before.map(|(instance, motion)| displaced(instance, *motion * since, 1.0 - f)));
+                instances.extend(after.map(|(instance, motion)| displaced(instance, -*motion * until, f)));
+            }
+            None => instances.extend(before.map(|(instance, _)| displaced(instance, Vec3::ZERO, 1.0))),
+        }
+    }
+
+    match morphed {
+        Some(mut morphed) => morphed.0 = instances,
+        None => {
+            commands.entity(entity).insert(InstanceMaterialData(instances));
+        }
+    }
+    commands.entity(entity).insert(RenderLayers::layer(views::moment_layer(info.scan_type)));
+    if *visibility != Visibility::Visible {
+        *visibility = Visibility::Visible;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::math::Vec2;
+    use bevy::utils::default;
+    use crate::motion::{estimate, MotionSettings};
+
+    #[test]
+    fn block_matching_follows_a_moving_blob() {
+        let settings = MotionSettings {
+            block: 8,
+            ..default()
+        };
+        let n = 32;
+        let blob = |ci: f32, ck: f32| -> Vec<f32> {
+            (0..n * n)
+                .map(|index| {
+                    let (i, k) = ((index % n) as f32, (index / n) as f32);
+                    (30.0 - 3.0 * ((i - ci).powi(2) + (k - ck).powi(2)).sqrt()).max(0.0)
+                })
+                .collect()
+        };
+        let (a, b) = (blob(12.0, 12.0), blob(15.0, 14.0));
+
+        let seconds = 300.0;
+        let estimates = estimate(&a, &b, n, seconds, &settings);
+        let expected = Vec2::new(3.0, 2.0) * settings.spacing / seconds;
+        for estimate in estimates.iter().flatten() {
+            assert!(estimate.distance(expected) < 1e-3, "{estimate} != {expected}");
+        }
+        assert!(estimates.iter().flatten().count() > 0);
+        assert!(estimates.iter().any(|estimate| estimate.is_none()));
+    }
+}
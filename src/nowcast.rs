@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use bevy::prelude::*;
+use chrono::{DateTime, TimeDelta, Utc};
+use rayon::prelude::*;
+use crate::colortable::ColorTables;
+use crate::grid::{self, Grid};
+use crate::input::{Action, Actions};
+use crate::motion::{MotionField, MotionFields};
+use crate::panel::LeftInset;
+use crate::rain;
+use crate::scan::{ScanInfo, ScanType};
+use crate::volume::{Volume, VolumeLoaded, Volumes};
+
+#[derive(Resource, Debug, Clone)]
+pub struct NowcastSettings {
+    pub enabled: bool,
+    /// Number of past motion fields averaged into the advecting motion.
+    pub history: usize,
+    pub step_minutes: i64,
+    pub lead_minutes: i64,
+    pub spacing: f32,
+    /// Forecast and observed reflectivity at or above this count as an event when verifying.
+    pub threshold: f32,
+}
+
+impl Default for NowcastSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            history: 3,
+            step_minutes: 5,
+            lead_minutes: 60,
+            spacing: 1000.0,
+            threshold: 35.0,
+        }
+    }
+}
+
+/// Column maximum reflectivity over all sweeps of `volume` on a single-level grid at ground level.
+pub fn composite(volume: &Volume, spacing: f32) -> Grid {
+    let sweeps = grid::sweeps(volume);
+    let radius = volume.scans.iter().map(|scan| scan.meta.max.range).fold(0.0, f32::max);
+    let n = (2.0 * radius / spacing).ceil() as u32 + 1;
+    let origin = Vec3::new(-radius, 0.0, -radius);
+
+    let mut data = vec![f32::NAN; (n * n) as usize];
+    data.par_chunks_mut(n as usize).enumerate().for_each(|(k, row)| {
+        for (i, value) in row.iter_mut().enumerate() {
+            let p = origin + spacing * Vec3::new(i as f32, 0.0, k as f32);
+            let horizontal = (p.x * p.x + p.z * p.z).sqrt();
+            let azimuth = p.z.atan2(p.x);
+            *value = sweeps.iter()
+                .map(|sweep| sweep.sample(azimuth, horizontal / sweep.elevation.cos(), ScanType::Reflectivity))
+                .filter(|dbz| !dbz.is_nan())
+                .reduce(f32::max)
+                .unwrap_or(f32::NAN);
+        }
+    });
+
+    Grid { origin, spacing: Vec3::new(spacing, 1.0, spacing), dims: UVec3::new(n, 1, n), data }
+}
+
+/// Mean of the last `history` motion fields that end at or before `issued`.
+pub fn mean_motion(fields: &MotionFields, issued: DateTime<Utc>, history: usize) -> Option<MotionField> {
+    let recent: Vec<_> = fields.0.range(..issued).rev().map(|(_, field)| field).filter(|field| field.end_time <= issued).take(history).collect();
+    let latest = recent.first()?;
+    let same: Vec<_> = recent.iter().filter(|field| field.vectors.len() == latest.vectors.len()).collect();
+    let vectors = (0..latest.vectors.len())
+        .map(|i| same.iter().map(|field| field.vectors[i]).sum::<Vec2>() / same.len() as f32)
+        .collect();
+    let start_time = same.iter().map(|field| field.start_time).min()?;
+    Some(MotionField { start_time, vectors, ..(*latest).clone() })
+}
+
+/// Semi-Lagrangian advection: each cell takes the value found upstream along the motion, `seconds` earlier.
+pub fn advect(grid: &Grid, motion: &MotionField, seconds: f32) -> Grid {
+    let n = grid.dims.x as usize;
+    let mut data = vec![f32::NAN; grid.data.len()];
+    data.par_chunks_mut(n).enumerate().for_each(|(k, row)| {
+        for (i, value) in row.iter_mut().enumerate() {
+            let p = grid.origin + grid.spacing * Vec3::new(i as f32, 0.0, k as f32);
+            let source = ((p - motion.at(p) * seconds - grid.origin) / grid.spacing).round();
+            if source.x >= 0.0 && source.z >= 0.0 && (source.x as u32) < grid.dims.x && (source.z as u32) < grid.dims.z {
+                *value = grid.data[source.z as usize * n + source.x as usize];
+            }
+        }
+    });
+    Grid { data, ..grid.clone() }
+}
+
+/// Forecast frames extrapolated from one volume, every `step_minutes` up to `lead_minutes`.
+#[derive(Clone)]
+pub struct Nowcast {
+    pub frames: Vec<(DateTime<Utc>, Arc<Grid>)>,
+}
+
+/// 2x2 contingency counts of forecast against observed events.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Contingency {
+    pub hits: usize,
+    pub misses: usize,
+    pub false_alarms: usize,
+}
+
+impl Contingency {
+    /// Count every cell where both grids have data.
+    pub fn add(&mut self, forecast: &Grid, observed: &Grid, threshold: f32) {
+        for (f, o) in forecast.data.iter().zip(observed.data.iter()) {
+            if f.is_nan() || o.is_nan() {
+                continue;
+            }
+            match (*f >= threshold, *o >= threshold) {
+                (true, true) => self.hits += 1,
+                (false, true) => self.misses += 1,
+                (true, false) => self.false_alarms += 1,
+                (false, false) => {}
+            }
+        }
+    }
+
+    /// Critical success index, or `None` before any event was forecast or observed.
+    pub fn csi(&self) -> Option<f32> {
+        ratio(self.hits, self.hits + self.misses + self.false_alarms)
+    }
+
+    /// Probability of detection, or `None` before any event was observed.
+    pub fn pod(&self) -> Option<f32> {
+        ratio(self.hits, self.hits + self.misses)
+    }
+
+    /// False alarm ratio, or `None` before any event was forecast.
+    pub fn far(&self) -> Option<f32> {
+        ratio(self.false_alarms, self.hits + self.false_alarms)
+    }
+}
+
+fn ratio(count: usize, total: usize) -> Option<f32> {
+    (total > 0).then(|| count as f32 / total as f32)
+}
+
+/// A nowcast from every volume with motion behind it, keyed by volume start, and their scores against the
+/// volumes observed later, summed per lead time.
+#[derive(Resource, Default)]
+pub struct Nowcasts {
+    pub composites: BTreeMap<DateTime<Utc>, Arc<Grid>>,
+    pub nowcasts: BTreeMap<DateTime<Utc>, Nowcast>,
+    pub scores: Vec<(i64, Contingency)>,
+}
+
+impl Nowcasts {
+    /// The observed composite closest to `time`, if one is within `tolerance`.
+    fn observed(&self, time: DateTime<Utc>, tolerance: TimeDelta) -> Option<&Arc<Grid>> {
+        let before = self.composites.range(..=time).next_back();
+        let after = self.composites.range(time..).next();
+        [before, after].into_iter().flatten()
+            .filter(|(start, _)| (**start - time).abs() <= tolerance)
+            .min_by_key(|(start, _)| (**start - time).abs())
+            .map(|(_, grid)| grid)
+    }
+}
+
+pub fn update_nowcasts(
+    mut events: EventReader<VolumeLoaded>,
+    mut nowcasts: ResMut<Nowcasts>,
+    volumes: Res<Volumes>,
+    fields: Res<MotionFields>,
+    settings: Res<NowcastSettings>,
+) {
+    if !settings.enabled {
+        events.clear();
+        return;
+    }
+    let starts: Vec<_> = if settings.is_changed() {
+        events.clear();
+        nowcasts.composites.clear();
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+    if starts.is_empty() && !fields.is_changed() {
+        return;
+    }
+
+    for start in starts {
+        if let Some(volume) = volumes.0.get(&start) {
+            nowcasts.composites.insert(start, Arc::new(composite(volume, settings.spacing)));
+        }
+    }
+
+    let step = TimeDelta::minutes(settings.step_minutes);
+    let issued: Vec<_> = nowcasts.composites.iter()
+        .filter_map(|(&start, grid)| {
+            let motion = mean_motion(&fields, start, settings.history)?;
+            let frames = (1..=settings.lead_minutes / settings.step_minutes)
+                .map(|k| (start + step * k as i32, Arc::new(advect(grid, &motion, (step * k as i32).num_seconds() as f32))))
+                .collect();
+            Some((start, Nowcast { frames }))
+        })
+        .collect();
+    nowcasts.nowcasts = issued.into_iter().collect();
+
+    let mut scores: Vec<_> = (1..=settings.lead_minutes / settings.step_minutes)
+        .map(|k| (k * settings.step_minutes, Contingency::default()))
+        .collect();
+    for nowcast in nowcasts.nowcasts.values() {
+        for ((time, forecast), (_, score)) in nowcast.frames.iter().zip(scores.iter_mut()) {
+            if let Some(observed) = nowcasts.observed(*time, step / 2).filter(|grid| grid.dims == forecast.dims) {
+                score.add(forecast, observed, settings.threshold);
+            }
+        }
+    }
+    nowcasts.scores = scores;
+}
+
+#[derive(Component)]
+pub struct ForecastMap {
+    image: Handle<Image>,
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
+#[derive(Component)]
+pub struct VerificationText;
+
+pub fn setup_nowcast(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    tables: Res<ColorTables>,
+) {
+    let empty = Grid { origin: Vec3::ZERO, spacing: Vec3::ONE, dims: UVec3::ONE, data: vec![f32::NAN] };
+    let image = images.add(rain::map_image(&empty, tables.get(ScanType::Reflectivity)));
+    let mesh = meshes.add(rain::map_mesh(&empty));
+    let material = materials.add(StandardMaterial {
+        base_color: Color::rgba(1.0, 1.0, 1.0, 0.5),
+        base_color_texture: Some(image.clone()),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        cull_mode: None,
+        double_sided: true,
+        ..default()
+    });
+    commands.spawn((
+        PbrBundle {
+            mesh: mesh.clone(),
+            material: material.clone(),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        ForecastMap { image, mesh, material },
+    ));
+
+    commands.spawn((
+        TextBundle::from_section("", TextStyle { font_size: 16.0, ..default() })
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(430.0),
+                bottom: Val::Px(50.0),
+                ..default()
+            })
+            .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.5)),
+        LeftInset(430.0),
+        VerificationText,
+    ));
+}
+
+/// Once `ScanInfo::time` is past the last observed volume, ghost the latest nowcast's frame for that time
+/// over the ground.
+pub fn update_forecast_map(
+    mut shown: Local<Option<DateTime<Utc>>>,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut query: Query<(&ForecastMap, &mut Visibility)>,
+    info: Res<ScanInfo>,
+    settings: Res<NowcastSettings>,
+    volumes: Res<Volumes>,
+    nowcasts: Res<Nowcasts>,
+    tables: Res<ColorTables>,
+) {
+    let Ok((map, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let observed = volumes.0.values().next_back().map(|volume| volume.end_time);
+    let frame = match (info.time, observed, nowcasts.nowcasts.values().next_back()) {
+        (Some(time), Some(observed), Some(nowcast)) if settings.enabled && time > observed => {
+            nowcast.frames.iter().rev().find(|(valid, _)| *valid <= time)
+        }
+        _ => None,
+    };
+    let Some((valid, grid)) = frame else {
+        *visibility = Visibility::Hidden;
+        *shown = None;
+        return;
+    };
+    *visibility = Visibility::Visible;
+    if *shown == Some(*valid) && !nowcasts.is_changed() {
+        return;
+    }
+    *shown = Some(*valid);
+
+    images.insert(&map.image, rain::map_image(grid, tables.get(ScanType::Reflectivity)));
+    meshes.insert(&map.mesh, rain::map_mesh(grid));
+    materials.get_mut(&map.material);
+}
+
+pub fn update_verification_text(
+    mut query: Query<(&mut Text, &mut Visibility), With<VerificationText>>,
+    settings: Res<NowcastSettings>,
+    nowcasts: Res<Nowcasts>,
+) {
+    let Ok((mut text, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let wanted = if settings.enabled { Visibility::Inherited } else { Visibility::Hidden };
+    if *visibility != wanted {
+        *visibility = wanted;
+    }
+    if !nowcasts.is_changed() {
+        return;
+    }
+
+    let mut value = format!("Nowcast verification (>= {} dBZ)\n", settings.threshold);
+    let score_text = |score: Option<f32>| score.map_or("–".to_string(), |score| format!("{score:.2}"));
+    for (lead, score) in nowcasts.scores.iter().filter(|(_, score)| score.csi().is_some()) {
+        value += &format!("+{lead:>2} min  CSI {:>4}  POD {:>4}  FAR {:>4}\n", score_text(score.csi()), score_text(score.pod()), score_text(score.far()));
+    }
+    text.sections[0].value = value;
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<NowcastSettings>,
+) {
+    if actions.just_pressed(Action::ToggleNowcast) {
+        settings.enabled = !settings.enabled;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::math::{UVec3, Vec2, Vec3};
+    use chrono::Utc;
+    use crate::grid::Grid;
+    use crate::motion::MotionField;
+    use crate::nowcast::{advect, Contingency};
+
+    #[test]
+    fn test_advected_echo_verifies_against_moved_echo() {
+        let grid = |cell: usize| {
+            let mut data = vec![10.0; 25];
+            data[cell] = 50.0;
+            Grid { origin: Vec3::new(-2000.0, 0.0, -2000.0), spacing: Vec3::new(1000.0, 1.0, 1000.0), dims: UVec3::new(5, 1, 5), data }
+        };
+        let motion = MotionField {
+            start_time: Utc::now(),
+            end_time: Utc::now(),
+            origin: Vec2::splat(-2000.0),
+            block_size: 5000.0,
+            columns: 1,
+            vectors: vec![Vec2::new(10.0, -5.0)],
+        };
+
+        // 10 m/s in x and -5 m/s in z for 200 s moves the echo two cells right and one up
+        let forecast = advect(&grid(12), &motion, 200.0);
+        let mut score = Contingency::default();
+        score.add(&forecast, &grid(5 + 4), 35.0);
+        assert_eq!((score.hits, score.misses, score.false_alarms), (1, 0, 0));
+        assert_eq!(score.csi(), Some(1.0));
+
+        let mut stale = Contingency::default();
+        stale.add(&grid(12), &forecast, 35.0);
+        assert_eq!((stale.hits, stale.misses, stale.false_alarms), (0, 1, 1));
+        assert_eq!(stale.far(), Some(1.0));
+    }
+
+    #[test]
+    fn test_scores_are_undefined_without_events() {
+        let none = Contingency::default();
+        assert_eq!((none.csi(), none.pod(), none.far()), (None, None, None));
+
+        // only false alarms: nothing was observed to detect
+        let alarms = Contingency { false_alarms: 3, ..Default::default() };
+        assert_eq!((alarms.csi(), alarms.pod(), alarms.far()), (Some(0.0), None, Some(1.0)));
+
+        // only misses: nothing was forecast to be a false alarm
+        let misses = Contingency { misses: 2, ..Default::default() };
+        assert_eq!((misses.csi(), misses.pod(), misses.far()), (Some(0.0), Some(0.0), None));
+    }
+}
@@ -0,0 +1,122 @@
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+/// A small CPU-side RGBA raster for 2-D plots, drawn into a UI image each time it changes.
+/// Pixel (0, 0) is the top left.
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        let rgba = color.as_rgba_u8();
+        self.data.chunks_exact_mut(4).for_each(|p| p.copy_from_slice(&rgba));
+    }
+
+    /// Blend `color` over the pixel at `(x, y)`, ignoring pixels outside the canvas.
+    pub fn pixel(&mut self, x: i32, y: i32, color: Color) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let i = ((y as u32 * self.width + x as u32) * 4) as usize;
+        let [r, g, b, a] = color.as_rgba_f32();
+        for (c, value) in [r, g, b].into_iter().enumerate() {
+            let old = self.data[i + c] as f32 / 255.0;
+            self.data[i + c] = ((old + (value - old) * a) * 255.0).round() as u8;
+        }
+        self.data[i + 3] = self.data[i + 3].max((a * 255.0) as u8);
+    }
+
+    pub fn line(&mut self, a: Vec2, b: Vec2, color: Color) {
+        let steps = (b - a).abs().max_element().ceil().max(1.0) as i32;
+        for i in 0..=steps {
+            let p = a.lerp(b, i as f32 / steps as f32);
+            self.pixel(p.x.round() as i32, p.y.round() as i32, color);
+        }
+    }
+
+    pub fn polyline(&mut self, points: &[Vec2], color: Color) {
+        for pair in points.windows(2) {
+            self.line(pair[0], pair[1], color);
+        }
+    }
+
+    pub fn circle(&mut self, centre: Vec2, radius: f32, color: Color) {
+        let steps = (radius * std::f32::consts::TAU).ceil().max(8.0) as usize;
+        let points: Vec<_> = (0..=steps)
+            .map(|i| centre + Vec2::from_angle(i as f32 / steps as f32 * std::f32::consts::TAU) * radius)
+            .collect();
+        self.polyline(&points, color);
+    }
+
+    pub fn to_image(&self) -> Image {
+        Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            self.data.clone(),
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+        )
+    }
+
+    /// Copy the canvas into an image created by `to_image`.
+    pub fn write(&self, image: &mut Image) {
+        image.data.clone_from(&self.data);
+    }
+}
+
+/// Pixels between a UI node and the right edge of the control panel, or the window edge while it is hidden.
+#[derive(Component)]
+pub struct LeftInset(pub f32);
+
+/// Spawn a titled UI panel showing `canvas`, positioned by `style`, and return the panel entity and its image.
+/// A panel placed from the left keeps clear of the control panel.
+pub fn spawn_panel(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    canvas: &Canvas,
+    title: &str,
+    style: Style,
+) -> (Entity, Handle<Image>) {
+    let image = images.add(canvas.to_image());
+    let entity = commands.spawn(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            flex_direction: FlexDirection::Column,
+            ..style
+        },
+        background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+        visibility: Visibility::Hidden,
+        ..default()
+    }).with_children(|parent| {
+        parent.spawn(TextBundle::from_section(title, TextStyle { font_size: 16.0, ..default() }));
+        parent.spawn(ImageBundle {
+            style: Style {
+                width: Val::Px(canvas.width as f32),
+                height: Val::Px(canvas.height as f32),
+                ..default()
+            },
+            image: UiImage::new(image.clone()),
+            ..default()
+        });
+    }).id();
+    if let Val::Px(left) = style.left {
+        commands.entity(entity).insert(LeftInset(left));
+    }
+    (entity, image)
+}
@@ -0,0 +1,395 @@
+use bevy::input::mouse::{MouseMotion, MouseWheel};
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy::window::PrimaryWindow;
+use bevy_egui::EguiContexts;
+use bevy_panorbit_camera::PanOrbitCamera;
+use chrono::{DateTime, Utc};
+use crate::colortable::{ColorTable, ColorTables};
+use crate::input::{Action, Actions};
+use crate::radar::{Gate, Scan};
+use crate::scan::{RenderMode, ScanInfo, ScanType};
+use crate::section;
+use crate::srv::StormMotionAt;
+use crate::views::ViewPanel;
+use crate::volume::Volumes;
+
+/// Height of the sweep texture above the ground, in m.
+const PLAN_HEIGHT: f32 = 10.0;
+/// Mean earth radius used for the lat/lon readout, in m.
+const EARTH_RADIUS: f32 = 6_371_000.0;
+
+#[derive(Resource, Default)]
+pub struct PlanSettings {
+    /// Sweep shown in plan view, from the lowest.
+    pub sweep_index: usize,
+}
+
+/// The sweep shown in plan view, from the latest volume with a sweep ended by `time`: the highest ended sweep
+/// up to `sweep_index`, or the lowest ended one when they are all higher.
+pub fn plan_scan<'a>(volumes: &'a Volumes, time: DateTime<Utc>, sweep_index: usize) -> Option<&'a Scan> {
+    volumes.0.range(..=time).rev().find_map(|(_, volume)| {
+        let ended: Vec<_> = volume.scans.iter().filter(|scan| scan.meta.end_time <= time).collect();
+        ended.iter().rev().find(|scan| scan.meta.sweep_index <= sweep_index).or(ended.first()).copied().map(|scan| scan.as_ref())
+    })
+}
+
+/// Latitude and longitude in degrees of a scene point, flat-earth about the site. Good to well under a
+/// gate within radar range.
+pub fn lat_lon((latitude, longitude): (f32, f32), point: Vec3) -> (f32, f32) {
+    let north = (point.x / EARTH_RADIUS).to_degrees();
+    let east = (point.z / (EARTH_RADIUS * latitude.to_radians().cos())).to_degrees();
+    (latitude + north, longitude + east)
+}
+
+/// A value for every gate of `scan`, ray by ray, as an image with one row per ray and one column per bin.
+pub fn polar_image(scan: &Scan, table: &ColorTable, value: impl Fn(&Gate) -> Option<f32>) -> Image {
+    let data = scan.gates.iter()
+        .flat_map(|gate| value(gate).map_or([0; 4], |value| table.color(value).as_rgba_u8()))
+        .collect();
+    let mut image = Image::new(
+        Extent3d {
+            width: scan.meta.bin_count as u32,
+            height: scan.meta.ray_count as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::nearest();
+    image
+}
+
+/// One flat wedge per ray reaching from the first to the last gate, sampling that ray's row of `polar_image`.
+pub fn polar_mesh(scan: &Scan) -> Mesh {
+    let (rays, bins) = (scan.meta.ray_count, scan.meta.bin_count);
+    let half_width = scan.meta.angular_resolution.abs() / 2.0;
+    let mut positions = Vec::with_capacity(rays * 4);
+    let mut uvs = Vec::with_capacity(rays * 4);
+    let mut indices = Vec::with_capacity(rays * 6);
+    for ray in 0..rays {
+        let first = scan.gate(ray, 0);
+        let near = (first.range - scan.meta.range_resolution / 2.0).max(0.0);
+        let far = near + scan.meta.range_resolution * bins as f32;
+        let v = (ray as f32 + 0.5) / rays as f32;
+        for (range, u) in [(near, 0.0), (far, 1.0)] {
+            for azimuth in [first.azimuth - half_width, first.azimuth + half_width] {
+                positions.push([range * azimuth.cos(), PLAN_HEIGHT, range * azimuth.sin()]);
+                uvs.push([u, v]);
+            }
+        }
+        let i = ray as u32 * 4;
+        indices.extend([i, i + 1, i + 3, i, i + 3, i + 2]);
+    }
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; positions.len()])
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_indices(Indices::U32(indices))
+}
+
+#[derive(Component)]
+pub struct PlanMap {
+    image: Handle<Image>,
+    mesh: Handle<Mesh>,
+}
+
+#[derive(Component)]
+pub struct PlanReadout;
+
+pub fn setup_plan_view(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let image = images.add(Image::default());
+    let mesh = meshes.add(Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD));
+    let material = materials.add(StandardMaterial {
+        base_color_texture: Some(image.clone()),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        cull_mode: None,
+        double_sided: true,
+        ..default()
+    });
+    commands.spawn((
+        PbrBundle {
+            mesh: mesh.clone(),
+            material,
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        PlanMap { image, mesh },
+    ));
+
+    commands.spawn((
+        TextBundle::from_section("", TextStyle { font_size: 18.0, ..default() })
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                right: Val::Px(10.0),
+                bottom: Val::Px(50.0),
+                ..default()
+            }),
+        PlanReadout,
+    ));
+}
+
+/// Swap the orbit camera of the first panel for a north-up orthographic one while in plan view, and back
+/// afterwards. Plan view has no other panels.
+pub fn switch_plan_camera(
+    mut commands: Commands,
+    mut stashed: Local<Option<PanOrbitCamera>>,
+    info: Res<ScanInfo>,
+    mut cameras: Query<(Entity, &ViewPanel, &mut Projection, &mut Transform, Option<&PanOrbitCamera>)>,
+) {
+    let plan = info.render_mode == RenderMode::Plan;
+    for (entity, _, mut projection, mut transform, orbit) in cameras.iter_mut().filter(|(_, panel, ..)| panel.0 == 0) {
+        match (plan, orbit) {
+            (true, Some(orbit)) => {
+                *stashed = Some(orbit.clone());
+                commands.entity(entity).remove::<PanOrbitCamera>();
+                *projection = Projection::Orthographic(OrthographicProjection {
+                    far: 200_000.0,
+                    scale: 150.0,
+                    ..default()
+                });
+                *transform = Transform::from_xyz(0.0, 100_000.0, 0.0).looking_at(Vec3::ZERO, Vec3::X);
+            }
+            (false, None) => {
+                *projection = Projection::Perspective(default());
+                if let Some(mut orbit) = stashed.take() {
+                    orbit.force_update = true;
+                    commands.entity(entity).insert(orbit);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Drag to pan and scroll to zoom about the cursor, like a map.
+pub fn plan_pan_zoom(
+    mut motion: EventReader<MouseMotion>,
+    mut wheel: EventReader<MouseWheel>,
+    mut contexts: EguiContexts,
+    buttons: Res<ButtonInput<MouseButton>>,
+    info: Res<ScanInfo>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mut cameras: Query<(&mut Projection, &mut Transform), With<Camera3d>>,
+) {
+    let dragged: Vec2 = motion.read().map(|event| event.delta).sum();
+    let scrolled: f32 = wheel.read().map(|event| event.y.signum()).sum();
+    if info.render_mode != RenderMode::Plan || contexts.ctx_mut().is_pointer_over_area() {
+        return;
+    }
+    let (Ok(window), Ok((mut projection, mut transform))) = (windows.get_single(), cameras.get_single_mut()) else {
+        return;
+    };
+    let Projection::Orthographic(projection) = projection.as_mut() else {
+        return;
+    };
+
+    // screen right is east (+z) and screen up is north (+x)
+    if buttons.pressed(MouseButton::Left) && dragged != Vec2::ZERO {
+        transform.translation.z -= dragged.x * projection.scale;
+        transform.translation.x += dragged.y * projection.scale;
+    }
+    if scrolled != 0.0 {
+        let scale = (projection.scale * 0.85f32.powf(scrolled)).clamp(5.0, 2_000.0);
+        if let Some(cursor) = window.cursor_position() {
+            let offset = cursor - Vec2::new(window.width(), window.height()) / 2.0;
+            transform.translation.z += offset.x * (projection.scale - scale);
+            transform.translation.x -= offset.y * (projection.scale - scale);
+        }
+        projection.scale = scale;
+    }
+}
+
+/// Redraw the sweep texture when the shown sweep, the moment, its threshold or colours change.
+pub fn update_plan_map(
+    mut shown: Local<Option<(DateTime<Utc>, usize, ScanType, f32, Vec3)>>,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut query: Query<(&PlanMap, &mut Visibility)>,
+    info: Res<ScanInfo>,
+    settings: Res<PlanSettings>,
+    volumes: Res<Volumes>,
+    tables: Res<ColorTables>,
+    storm_motion: StormMotionAt,
+) {
+    let Ok((map, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let scan = info.time.filter(|_| info.render_mode == RenderMode::Plan)
+        .and_then(|time| plan_scan(&volumes, time, settings.sweep_index));
+    let wanted = if scan.is_some() { Visibility::Visible } else { Visibility::Hidden };
+    if *visibility != wanted {
+        *visibility = wanted;
+    }
+    let Some(scan) = scan else {
+        return;
+    };
+
+    let scan_type = info.scan_type;
+    let threshold = info.thresholds.get(scan_type);
+    let storm = storm_motion.at(scan.meta.volume_start);
+    let key = (scan.meta.volume_start, scan.meta.sweep_index, scan_type, threshold, storm);
+    if *shown == Some(key) && !tables.is_changed() {
+        return;
+    }
+    *shown = Some(key);
+
+    let image = polar_image(scan, tables.get(scan_type), |gate| {
+        if scan_type.per_gate() {
+            scan_type.visible(gate, threshold).then(|| scan_type.value(gate))
+        } else {
+            let value = scan_type.relative_value(gate, storm);
+            (value.abs() >= threshold).then_some(value)
+        }
+    });
+    images.insert(map.image.clone(), image);
+    meshes.insert(map.mesh.clone(), polar_mesh(scan));
+}
+
+/// Position, range and value under the cursor in plan view.
+pub fn update_plan_readout(
+    mut query: Query<(&mut Text, &mut Visibility), With<PlanReadout>>,
+    info: Res<ScanInfo>,
+    settings: Res<PlanSettings>,
+    volumes: Res<Volumes>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    storm_motion: StormMotionAt,
+) {
+    let Ok((mut text, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let scan = info.time.filter(|_| info.render_mode == RenderMode::Plan)
+        .and_then(|time| plan_scan(&volumes, time, settings.sweep_index));
+    let (Some(scan), Ok(window), Ok((camera, transform))) = (scan, windows.get_single(), cameras.get_single()) else {
+        if *visibility != Visibility::Hidden {
+            *visibility = Visibility::Hidden;
+        }
+        return;
+    };
+    if *visibility != Visibility::Inherited {
+        *visibility = Visibility::Inherited;
+    }
+
+    let mut value = format!("{:?} sweep {} ({:.1}°)", info.scan_type, scan.meta.sweep_index, scan.meta.fixed_angle.to_degrees());
+    if let Some(point) = section::cursor_on_ground(window, camera, transform) {
+        let range = Vec2::new(point.x, point.z).length();
+        let azimuth = point.z.atan2(point.x).to_degrees().rem_euclid(360.0);
+        if !scan.meta.latitude.is_nan() {
+            let (latitude, longitude) = lat_lon((scan.meta.latitude, scan.meta.longitude), point);
+            value += &format!("\n{latitude:.4}°, {longitude:.4}°");
+        }
+        value += &format!("\n{:.1} km at {azimuth:.1}°", range / 1000.0);
+
+        let ray = (0..scan.meta.ray_count).min_by(|a, b| {
+            let distance = |ray: &usize| (scan.gate(*ray, 0).azimuth.to_degrees() - azimuth + 180.0).rem_euclid(360.0) - 180.0;
+            distance(a).abs().total_cmp(&distance(b).abs())
+        });
+        let bin = ((range - scan.gate(0, 0).range) / scan.meta.range_resolution).round();
+        if let (Some(ray), true) = (ray, bin >= 0.0 && (bin as usize) < scan.meta.bin_count) {
+            let gate = scan.gate(ray, bin as usize);
+            let sample = info.scan_type.relative_value(gate, storm_motion.at(scan.meta.volume_start));
+            if !sample.is_nan() {
+                value += &format!("\n{sample:.2}");
+            }
+        }
+    }
+    text.sections[0].value = value;
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<PlanSettings>,
+    info: Res<ScanInfo>,
+    volumes: Res<Volumes>,
+) {
+    if actions.just_pressed(Action::PlanSweepUp) {
+        let highest = info.time
+            .and_then(|time| volumes.0.range(..=time).next_back())
+            .and_then(|(_, volume)| volume.scans.last())
+            .map_or(0, |scan| scan.meta.sweep_index);
+        settings.sweep_index = (settings.sweep_index + 1).min(highest);
+    }
+    if actions.just_pressed(Action::PlanSweepDown) {
+        settings.sweep_index = settings.sweep_index.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use bevy::math::Vec3;
+    use bevy::render::mesh::{Indices, Mesh, VertexAttributeValues};
+    use chrono::{TimeDelta, Utc};
+    use crate::plan::{lat_lon, plan_scan, polar_mesh, PLAN_HEIGHT};
+    use crate::radar::{Gate, Scan};
+    use crate::volume::{Volume, Volumes};
+
+    #[test]
+    fn test_lat_lon_of_points_north_and_east_of_the_site() {
+        // a degree of latitude is about 111.2 km
+        let (latitude, longitude) = lat_lon((35.0, -97.0), Vec3::new(111_195.0, 0.0, 0.0));
+        assert!((latitude - 36.0).abs() < 1e-3 && (longitude + 97.0).abs() < 1e-6);
+
+        // and a degree of longitude shrinks with the cosine of latitude
+        let (latitude, longitude) = lat_lon((60.0, 10.0), Vec3::new(0.0, 0.0, 55_597.0));
+        assert!((latitude - 60.0).abs() < 1e-6 && (longitude - 11.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_polar_mesh_has_a_quad_per_ray_out_to_the_last_gate() {
+        let (rays, bins) = (4, 3);
+        let gates = (0..rays * bins)
+            .map(|i| Gate {
+                azimuth: (i / bins) as f32 * std::f32::consts::FRAC_PI_2,
+                range: 1000.0 + 500.0 * (i % bins) as f32,
+                ..Default::default()
+            })
+            .collect();
+        let mesh = polar_mesh(&Scan::ppi(gates, bins, Utc::now()));
+
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            panic!("no positions");
+        };
+        assert_eq!(positions.len(), rays * 4);
+        assert!(positions.iter().all(|p| p[1] == PLAN_HEIGHT));
+        // the first ray points north and reaches from half a gate before the first to half a gate past the last
+        let north: Vec<_> = positions[..4].iter().map(|p| Vec3::new(p[0], 0.0, p[2])).collect();
+        assert!((north[0].length() - 750.0).abs() < 1e-2 && (north[3].length() - 2250.0).abs() < 1e-2);
+        assert!(north.iter().all(|p| p.x > 0.0));
+        let Some(Indices::U32(indices)) = mesh.indices() else {
+            panic!("no indices");
+        };
+        assert_eq!(indices.len(), rays * 6);
+    }
+
+    #[test]
+    fn test_plan_scan_only_shows_ended_sweeps() {
+        let start = Utc::now();
+        let sweep = |index: usize| {
+            let mut scan = Scan::ppi(vec![Gate::default()], 1, start + TimeDelta::minutes(index as i64 + 1));
+            (scan.meta.sweep_index, scan.meta.volume_start) = (index, start);
+            Arc::new(scan)
+        };
+        let volume = Volume { start_time: start, end_time: start + TimeDelta::minutes(3), scans: (0..3).map(sweep).collect(), rhis: vec![] };
+        let volumes = Volumes(BTreeMap::from([(start, volume)]));
+        let index = |minutes: i64, wanted: usize| plan_scan(&volumes, start + TimeDelta::minutes(minutes), wanted).map(|scan| scan.meta.sweep_index);
+
+        assert_eq!(index(0, 0), None);
+        assert_eq!(index(3, 1), Some(1));
+        // the wanted sweep hasn't ended yet, so the highest that has stands in
+        assert_eq!(index(2, 2), Some(1));
+    }
+}
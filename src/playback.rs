@@ -0,0 +1,180 @@
+use std::collections::BTreeSet;
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use crate::input::{Action, Actions};
+use crate::scan::ScanInfo;
+use crate::volume::Volumes;
+
+/// Playback speeds, as data seconds per wall-clock second.
+pub const SPEEDS: [f32; 12] = [0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 180.0, 300.0, 600.0];
+
+#[derive(Component, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PlaybackAction {
+    Slower,
+    StepBack,
+    Reverse,
+    TogglePause,
+    StepForward,
+    Faster,
+    Loop,
+}
+
+impl PlaybackAction {
+    pub const ALL: [PlaybackAction; 7] = [
+        PlaybackAction::Slower,
+        PlaybackAction::StepBack,
+        PlaybackAction::Reverse,
+        PlaybackAction::TogglePause,
+        PlaybackAction::StepForward,
+        PlaybackAction::Faster,
+        PlaybackAction::Loop,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PlaybackAction::Slower => "<<",
+            PlaybackAction::StepBack => "|<",
+            PlaybackAction::Reverse => "<>",
+            PlaybackAction::TogglePause => "||",
+            PlaybackAction::StepForward => ">|",
+            PlaybackAction::Faster => ">>",
+            PlaybackAction::Loop => "loop",
+        }
+    }
+}
+
+/// The next speed in `SPEEDS` above (or with `slower`, below) `ratio`, staying at the ends.
+pub fn next_speed(ratio: f32, slower: bool) -> f32 {
+    if slower {
+        SPEEDS.iter().rev().copied().find(|speed| *speed < ratio).unwrap_or(SPEEDS[0])
+    } else {
+        SPEEDS.iter().copied().find(|speed| *speed > ratio).unwrap_or(SPEEDS[SPEEDS.len() - 1])
+    }
+}
+
+/// The end time of the next sweep after `time` (or with `back`, the last one before it). Sweeps are shown
+/// from their end time, so this is when each one appears.
+pub fn next_scan(volumes: &Volumes, time: DateTime<Utc>, back: bool) -> Option<DateTime<Utc>> {
+    let ends: BTreeSet<_> = volumes.0.values()
+        .flat_map(|volume| volume.scans.iter().map(|scan| scan.meta.end_time))
+        .collect();
+    if back {
+        ends.range(..time).next_back().copied()
+    } else {
+        ends.range(time..).find(|end| **end > time).copied()
+    }
+}
+
+/// First end of a loop being set with `PlaybackAction::Loop`, waiting for the second.
+#[derive(Resource, Default)]
+pub struct PendingLoop(pub Option<DateTime<Utc>>);
+
+pub fn apply(action: PlaybackAction, info: &mut ScanInfo, pending: &mut PendingLoop, volumes: &Volumes) {
+    match action {
+        PlaybackAction::Slower => info.time_ratio = next_speed(info.time_ratio, true),
+        PlaybackAction::Faster => info.time_ratio = next_speed(info.time_ratio, false),
+        PlaybackAction::Reverse => info.reverse = !info.reverse,
+        PlaybackAction::TogglePause => info.paused = !info.paused,
+        PlaybackAction::StepBack | PlaybackAction::StepForward => {
+            let back = action == PlaybackAction::StepBack;
+            if let Some(next) = info.time.and_then(|time| next_scan(volumes, time, back)) {
+                info.time = Some(next);
+                info.paused = true;
+            }
+        }
+        // the first press marks one end, the second the other, and a third clears the loop
+        PlaybackAction::Loop => match (info.loop_range, pending.0, info.time) {
+            (Some(_), _, _) => info.loop_range = None,
+            (None, Some(start), Some(time)) if start != time => {
+                info.loop_range = Some((start.min(time), start.max(time)));
+                pending.0 = None;
+            }
+            (None, _, time) => pending.0 = time,
+        },
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut info: ResMut<ScanInfo>,
+    mut pending: ResMut<PendingLoop>,
+    volumes: Res<Volumes>,
+) {
+    // pausing is handled with the rest of the viewer keys in scan::keyboard_input
+    let bindings = [
+        (Action::Slower, PlaybackAction::Slower),
+        (Action::PreviousScan, PlaybackAction::StepBack),
+        (Action::Reverse, PlaybackAction::Reverse),
+        (Action::NextScan, PlaybackAction::StepForward),
+        (Action::Faster, PlaybackAction::Faster),
+        (Action::Loop, PlaybackAction::Loop),
+    ];
+    for (binding, action) in bindings {
+        if actions.just_pressed(binding) {
+            apply(action, &mut info, &mut pending, &volumes);
+        }
+    }
+}
+
+pub fn setup_playback_controls(
+    mut commands: Commands,
+) {
+    commands.spawn(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(40.0),
+            bottom: Val::Px(45.0),
+            column_gap: Val::Px(4.0),
+            ..default()
+        },
+        ..default()
+    }).with_children(|parent| {
+        for action in PlaybackAction::ALL {
+            parent.spawn((
+                ButtonBundle {
+                    style: Style {
+                        padding: UiRect::axes(Val::Px(8.0), Val::Px(2.0)),
+                        ..default()
+                    },
+                    background_color: Color::rgba(0.0, 0.0, 0.0, 0.6).into(),
+                    ..default()
+                },
+                action,
+            )).with_children(|button| {
+                button.spawn(TextBundle::from_section(action.label(), TextStyle { font_size: 18.0, ..default() }));
+            });
+        }
+    });
+}
+
+pub fn playback_buttons(
+    mut buttons: Query<(&Interaction, &PlaybackAction, &mut BackgroundColor), Changed<Interaction>>,
+    mut info: ResMut<ScanInfo>,
+    mut pending: ResMut<PendingLoop>,
+    volumes: Res<Volumes>,
+) {
+    for (interaction, action, mut background) in buttons.iter_mut() {
+        *background = match interaction {
+            Interaction::Pressed => Color::rgba(0.3, 0.3, 0.3, 0.8),
+            Interaction::Hovered => Color::rgba(0.15, 0.15, 0.15, 0.7),
+            Interaction::None => Color::rgba(0.0, 0.0, 0.0, 0.6),
+        }.into();
+        if *interaction == Interaction::Pressed {
+            apply(*action, &mut info, &mut pending, &volumes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::playback::{next_speed, SPEEDS};
+
+    #[test]
+    fn test_speeds_step_through_the_list_and_stop_at_the_ends() {
+        assert_eq!(next_speed(1.0, false), 2.0);
+        assert_eq!(next_speed(1.0, true), 0.5);
+        assert_eq!(next_speed(7.0, false), 10.0);
+        assert_eq!(next_speed(SPEEDS[0], true), SPEEDS[0]);
+        assert_eq!(next_speed(600.0, false), 600.0);
+    }
+}
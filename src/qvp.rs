@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, HashMap};
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use crate::colortable::ColorTables;
+use crate::input::{Action, Actions};
+use crate::panel::{self, Canvas};
+use crate::radar::Scan;
+use crate::scan::{ScanInfo, ScanType};
+use crate::srv::{StormMotionAt, MOTION_TOLERANCE};
+use crate::volume::{Volume, VolumeLoaded, Volumes};
+
+const PANEL_WIDTH: u32 = 400;
+const PANEL_HEIGHT: u32 = 200;
+
+#[derive(Resource, Debug, Clone)]
+pub struct QvpSettings {
+    pub enabled: bool,
+    /// Only sweeps at or above this elevation are used, in radians.
+    pub min_elevation: f32,
+    pub height_step: f32,
+    pub max_height: f32,
+    /// Fraction of rays that must have a value for a range to be averaged.
+    pub min_coverage: f32,
+}
+
+impl Default for QvpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_elevation: 20f32.to_radians(),
+            height_step: 100.0,
+            max_height: 10000.0,
+            min_coverage: 0.5,
+        }
+    }
+}
+
+/// Azimuthally averaged profiles of every moment for one volume, on levels `height_step` apart.
+#[derive(Debug, Clone)]
+pub struct Qvp {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// Storm motion the storm-relative velocity profile was taken against.
+    pub storm: Vec3,
+    pub profiles: HashMap<ScanType, Vec<f32>>,
+}
+
+/// Azimuthal mean of `scan_type` at each range of a sweep, NaN where coverage is too sparse. Storm-relative
+/// velocity is taken against `storm`.
+pub fn azimuthal_mean(scan: &Scan, scan_type: ScanType, storm: Vec3, min_coverage: f32) -> Vec<f32> {
+    (0..scan.meta.bin_count)
+        .map(|bin| {
+            let (sum, count) = (0..scan.meta.ray_count)
+                .map(|ray| scan_type.relative_value(scan.gate(ray, bin), storm))
+                .filter(|value| !value.is_nan())
+                .fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+            if count == 0 || (count as f32) < min_coverage * scan.meta.ray_count as f32 {
+                f32::NAN
+            } else {
+                sum / count as f32
+            }
+        })
+        .collect()
+}
+
+/// Build a QVP from the highest sweep of `volume`, if it is steep enough.
+pub fn qvp(volume: &Volume, settings: &QvpSettings, storm: Vec3) -> Option<Qvp> {
+    let scan = volume.scans.iter().max_by(|a, b| a.elevation().total_cmp(&b.elevation()))?;
+    let elevation = scan.elevation();
+    if elevation < settings.min_elevation || scan.meta.bin_count < 2 {
+        return None;
+    }
+
+    let first = scan.gate(0, 0).range;
+    let step = (scan.gate(0, scan.meta.bin_count - 1).range - first) / (scan.meta.bin_count - 1) as f32;
+    let levels = (settings.max_height / settings.height_step).ceil() as usize;
+    let profiles = ScanType::ALL.into_iter()
+        .filter(|scan_type| !scan_type.categorical())
+        .map(|scan_type| {
+            let means = azimuthal_mean(scan, scan_type, storm, settings.min_coverage);
+            let profile = (0..levels)
+                .map(|level| {
+                    let height = (level as f32 + 0.5) * settings.height_step;
+                    let bin = ((height / elevation.sin() - first) / step).round();
+                    if bin < 0.0 || bin as usize >= means.len() {
+                        f32::NAN
+                    } else {
+                        means[bin as usize]
+                    }
+                })
+                .collect();
+            (scan_type, profile)
+        })
+        .collect();
+
+    Some(Qvp {
+        start_time: volume.start_time,
+        end_time: volume.end_time,
+        storm,
+        profiles,
+    })
+}
+
+/// QVPs keyed by volume start.
+#[derive(Resource, Default)]
+pub struct Qvps(pub BTreeMap<DateTime<Utc>, Qvp>);
+
+pub fn update_qvps(
+    mut events: EventReader<VolumeLoaded>,
+    mut qvps: ResMut<Qvps>,
+    volumes: Res<Volumes>,
+    settings: Res<QvpSettings>,
+    storm_motion: StormMotionAt,
+) {
+    let mut starts: Vec<_> = if settings.is_changed() && !settings.is_added() {
+        events.clear();
+        qvps.0.clear();
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+    if storm_motion.is_changed() {
+        starts.extend(qvps.0.iter()
+            .filter(|(start, qvp)| qvp.storm.distance(storm_motion.at(**start)) >= MOTION_TOLERANCE)
+            .map(|(start, _)| *start));
+    }
+
+    for start in starts {
+        if let Some(qvp) = volumes.0.get(&start).and_then(|volume| qvp(volume, &settings, storm_motion.at(start))) {
+            qvps.0.insert(start, qvp);
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct QvpPanel(Handle<Image>);
+
+pub fn setup_qvp_panel(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    let (entity, image) = panel::spawn_panel(&mut commands, &mut images, &canvas, "QVP (time-height)", Style {
+        right: Val::Px(10.0),
+        bottom: Val::Px(50.0),
+        ..default()
+    });
+    commands.entity(entity).insert(QvpPanel(image));
+}
+
+/// Draw the time-height display of the selected moment with a cursor at the current time.
+pub fn update_qvp_panel(
+    mut shown: Local<Option<(ScanType, i32)>>,
+    mut images: ResMut<Assets<Image>>,
+    mut query: Query<(&QvpPanel, &mut Visibility)>,
+    info: Res<ScanInfo>,
+    settings: Res<QvpSettings>,
+    qvps: Res<Qvps>,
+    tables: Res<ColorTables>,
+) {
+    let Ok((panel, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let (Some(first), Some(last)) = (qvps.0.values().next(), qvps.0.values().next_back()) else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    let wanted = if settings.enabled { Visibility::Inherited } else { Visibility::Hidden };
+    if *visibility != wanted {
+        *visibility = wanted;
+    }
+    if !settings.enabled {
+        *shown = None;
+        return;
+    }
+
+    let (t0, t1) = (first.start_time, last.end_time);
+    let span = (t1 - t0).num_milliseconds().max(1) as f32;
+    let x = |time: DateTime<Utc>| ((time - t0).num_milliseconds() as f32 / span * PANEL_WIDTH as f32) as i32;
+    let cursor = info.time.map_or(-1, x);
+    if *shown == Some((info.scan_type, cursor)) && !qvps.is_changed() && !tables.is_changed() {
+        return;
+    }
+    *shown = Some((info.scan_type, cursor));
+
+    let table = tables.get(info.scan_type);
+    let mut canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    canvas.clear(Color::rgba(0.0, 0.0, 0.0, 0.0));
+    let mut columns = qvps.0.values().peekable();
+    while let Some(qvp) = columns.next() {
+        let Some(profile) = qvp.profiles.get(&info.scan_type) else {
+            continue;
+        };
+        let end = columns.peek().map_or(qvp.end_time, |next| next.start_time);
+        for column in x(qvp.start_time)..x(end).max(x(qvp.start_time) + 1) {
+            for row in 0..PANEL_HEIGHT {
+                let height = (1.0 - (row as f32 + 0.5) / PANEL_HEIGHT as f32) * settings.max_height;
+                let level = (height / settings.height_step) as usize;
+                if let Some(value) = profile.get(level).filter(|v| !v.is_nan()) {
+                    canvas.pixel(column, row as i32, table.color(*value).with_a(1.0));
+                }
+            }
+        }
+    }
+
+    // a grid line every kilometre
+    for km in 1..(settings.max_height / 1000.0) as u32 {
+        let y = (1.0 - km as f32 * 1000.0 / settings.max_height) * PANEL_HEIGHT as f32;
+        canvas.line(Vec2::new(0.0, y), Vec2::new(PANEL_WIDTH as f32, y), Color::rgba(1.0, 1.0, 1.0, 0.2));
+    }
+    canvas.line(Vec2::new(cursor as f32, 0.0), Vec2::new(cursor as f32, PANEL_HEIGHT as f32), Color::WHITE);
+
+    if let Some(image) = images.get_mut(&panel.0) {
+        canvas.write(image);
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<QvpSettings>,
+) {
+    if actions.just_pressed(Action::ToggleQvp) {
+        let settings = settings.bypass_change_detection();
+        settings.enabled = !settings.enabled;
+    }
+}
@@ -1,28 +1,96 @@
 use std::path::Path;
 use std::vec::Vec;
 use bevy::math::Vec3;
-use bevy::prelude::Component;
+use bevy::prelude::{Component, Resource};
 use chrono::{DateTime, TimeDelta, Utc};
-use netcdf::Extents;
+use netcdf::{AttrValue, Extents, Variable};
+use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 use rayon::prelude::*;
+use crate::dualpol::{self, Attenuation};
+use crate::hca;
+use crate::llsd;
+
+const FILL_VALUE: f32 = i16::MIN as f32;
 
 pub trait Radar{
     fn get_gates(&self) -> (std::sync::mpsc::Receiver<Scan>, usize);
 }
 
+#[derive(Default, Clone)]
 pub struct AIRRadar{
-    
+    pub attenuation: Attenuation,
+    /// Height of the melting layer above the radar in metres, from `Processing::melting_layer`.
+    pub melting_layer: Option<f32>,
+    pub sources: DataSources,
+}
+
+/// How gates are processed as they are read, from the `processing` section of the config file.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Processing {
+    pub attenuation: Attenuation,
+    /// Height of the melting layer above the radar in metres, e.g. the 0 °C level of a nearby sounding.
+    /// Without one every hydrometeor class is allowed at every height.
+    pub melting_layer: Option<f32>,
+}
+
+/// Glob patterns of the CfRadial files read at startup.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataSources(pub Vec<String>);
+
+impl Default for DataSources {
+    fn default() -> Self {
+        Self(vec![
+            //"AIR_cfradial/cfrad.20130531_231156_AIR_v1_s1.nc",
+            String::from("AIR_cfradial/cfrad*v1_*.nc"),
+            //"AIR_cfradial/cfrad*v2_*.nc",
+            //"AIR_cfradial/cfrad.20130531_231204_AIR_v2_s*.nc",
+            //"AIR_cfradial/cfrad.20130531_231211_AIR_v3_s*.nc",
+            //"AIR_cfradial/cfrad.20130531_231219_AIR_v4_s*.nc",
+            //"AIR_cfradial/cfrad.20130531_231226_AIR_v5_s*.nc",
+        ])
+    }
+}
+
+/// CfRadial `sweep_mode`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SweepMode {
+    Ppi,
+    Sector,
+    Rhi,
+    VerticalPointing,
+}
+
+impl SweepMode {
+    fn parse(mode: &str) -> Self {
+        match mode {
+            "sector" => SweepMode::Sector,
+            "rhi" | "elevation_surveillance" => SweepMode::Rhi,
+            "vertical_pointing" => SweepMode::VerticalPointing,
+            _ => SweepMode::Ppi,
+        }
+    }
 }
 
 #[derive(Component, Clone)]
 pub struct ScanMetadata {
     pub name: String,
+    /// Angle between rays along the scanning axis: azimuth for PPIs and sectors, elevation for RHIs.
     pub angular_resolution: f32,
     pub range_resolution: f32,
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
     pub sweep_index: usize,
+    pub volume_start: DateTime<Utc>,
+    pub ray_count: usize,
+    pub bin_count: usize,
+    pub sweep_mode: SweepMode,
+    /// Elevation of a PPI or azimuth of an RHI, in radians.
+    pub fixed_angle: f32,
+    /// Site position in degrees, NaN when the file doesn't give it.
+    pub latitude: f32,
+    pub longitude: f32,
 
     // Aggregate min and max
     pub min: Gate,
@@ -41,16 +109,55 @@ pub struct Gate{
     pub azimuth:f32,
     pub elevation:f32,
     pub range:f32,
+    pub azimuthal_shear:f32,
+    pub divergence:f32,
+    pub differential_reflectivity:f32,
+    pub differential_phase:f32,
+    pub cross_correlation:f32,
+    pub specific_differential_phase:f32,
+    pub corrected_reflectivity:f32,
+    pub corrected_differential_reflectivity:f32,
+    /// `hca::HydrometeorClass::index`, or NaN when unclassified.
+    pub hydrometeor_class:f32,
+}
+
+/// A gate at the radar with every moment missing.
+impl Default for Gate {
+    fn default() -> Self {
+        Self {
+            reflectivity: f32::NAN,
+            doppler_velocity: f32::NAN,
+            azimuth: 0.0,
+            elevation: 0.0,
+            range: 0.0,
+            azimuthal_shear: f32::NAN,
+            divergence: f32::NAN,
+            differential_reflectivity: f32::NAN,
+            differential_phase: f32::NAN,
+            cross_correlation: f32::NAN,
+            specific_differential_phase: f32::NAN,
+            corrected_reflectivity: f32::NAN,
+            corrected_differential_reflectivity: f32::NAN,
+            hydrometeor_class: f32::NAN,
+        }
+    }
 }
 
 impl Gate {
     pub fn as_cart(&self) -> Vec3 {
+        let horizontal = self.range * self.elevation.cos();
         let y = self.range * self.elevation.sin();
-        let z = self.range * self.azimuth.sin();
-        let x = self.range * self.azimuth.cos();
+        let z = horizontal * self.azimuth.sin();
+        let x = horizontal * self.azimuth.cos();
         Vec3::new(x, y, z)
     }
 
+    /// Unit vector across the beam in the direction of increasing elevation.
+    pub fn up(&self) -> Vec3 {
+        let horizontal = -self.elevation.sin();
+        Vec3::new(horizontal * self.azimuth.cos(), self.elevation.cos(), horizontal * self.azimuth.sin())
+    }
+
     fn max(&self, other: &Self) -> Self {
         Self{
             reflectivity: self.reflectivity.max(other.reflectivity),
@@ -58,6 +165,15 @@ impl Gate {
             azimuth: self.azimuth.max(other.azimuth),
             elevation: self.elevation.max(other.elevation),
             range: self.range.max(other.range),
+            azimuthal_shear: self.azimuthal_shear.max(other.azimuthal_shear),
+            divergence: self.divergence.max(other.divergence),
+            differential_reflectivity: self.differential_reflectivity.max(other.differential_reflectivity),
+            differential_phase: self.differential_phase.max(other.differential_phase),
+            cross_correlation: self.cross_correlation.max(other.cross_correlation),
+            specific_differential_phase: self.specific_differential_phase.max(other.specific_differential_phase),
+            corrected_reflectivity: self.corrected_reflectivity.max(other.corrected_reflectivity),
+            corrected_differential_reflectivity: self.corrected_differential_reflectivity.max(other.corrected_differential_reflectivity),
+            hydrometeor_class: self.hydrometeor_class.max(other.hydrometeor_class),
         }
     }
 
@@ -68,12 +184,105 @@ impl Gate {
             azimuth: self.azimuth.min(other.azimuth),
             elevation: self.elevation.min(other.elevation),
             range: self.range.min(other.range),
+            azimuthal_shear: self.azimuthal_shear.min(other.azimuthal_shear),
+            divergence: self.divergence.min(other.divergence),
+            differential_reflectivity: self.differential_reflectivity.min(other.differential_reflectivity),
+            differential_phase: self.differential_phase.min(other.differential_phase),
+            cross_correlation: self.cross_correlation.min(other.cross_correlation),
+            specific_differential_phase: self.specific_differential_phase.min(other.specific_differential_phase),
+            corrected_reflectivity: self.corrected_reflectivity.min(other.corrected_reflectivity),
+            corrected_differential_reflectivity: self.corrected_differential_reflectivity.min(other.corrected_differential_reflectivity),
+            hydrometeor_class: self.hydrometeor_class.min(other.hydrometeor_class),
+        }
+    }
+}
+
+/// A moment stored as scaled shorts; the fill value becomes NaN. Moments missing from the file read as NaN.
+struct Moment<'f> {
+    variable: Option<Variable<'f>>,
+    scale: f32,
+    offset: f32,
+}
+
+impl<'f> Moment<'f> {
+    fn new(variable: Option<Variable<'f>>) -> Self {
+        let attribute = |name: &str| {
+            match variable.as_ref()?.attribute(name)?.value().ok()? {
+                AttrValue::Float(v) => Some(v),
+                AttrValue::Double(v) => Some(v as f32),
+                _ => None,
+            }
+        };
+        Self {
+            scale: attribute("scale_factor").unwrap_or(0.01),
+            offset: attribute("add_offset").unwrap_or(0.0),
+            variable,
         }
     }
+
+    fn ray(&self, ray: usize, bins: usize) -> Vec<f32> {
+        let Some(variable) = self.variable.as_ref() else {
+            return vec![f32::NAN; bins];
+        };
+        variable.get_values::<f32, _>((ray, ..)).unwrap()
+            .into_iter()
+            .map(|raw| if raw <= FILL_VALUE { f32::NAN } else { raw * self.scale + self.offset })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Scan {
+    /// A full-circle PPI of ray-major `gates`, `bins` to a ray, swept at `time`. For tests.
+    pub fn ppi(gates: Vec<Gate>, bins: usize, time: DateTime<Utc>) -> Self {
+        let rays = gates.len() / bins;
+        let (min, max) = gates.iter().fold((gates[0].clone(), gates[0].clone()), |(min, max), gate| (min.min(gate), max.max(gate)));
+        Scan {
+            meta: ScanMetadata {
+                name: String::new(),
+                angular_resolution: std::f32::consts::TAU / rays as f32,
+                range_resolution: if bins > 1 { gates[1].range - gates[0].range } else { 250.0 },
+                start_time: time,
+                end_time: time,
+                sweep_index: 0,
+                volume_start: time,
+                ray_count: rays,
+                bin_count: bins,
+                sweep_mode: SweepMode::Ppi,
+                fixed_angle: gates[0].elevation,
+                latitude: f32::NAN,
+                longitude: f32::NAN,
+                min,
+                max,
+            },
+            gates,
+        }
+    }
+}
+
+impl Scan {
+    pub fn gate(&self, ray: usize, bin: usize) -> &Gate {
+        &self.gates[ray * self.meta.bin_count + bin]
+    }
+
+    pub fn elevation(&self) -> f32 {
+        (self.meta.min.elevation + self.meta.max.elevation) / 2.0
+    }
+
+    /// Index of the ray closest to `elevation`, for RHIs.
+    pub fn ray_at_elevation(&self, elevation: f32) -> Option<usize> {
+        (0..self.meta.ray_count)
+            .min_by(|a, b| {
+                let a = (self.gate(*a, 0).elevation - elevation).abs();
+                let b = (self.gate(*b, 0).elevation - elevation).abs();
+                a.total_cmp(&b)
+            })
+            .filter(|ray| (self.gate(*ray, 0).elevation - elevation).abs() <= self.meta.angular_resolution.abs() * 1.5)
+    }
 }
 
 impl AIRRadar {
-    fn get_gates_from_file(path: &Path) -> Scan {
+    fn get_gates_from_file(&self, path: &Path) -> Scan {
         let file = netcdf::open(path).unwrap();
 
         /*
@@ -96,6 +305,7 @@ impl AIRRadar {
         let start_time = file.variable("time_coverage_start").unwrap().get_raw_values(&mut buf, Extents::All).unwrap();
         let start_time = String::from_utf8_lossy(&buf);
         let mut start_time = start_time.parse::<DateTime<Utc>>().unwrap();
+        let volume_start = start_time;
 
         let end_time = file.variable("time_coverage_start").unwrap().get_raw_values(&mut buf, Extents::All).unwrap();
         let end_time = String::from_utf8_lossy(&buf);
@@ -107,8 +317,27 @@ impl AIRRadar {
             end_time = start_time + sweep_time;
         }
 
-        let vel = file.variable("VEL").unwrap();
+        let sweep_mode = file.variable("sweep_mode").map_or(SweepMode::Ppi, |variable| {
+            let mut buf = vec![0; variable.len()];
+            variable.get_raw_values(&mut buf, Extents::All).unwrap();
+            SweepMode::parse(String::from_utf8_lossy(&buf).trim_matches(char::from(0)).trim())
+        });
+        let fixed_angle = file.variable("fixed_angle")
+            .and_then(|variable| variable.get_values::<f32, _>(..).ok())
+            .and_then(|angles| angles.first().copied())
+            .unwrap_or(0.0)
+            .to_radians();
+        let site = |name: &str| file.variable(name)
+            .and_then(|variable| variable.get_values::<f64, _>(..).ok())
+            .and_then(|values| values.first().copied())
+            .map_or(f32::NAN, |value| value as f32);
+        let (latitude, longitude) = (site("latitude"), site("longitude"));
+
+        let vel = Moment::new(Some(file.variable("VEL").unwrap()));
         let dbz = file.variable("DBZ").unwrap();
+        let zdr = Moment::new(file.variable("ZDR"));
+        let phidp = Moment::new(file.variable("PHIDP"));
+        let rhohv = Moment::new(file.variable("RHOHV"));
         let azimuth = file.variable("azimuth").unwrap();
         let elevation = file.variable("elevation").unwrap();
         let range = file.variable("range").unwrap();
@@ -118,18 +347,37 @@ impl AIRRadar {
         let elevation_data = elevation.get_values::<f32, _>(..).unwrap();
         let range_data = range.get_values::<f32, _>(..).unwrap();
 
+        let dbz = Moment::new(Some(dbz));
+        let bins = range_data.len();
         let mut gates = Vec::new();
         for (i, az) in azimuth_data.iter().enumerate() {
-            let dbz_data = dbz.get_values::<f32, _>((i, ..)).unwrap();
-            let vel_data = vel.get_values::<f32, _>((i, ..)).unwrap();
+            let dbz_data = dbz.ray(i, bins);
+            let vel_data = vel.ray(i, bins);
+            let zdr_data = zdr.ray(i, bins);
+            let phidp_data = phidp.ray(i, bins);
+            let rhohv_data = rhohv.ray(i, bins);
             for (j, range) in range_data.iter().enumerate() {
-                let dbz = dbz_data.get(j).unwrap() * 0.01;
-                let vel = vel_data.get(j).unwrap() * 0.01;
+                let dbz = dbz_data[j];
+                let vel = vel_data[j];
                 let elevation = elevation_data[i].to_radians();
-                gates.push(Gate { azimuth: az.to_radians(), doppler_velocity: vel, range: *range, elevation, reflectivity: dbz});
+                gates.push(Gate {
+                    azimuth: az.to_radians(),
+                    doppler_velocity: vel,
+                    range: *range,
+                    elevation,
+                    reflectivity: dbz,
+                    differential_reflectivity: zdr_data[j],
+                    differential_phase: phidp_data[j],
+                    cross_correlation: rhohv_data[j],
+                    ..Default::default()
+                });
             }
         }
 
+        llsd::llsd(&mut gates, bins);
+        dualpol::process(&mut gates, bins, self.attenuation);
+        hca::process(&mut gates, self.melting_layer);
+
         let mut min = gates.first().unwrap().clone();
         let mut max = gates.first().unwrap().clone();
         for gate in gates.iter(){
@@ -141,13 +389,23 @@ impl AIRRadar {
             gates,
             meta: ScanMetadata{
                 name: path.to_string_lossy().to_string(),
-                angular_resolution: (max.azimuth - min.azimuth) / (azimuth_data.len() as f32),
+                angular_resolution: match sweep_mode {
+                    SweepMode::Rhi => (max.elevation - min.elevation) / (azimuth_data.len() as f32),
+                    _ => (max.azimuth - min.azimuth) / (azimuth_data.len() as f32),
+                },
                 range_resolution: (max.range - min.range) / (range_data.len() as f32),
                 min,
                 max,
                 start_time,
                 end_time,
                 sweep_index: sweep as usize,
+                volume_start,
+                ray_count: azimuth_data.len(),
+                bin_count: range_data.len(),
+                sweep_mode,
+                fixed_angle,
+                latitude,
+                longitude,
             }
         };
     }
@@ -158,15 +416,7 @@ impl Radar for AIRRadar{
         let (tx, rx) = std::sync::mpsc::sync_channel(8);
 
         let mut all_paths = Vec::new();
-        for entry in [
-            //"AIR_cfradial/cfrad.20130531_231156_AIR_v1_s1.nc",
-            "AIR_cfradial/cfrad*v1_*.nc",
-            //"AIR_cfradial/cfrad*v2_*.nc",
-            //"AIR_cfradial/cfrad.20130531_231204_AIR_v2_s*.nc",
-            //"AIR_cfradial/cfrad.20130531_231211_AIR_v3_s*.nc",
-            //"AIR_cfradial/cfrad.20130531_231219_AIR_v4_s*.nc",
-            //"AIR_cfradial/cfrad.20130531_231226_AIR_v5_s*.nc",
-        ] {
+        for entry in self.sources.0.iter() {
             let glob = glob::glob(entry).unwrap();
             let paths: Vec<_> = glob.collect();
             all_paths.extend(paths.into_iter().filter_map(|path| {
@@ -181,11 +431,12 @@ impl Radar for AIRRadar{
         all_paths.sort();
 
         let count = all_paths.len();
+        let radar = self.clone();
         std::thread::spawn(move || {
                 all_paths.par_iter().for_each({
                     let tx = tx.clone();
                     move |path| {
-                        let scan = Self::get_gates_from_file(&path);
+                        let scan = radar.get_gates_from_file(&path);
                         tx.send(scan).unwrap();
                     }
                 });
@@ -200,7 +451,7 @@ mod test{
     use crate::radar::Radar;
     #[test]
     fn test_air_read(){
-        let radar = AIRRadar{};
+        let radar = AIRRadar::default();
         let gates = radar.get_gates();
     }
 }
\ No newline at end of file
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::f32::consts::TAU;
+use std::sync::Arc;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use chrono::{DateTime, TimeDelta, Utc};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::colortable::ColorTable;
+use crate::grid::{Grid, PolarIndex};
+use crate::input::{Action, Actions};
+use crate::radar::{Gate, Scan};
+use crate::scan::ScanInfo;
+use crate::volume::{VolumeLoaded, Volumes};
+
+/// Gaps between volumes longer than this are not filled with the previous rain rate.
+const MAX_GAP_MINUTES: i64 = 10;
+/// Height of the accumulation map above the ground plane, to keep it from z-fighting.
+const MAP_HEIGHT: f32 = 5.0;
+
+/// Z = a R^b relations, with R in mm/h.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ZrRelation {
+    MarshallPalmer,
+    Convective,
+    Tropical,
+}
+
+impl ZrRelation {
+    pub const ALL: [ZrRelation; 3] = [ZrRelation::MarshallPalmer, ZrRelation::Convective, ZrRelation::Tropical];
+
+    /// `(a, b)` in Z = a R^b.
+    pub fn coefficients(&self) -> (f32, f32) {
+        match self {
+            ZrRelation::MarshallPalmer => (200.0, 1.6),
+            ZrRelation::Convective => (300.0, 1.4),
+            ZrRelation::Tropical => (250.0, 1.2),
+        }
+    }
+
+    pub fn next(&self) -> ZrRelation {
+        let i = ZrRelation::ALL.iter().position(|r| r == self).unwrap();
+        ZrRelation::ALL[(i + 1) % ZrRelation::ALL.len()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Accumulation {
+    Off,
+    OneHour,
+    StormTotal,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct RainSettings {
+    pub accumulation: Accumulation,
+    pub relation: ZrRelation,
+    /// Use R(KDP) where KDP is reliable, which is immune to attenuation and hail contamination.
+    pub use_kdp: bool,
+    pub kdp_threshold: f32,
+    pub kdp_min_reflectivity: f32,
+    /// Reflectivity is capped here before the Z-R relation so hail doesn't produce absurd rates.
+    pub hail_cap: f32,
+    pub min_reflectivity: f32,
+    pub spacing: f32,
+    /// Colour table for accumulations in mm.
+    pub table: ColorTable,
+}
+
+impl Default for RainSettings {
+    fn default() -> Self {
+        Self {
+            accumulation: Accumulation::Off,
+            relation: ZrRelation::MarshallPalmer,
+            use_kdp: true,
+            kdp_threshold: 0.3,
+            kdp_min_reflectivity: 35.0,
+            hail_cap: 53.0,
+            min_reflectivity: 10.0,
+            spacing: 500.0,
+            table: ColorTable::accumulation(),
+        }
+    }
+}
+
+/// X-band R(KDP) power law coefficients, R = c KDP^d.
+const KDP_COEFFICIENT: f32 = 19.63;
+const KDP_EXPONENT: f32 = 0.823;
+
+/// Rain rate in mm/h, from attenuation corrected reflectivity when available.
+pub fn rain_rate(gate: &Gate, settings: &RainSettings) -> f32 {
+    let dbz = if gate.corrected_reflectivity.is_nan() { gate.reflectivity } else { gate.corrected_reflectivity };
+    if dbz.is_nan() {
+        return f32::NAN;
+    }
+    if dbz < settings.min_reflectivity {
+        return 0.0;
+    }
+
+    let kdp = gate.specific_differential_phase;
+    if settings.use_kdp && kdp >= settings.kdp_threshold && dbz >= settings.kdp_min_reflectivity {
+        return KDP_COEFFICIENT * kdp.powf(KDP_EXPONENT);
+    }
+
+    let (a, b) = settings.relation.coefficients();
+    let z = 10f32.powf(dbz.min(settings.hail_cap) / 10.0);
+    (z / a).powf(1.0 / b)
+}
+
+/// Rain rate from one sweep on a single-level grid at ground level.
+pub fn rate_grid(scan: &Scan, settings: &RainSettings) -> Grid {
+    let index = PolarIndex::new(scan);
+    let radius = scan.meta.max.range;
+    let n = (2.0 * radius / settings.spacing).ceil() as u32 + 1;
+    let origin = Vec3::new(-radius, 0.0, -radius);
+    let spacing = Vec3::new(settings.spacing, 1.0, settings.spacing);
+
+    let mut data = vec![f32::NAN; (n * n) as usize];
+    data.par_chunks_mut(n as usize).enumerate().for_each(|(k, row)| {
+        for (i, value) in row.iter_mut().enumerate() {
+            let p = origin + spacing * Vec3::new(i as f32, 0.0, k as f32);
+            let range = (p.x * p.x + p.z * p.z).sqrt();
+            let azimuth = p.z.atan2(p.x).rem_euclid(TAU);
+            if let Some(gate) = index.gate(azimuth, range) {
+                *value = rain_rate(gate, settings);
+            }
+        }
+    });
+
+    Grid { origin, spacing, dims: UVec3::new(n, 1, n), data }
+}
+
+/// Lowest-sweep rain rate grid for each volume, keyed by volume start.
+#[derive(Resource, Default)]
+pub struct RainRates(pub BTreeMap<DateTime<Utc>, Arc<Grid>>);
+
+impl RainRates {
+    /// Accumulated rain in mm between `from` and `to`, holding each volume's rate until the next volume.
+    pub fn accumulate(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<Grid> {
+        let mut total: Option<Grid> = None;
+        let mut volumes = self.0.range(..=to).peekable();
+        while let Some((start, rates)) = volumes.next() {
+            let end = volumes.peek().map(|(next, _)| **next).unwrap_or(to);
+            let end = end.min(*start + TimeDelta::minutes(MAX_GAP_MINUTES)).min(to);
+            let start = (*start).max(from);
+            if end <= start {
+                continue;
+            }
+
+            let hours = (end - start).num_milliseconds() as f32 / 3_600_000.0;
+            let total = total.get_or_insert_with(|| Grid { data: vec![0.0; rates.data.len()], ..(**rates).clone() });
+            if total.dims != rates.dims {
+                continue;
+            }
+            for (sum, rate) in total.data.iter_mut().zip(rates.data.iter()) {
+                if !rate.is_nan() {
+                    *sum += rate * hours;
+                }
+            }
+        }
+        total
+    }
+}
+
+pub fn update_rain_rates(
+    mut events: EventReader<VolumeLoaded>,
+    mut rates: ResMut<RainRates>,
+    volumes: Res<Volumes>,
+    settings: Res<RainSettings>,
+) {
+    let starts: Vec<_> = if settings.is_changed() && !settings.is_added() {
+        events.clear();
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+
+    for start in starts {
+        let Some(volume) = volumes.0.get(&start) else {
+            continue;
+        };
+        let Some(lowest) = volume.scans.iter().min_by(|a, b| a.elevation().total_cmp(&b.elevation())) else {
+            continue;
+        };
+        rates.0.insert(start, Arc::new(rate_grid(lowest, &settings)));
+    }
+}
+
+#[derive(Component)]
+pub struct AccumulationMap {
+    image: Handle<Image>,
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
+pub fn setup_accumulation_map(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let empty = Grid { origin: Vec3::ZERO, spacing: Vec3::ONE, dims: UVec3::ONE, data: vec![f32::NAN] };
+    let image = images.add(map_image(&empty, &ColorTable::accumulation()));
+    let mesh = meshes.add(map_mesh(&empty));
+    let material = materials.add(StandardMaterial {
+        base_color_texture: Some(image.clone()),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        cull_mode: None,
+        double_sided: true,
+        ..default()
+    });
+    commands.spawn((
+        PbrBundle {
+            mesh: mesh.clone(),
+            material: material.clone(),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        AccumulationMap { image, mesh, material },
+    ));
+}
+
+/// Recompute the displayed accumulation when the current volume, the period or the rain rates change.
+pub fn update_accumulation_map(
+    mut shown: Local<Option<(DateTime<Utc>, Accumulation)>>,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut query: Query<(&AccumulationMap, &mut Visibility)>,
+    info: Res<ScanInfo>,
+    settings: Res<RainSettings>,
+    rates: Res<RainRates>,
+) {
+    let Ok((map, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let Some(time) = info.time else {
+        return;
+    };
+    let current = rates.0.range(..=time).next_back().map(|(start, _)| *start);
+    let (Some(current), true) = (current, settings.accumulation != Accumulation::Off) else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    *visibility = Visibility::Visible;
+    if *shown == Some((current, settings.accumulation)) && !rates.is_changed() {
+        return;
+    }
+    *shown = Some((current, settings.accumulation));
+
+    let from = match settings.accumulation {
+        Accumulation::OneHour => time - TimeDelta::hours(1),
+        _ => DateTime::<Utc>::MIN_UTC,
+    };
+    let Some(total) = rates.accumulate(from, time) else {
+        return;
+    };
+    images.insert(&map.image, map_image(&total, &settings.table));
+    meshes.insert(&map.mesh, map_mesh(&total));
+    // the material's bind group only picks up the new texture once the material itself changes
+    materials.get_mut(&map.material);
+}
+
+/// Texels follow the grid: u along x and v along z.
+pub fn map_image(grid: &Grid, table: &ColorTable) -> Image {
+    let data = grid.data.iter()
+        .flat_map(|mm| if mm.is_nan() { [0; 4] } else { table.color(*mm).as_rgba_u8() })
+        .collect();
+    Image::new(
+        Extent3d {
+            width: grid.dims.x,
+            height: grid.dims.z,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+pub fn map_mesh(grid: &Grid) -> Mesh {
+    let min = grid.origin;
+    let max = grid.origin + grid.spacing * (grid.dims.max(UVec3::splat(2)) - UVec3::ONE).as_vec3();
+    let positions = vec![
+        [min.x, MAP_HEIGHT, min.z],
+        [max.x, MAP_HEIGHT, min.z],
+        [max.x, MAP_HEIGHT, max.z],
+        [min.x, MAP_HEIGHT, max.z],
+    ];
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; 4])
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]])
+        .with_inserted_indices(Indices::U32(vec![0, 2, 1, 0, 3, 2]))
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<RainSettings>,
+) {
+    // switching the period only changes what is summed, not the rain rates
+    if actions.just_pressed(Action::CycleAccumulation) {
+        let settings = settings.bypass_change_detection();
+        settings.accumulation = match settings.accumulation {
+            Accumulation::Off => Accumulation::OneHour,
+            Accumulation::OneHour => Accumulation::StormTotal,
+            Accumulation::StormTotal => Accumulation::Off,
+        };
+    }
+
+    if actions.just_pressed(Action::CycleZrRelation) {
+        settings.relation = settings.relation.next();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use bevy::math::{UVec3, Vec3};
+    use chrono::{DateTime, TimeDelta, Utc};
+    use crate::grid::Grid;
+    use crate::rain::RainRates;
+
+    #[test]
+    fn test_accumulate_holds_rate_until_next_volume() {
+        let start = "2013-05-31 23:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let grid = |rate: f32| Arc::new(Grid { origin: Vec3::ZERO, spacing: Vec3::ONE, dims: UVec3::new(1, 1, 1), data: vec![rate] });
+        let mut rates = RainRates::default();
+        rates.0.insert(start, grid(12.0));
+        rates.0.insert(start + TimeDelta::minutes(5), grid(f32::NAN));
+        rates.0.insert(start + TimeDelta::minutes(10), grid(60.0));
+
+        // 12 mm/h for 5 minutes, nothing for 5, then 60 mm/h for 5
+        let total = rates.accumulate(DateTime::<Utc>::MIN_UTC, start + TimeDelta::minutes(15)).unwrap();
+        assert!((total.data[0] - 6.0).abs() < 1e-4, "{}", total.data[0]);
+
+        let last_hour = rates.accumulate(start + TimeDelta::minutes(12), start + TimeDelta::minutes(15)).unwrap();
+        assert!((last_hour.data[0] - 3.0).abs() < 1e-4, "{}", last_hour.data[0]);
+    }
+}
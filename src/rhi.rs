@@ -0,0 +1,128 @@
+use bevy::prelude::*;
+use crate::colortable::ColorTables;
+use crate::panel::{self, Canvas};
+use crate::scan::{ScanInfo, ScanType};
+use crate::srv::StormMotionAt;
+use crate::volume::Volumes;
+
+const PANEL_WIDTH: u32 = 400;
+const PANEL_HEIGHT: u32 = 200;
+
+#[derive(Resource, Debug, Clone)]
+pub struct RhiSettings {
+    pub max_range: f32,
+    pub max_height: f32,
+}
+
+impl Default for RhiSettings {
+    fn default() -> Self {
+        Self {
+            max_range: 30000.0,
+            max_height: 15000.0,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct RhiPanel(Handle<Image>);
+
+pub fn setup_rhi_panel(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    let (entity, image) = panel::spawn_panel(&mut commands, &mut images, &canvas, "RHI (range-height)", Style {
+        left: Val::Px(10.0),
+        bottom: Val::Px(340.0),
+        ..default()
+    });
+    commands.entity(entity).insert(RhiPanel(image));
+}
+
+/// Mark the azimuth of the displayed RHI on the ground.
+pub fn draw_rhi_azimuth(
+    mut gizmos: Gizmos,
+    info: Res<ScanInfo>,
+    settings: Res<RhiSettings>,
+    volumes: Res<Volumes>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+    let Some((_, volume)) = volumes.0.range(..=time).next_back() else {
+        return;
+    };
+    if let Some(scan) = volume.rhis.iter().filter(|scan| scan.meta.start_time <= time).last() {
+        let azimuth = scan.meta.fixed_angle;
+        let direction = Vec3::new(azimuth.cos(), 0.0, azimuth.sin());
+        gizmos.line(Vec3::ZERO, direction * settings.max_range, Color::WHITE);
+    }
+}
+
+/// Show the latest RHI of the current volume in range-height coordinates, whenever there is one.
+pub fn update_rhi_panel(
+    mut shown: Local<Option<(String, ScanType, Vec3)>>,
+    mut images: ResMut<Assets<Image>>,
+    mut query: Query<(&RhiPanel, &mut Visibility)>,
+    info: Res<ScanInfo>,
+    settings: Res<RhiSettings>,
+    volumes: Res<Volumes>,
+    tables: Res<ColorTables>,
+    storm_motion: StormMotionAt,
+) {
+    let Ok((panel, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let scan = info.time.and_then(|time| {
+        let (start, volume) = volumes.0.range(..=time).next_back()?;
+        Some((*start, volume.rhis.iter().filter(|scan| scan.meta.start_time <= time).last()?))
+    });
+    let Some((start, scan)) = scan.filter(|(_, scan)| scan.meta.bin_count > 1) else {
+        *visibility = Visibility::Hidden;
+        *shown = None;
+        return;
+    };
+    if *visibility != Visibility::Inherited {
+        *visibility = Visibility::Inherited;
+    }
+
+    // only storm-relative velocity moves with the storm motion
+    let storm = if info.scan_type.per_gate() { Vec3::ZERO } else { storm_motion.at(start) };
+    let key = (scan.meta.name.clone(), info.scan_type, storm);
+    if shown.as_ref() == Some(&key) && !tables.is_changed() {
+        return;
+    }
+    *shown = Some(key);
+
+    let table = tables.get(info.scan_type);
+    let first = scan.gate(0, 0).range;
+    let last = scan.gate(0, scan.meta.bin_count - 1).range;
+    let step = ((last - first) / (scan.meta.bin_count - 1) as f32).max(1.0);
+    let mut canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    canvas.clear(Color::rgba(0.0, 0.0, 0.0, 0.0));
+    for row in 0..PANEL_HEIGHT {
+        let height = (1.0 - (row as f32 + 0.5) / PANEL_HEIGHT as f32) * settings.max_height;
+        for column in 0..PANEL_WIDTH {
+            let distance = (column as f32 + 0.5) / PANEL_WIDTH as f32 * settings.max_range;
+            let Some(ray) = scan.ray_at_elevation(height.atan2(distance)) else {
+                continue;
+            };
+            let bin = ((distance.hypot(height) - first) / step).round();
+            if bin < 0.0 || bin as usize >= scan.meta.bin_count {
+                continue;
+            }
+            let value = info.scan_type.relative_value(scan.gate(ray, bin as usize), storm);
+            if !value.is_nan() {
+                canvas.pixel(column as i32, row as i32, table.color(value).with_a(1.0));
+            }
+        }
+    }
+    for km in 1..(settings.max_height / 1000.0) as u32 {
+        let y = (1.0 - km as f32 * 1000.0 / settings.max_height) * PANEL_HEIGHT as f32;
+        canvas.line(Vec2::new(0.0, y), Vec2::new(PANEL_WIDTH as f32, y), Color::rgba(1.0, 1.0, 1.0, 0.2));
+    }
+
+    if let Some(image) = images.get_mut(&panel.0) {
+        canvas.write(image);
+    }
+}
@@ -0,0 +1,210 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+use crate::input::{Action, Actions};
+use crate::llsd::azimuth_difference;
+use crate::radar::{Scan, ScanMetadata};
+use crate::scan::ScanInfo;
+use crate::volume::{VolumeLoaded, Volumes};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RotationKind {
+    Mesocyclone,
+    Tvs,
+}
+
+#[derive(Debug, Clone)]
+pub struct Detection {
+    pub kind: RotationKind,
+    pub position: Vec3,
+    /// Velocity difference across the couplet, positive for cyclonic rotation.
+    pub delta_v: f32,
+}
+
+/// Detection thresholds, from the `rotation` section of the config file.
+#[derive(Resource, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RotationSettings {
+    pub enabled: bool,
+    /// Minimum gate-to-gate velocity difference for a tornado vortex signature.
+    pub tvs_delta_v: f32,
+    /// Minimum velocity difference across a mesocyclone couplet.
+    pub meso_delta_v: f32,
+    /// Minimum couplet shear for a mesocyclone, in s^-1.
+    pub meso_shear: f32,
+    /// Widest couplet searched along a range ring, in metres.
+    pub max_couplet_width: f32,
+    pub min_range: f32,
+    /// Detections of the same kind closer than this are merged, keeping the strongest.
+    pub cluster_distance: f32,
+}
+
+impl Default for RotationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tvs_delta_v: 35.0,
+            meso_delta_v: 25.0,
+            meso_shear: 0.008,
+            max_couplet_width: 3000.0,
+            min_range: 3000.0,
+            cluster_distance: 2000.0,
+        }
+    }
+}
+
+/// Search each range ring for azimuthally adjacent inbound/outbound velocity pairs.
+pub fn detect(scan: &Scan, settings: &RotationSettings) -> Vec<Detection> {
+    let rays = scan.meta.ray_count;
+    let bins = scan.meta.bin_count;
+    let mut candidates = Vec::new();
+
+    for bin in 0..bins {
+        for ray in 0..rays {
+            let a = scan.gate(ray, bin);
+            if a.doppler_velocity.is_nan() || a.range < settings.min_range {
+                continue;
+            }
+
+            for width in 1..rays {
+                let b = scan.gate((ray + width) % rays, bin);
+                let arc = a.range * azimuth_difference(b.azimuth, a.azimuth).abs();
+                if arc > settings.max_couplet_width {
+                    break;
+                }
+                if b.doppler_velocity.is_nan() {
+                    continue;
+                }
+
+                let delta_v = b.doppler_velocity - a.doppler_velocity;
+                let kind = if width == 1 && delta_v.abs() >= settings.tvs_delta_v {
+                    RotationKind::Tvs
+                } else if delta_v.abs() >= settings.meso_delta_v && delta_v.abs() / arc.max(1.0) >= settings.meso_shear {
+                    RotationKind::Mesocyclone
+                } else {
+                    continue;
+                };
+
+                candidates.push(Detection {
+                    kind,
+                    position: (a.as_cart() + b.as_cart()) / 2.0,
+                    delta_v,
+                });
+            }
+        }
+    }
+
+    candidates.sort_by(|a, b| b.delta_v.abs().total_cmp(&a.delta_v.abs()));
+    let mut detections: Vec<Detection> = Vec::new();
+    for candidate in candidates {
+        let duplicate = detections.iter().any(|d| {
+            d.kind == candidate.kind && d.position.distance(candidate.position) < settings.cluster_distance
+        });
+        if !duplicate {
+            detections.push(candidate);
+        }
+    }
+    detections
+}
+
+#[derive(Component, Debug)]
+pub struct RotationMarker {
+    pub kind: RotationKind,
+    pub delta_v: f32,
+}
+
+/// Detect couplets in every sweep of newly loaded volumes, or all volumes when the thresholds change.
+pub fn detect_rotation(
+    mut commands: Commands,
+    mut events: EventReader<VolumeLoaded>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    volumes: Res<Volumes>,
+    settings: Res<RotationSettings>,
+    existing: Query<Entity, With<RotationMarker>>,
+) {
+    let starts: Vec<_> = if settings.is_changed() && !settings.is_added() {
+        events.clear();
+        existing.iter().for_each(|entity| commands.entity(entity).despawn());
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+    if starts.is_empty() {
+        return;
+    }
+
+    let meso_mesh = meshes.add(Torus::new(600.0, 900.0));
+    let tvs_mesh = meshes.add(Cylinder::new(150.0, 3000.0));
+    let meso_material = materials.add(StandardMaterial {
+        base_color: Color::YELLOW,
+        unlit: true,
+        ..default()
+    });
+    let tvs_material = materials.add(StandardMaterial {
+        base_color: Color::FUCHSIA,
+        unlit: true,
+        ..default()
+    });
+
+    for start in starts {
+        let Some(volume) = volumes.0.get(&start) else {
+            continue;
+        };
+
+        for scan in volume.scans.iter() {
+            for detection in detect(scan, &settings) {
+                let (mesh, material) = match detection.kind {
+                    RotationKind::Mesocyclone => (meso_mesh.clone(), meso_material.clone()),
+                    RotationKind::Tvs => (tvs_mesh.clone(), tvs_material.clone()),
+                };
+                commands.spawn((
+                    PbrBundle {
+                        mesh,
+                        material,
+                        transform: Transform::from_translation(detection.position),
+                        visibility: Visibility::Hidden,
+                        ..default()
+                    },
+                    RotationMarker {
+                        kind: detection.kind,
+                        delta_v: detection.delta_v,
+                    },
+                    scan.meta.clone(),
+                ));
+            }
+        }
+    }
+}
+
+pub fn visible_markers(
+    info: Res<ScanInfo>,
+    settings: Res<RotationSettings>,
+    mut query: Query<(&ScanMetadata, &mut Visibility), With<RotationMarker>>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+
+    let window_start = time - info.window();
+    for (scan, mut visibility) in query.iter_mut() {
+        let visible = settings.enabled
+            && scan.end_time > window_start
+            && scan.end_time <= time
+            && !info.hidden_scans.contains(&(scan.volume_start, scan.sweep_index));
+        let wanted = if visible { Visibility::Visible } else { Visibility::Hidden };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<RotationSettings>,
+) {
+    // toggling only hides the markers, it shouldn't run detection again
+    if actions.just_pressed(Action::ToggleRotation) {
+        let settings = settings.bypass_change_detection();
+        settings.enabled = !settings.enabled;
+    }
+}
@@ -0,0 +1,217 @@
+use bevy::prelude::*;
+use crate::input::{Action, Actions};
+use crate::scan::{RenderMode, ScanInfo};
+use crate::views::ViewPanel;
+
+/// Longest the scale bar gets, in logical pixels.
+const SCALE_BAR_PIXELS: f32 = 150.0;
+/// Heights marked on the axis over the radar, in km.
+const HEIGHT_MARKS: [f32; 4] = [5.0, 10.0, 15.0, 20.0];
+
+/// How much heights are stretched on screen. The scene itself stays in metres, so gates, overlays, the
+/// ground and picking all agree; only the cameras see it stretched.
+#[derive(Resource)]
+pub struct SceneScale {
+    pub exaggeration: f32,
+}
+
+impl Default for SceneScale {
+    fn default() -> Self {
+        Self {
+            exaggeration: 1.0,
+        }
+    }
+}
+
+impl SceneScale {
+    /// How steep a line climbing at `elevation` radians looks once stretched.
+    pub fn apparent_elevation(&self, elevation: f32) -> f32 {
+        (elevation.tan() * self.exaggeration).atan()
+    }
+}
+
+/// Parent of the 3D cameras. Squashing it vertically stretches everything they see by as much, so camera
+/// poses are in stretched space.
+#[derive(Component)]
+pub struct CameraRig;
+
+#[derive(Component)]
+pub struct ScaleBar;
+
+#[derive(Component)]
+pub struct ScaleBarLabel;
+
+#[derive(Component)]
+pub struct HeightLabel(f32);
+
+/// The longest 1, 2 or 5 times a power of ten metres that isn't over `max`.
+pub fn nice_length(max: f32) -> f32 {
+    let power = 10f32.powf(max.log10().floor());
+    [5.0, 2.0, 1.0].into_iter().map(|step| step * power).find(|length| *length <= max).unwrap_or(power)
+}
+
+pub fn setup_scale_bar(
+    mut commands: Commands,
+) {
+    commands.spawn(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(40.0),
+            bottom: Val::Px(85.0),
+            flex_direction: FlexDirection::Column,
+            ..default()
+        },
+        ..default()
+    }).with_children(|parent| {
+        parent.spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Px(0.0),
+                    height: Val::Px(4.0),
+                    ..default()
+                },
+                background_color: Color::WHITE.into(),
+                ..default()
+            },
+            ScaleBar,
+        ));
+        parent.spawn((
+            TextBundle::from_section("", TextStyle { font_size: 16.0, ..default() }),
+            ScaleBarLabel,
+        ));
+    });
+
+    for km in HEIGHT_MARKS {
+        commands.spawn((
+            TextBundle::from_section(format!("{km} km"), TextStyle { font_size: 14.0, ..default() })
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    ..default()
+                }),
+            HeightLabel(km),
+        ));
+    }
+}
+
+/// Squash the camera rig to match the exaggeration.
+pub fn apply_exaggeration(
+    scale: Res<SceneScale>,
+    mut rigs: Query<&mut Transform, With<CameraRig>>,
+) {
+    if !scale.is_changed() {
+        return;
+    }
+    for mut transform in rigs.iter_mut() {
+        transform.scale = Vec3::new(1.0, 1.0 / scale.exaggeration, 1.0);
+    }
+}
+
+/// Size the scale bar to a round distance on the ground in the middle of the first panel.
+pub fn update_scale_bar(
+    scale: Res<SceneScale>,
+    cameras: Query<(&Camera, &GlobalTransform, &ViewPanel)>,
+    mut bars: Query<&mut Style, With<ScaleBar>>,
+    mut labels: Query<&mut Text, With<ScaleBarLabel>>,
+) {
+    let Some((camera, transform, _)) = cameras.iter().find(|(_, _, panel)| panel.0 == 0) else {
+        return;
+    };
+    let (Ok(mut style), Ok(mut text)) = (bars.get_single_mut(), labels.get_single_mut()) else {
+        return;
+    };
+    let center = camera.logical_viewport_size().map(|size| size / 2.0);
+    let ground = center
+        .and_then(|center| camera.viewport_to_world(transform, center))
+        .and_then(|ray| ray.intersect_plane(Vec3::ZERO, Plane3d::new(Vec3::Y)).map(|distance| ray.get_point(distance)));
+    let right = (transform.right() * Vec3::new(1.0, 0.0, 1.0)).normalize_or_zero();
+    let pixels_per_metre = ground.and_then(|ground| {
+        let a = camera.world_to_viewport(transform, ground)?;
+        let b = camera.world_to_viewport(transform, ground + right * 1_000.0)?;
+        Some(a.distance(b) / 1_000.0)
+    });
+
+    let (width, value) = match pixels_per_metre.filter(|p| *p > 0.0) {
+        Some(pixels_per_metre) => {
+            let length = nice_length(SCALE_BAR_PIXELS / pixels_per_metre);
+            let distance = if length >= 1_000.0 { format!("{} km", length / 1_000.0) } else { format!("{length} m") };
+            (Val::Px(length * pixels_per_metre), format!("{distance}, heights x{:.1}", scale.exaggeration))
+        }
+        None => (Val::Px(0.0), format!("heights x{:.1}", scale.exaggeration)),
+    };
+    if style.width != width {
+        style.width = width;
+    }
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+/// A height axis over the radar, with its marks labelled in real km however much it is stretched. Plan
+/// view looks straight down it, so it is left out there.
+pub fn draw_height_axis(
+    mut gizmos: Gizmos,
+    info: Res<ScanInfo>,
+    cameras: Query<(&Camera, &GlobalTransform, &ViewPanel)>,
+    mut labels: Query<(&HeightLabel, &mut Style, &mut Visibility)>,
+) {
+    let plan = info.render_mode == RenderMode::Plan;
+    if !plan {
+        let top = HEIGHT_MARKS[HEIGHT_MARKS.len() - 1] * 1_000.0;
+        gizmos.line(Vec3::ZERO, Vec3::Y * top, Color::GRAY);
+        for km in HEIGHT_MARKS {
+            gizmos.line(Vec3::new(-1_000.0, km * 1_000.0, 0.0), Vec3::new(1_000.0, km * 1_000.0, 0.0), Color::GRAY);
+        }
+    }
+
+    let camera = cameras.iter().find(|(_, _, panel)| panel.0 == 0).filter(|_| !plan);
+    for (label, mut style, mut visibility) in labels.iter_mut() {
+        let position = camera.and_then(|(camera, transform, _)| {
+            let offset = camera.logical_viewport_rect()?.min;
+            Some(camera.world_to_viewport(transform, Vec3::new(0.0, label.0 * 1_000.0, 0.0))? + offset)
+        });
+        let Some(position) = position else {
+            if *visibility != Visibility::Hidden {
+                *visibility = Visibility::Hidden;
+            }
+            continue;
+        };
+        let (left, top) = (Val::Px(position.x + 6.0), Val::Px(position.y - 8.0));
+        if (style.left, style.top) != (left, top) {
+            style.left = left;
+            style.top = top;
+        }
+        if *visibility != Visibility::Inherited {
+            *visibility = Visibility::Inherited;
+        }
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut scale: ResMut<SceneScale>,
+) {
+    if actions.just_pressed(Action::RaiseExaggeration) {
+        scale.exaggeration = (scale.exaggeration * 1.25).min(20.0);
+    }
+    if actions.just_pressed(Action::LowerExaggeration) {
+        scale.exaggeration = (scale.exaggeration / 1.25).max(1.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::scale::{nice_length, SceneScale};
+
+    #[test]
+    fn test_scale_bar_rounds_down_and_exaggeration_steepens_beams() {
+        assert_eq!(nice_length(37_000.0), 20_000.0);
+        assert_eq!(nice_length(5_000.0), 5_000.0);
+        assert_eq!(nice_length(140.0), 100.0);
+        assert_eq!(nice_length(9.9), 5.0);
+
+        let scale = SceneScale { exaggeration: 3.0 };
+        let elevation = 10f32.to_radians();
+        assert!((scale.apparent_elevation(elevation).tan() - 3.0 * elevation.tan()).abs() < 1e-6);
+        assert!((SceneScale::default().apparent_elevation(elevation) - elevation).abs() < 1e-6);
+    }
+}
@@ -1,41 +1,214 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ops::{AddAssign, SubAssign};
 use std::sync::{Arc, Mutex};
 use bevy::asset::{Assets, Handle};
-use bevy::input::{ButtonInput, InputPlugin};
+use bevy::input::InputPlugin;
 use bevy::input::keyboard::Key;
 use bevy::math::{Quat, Vec3};
 use bevy::pbr::StandardMaterial;
-use bevy::prelude::{Color, Commands, Component, Cuboid, Entity, KeyCode, Mesh, Query, Res, ResMut, Resource, SpatialBundle, TextBundle, TextStyle, Transform, Visibility, With};
-use bevy::render::view::NoFrustumCulling;
-use bevy::text::{Text, TextSection};
+use bevy::prelude::{Commands, Component, Cuboid, DetectChanges, Entity, EventWriter, Local, Mesh, Query, Res, ResMut, Resource, SpatialBundle, Transform, Visibility};
+use bevy::render::view::{NoFrustumCulling, RenderLayers};
 use bevy::time::Time;
 use bevy::ui::{Style, Val};
-use bevy::utils::default;
 use chrono::{DateTime, TimeDelta, Utc};
 use itertools::Position;
+use serde::{Deserialize, Serialize};
 use crate::instance::{InstanceData, InstanceMaterialData };
 //use crate::instance::{InstanceData, InstanceMaterialData};
+use crate::colortable::{ColorTable, ColorTables};
+use crate::input::{Action, Actions};
 use crate::radar;
 use crate::scan::ScanType::Reflectivity;
-use crate::radar::{Radar, Scan, ScanMetadata};
+use crate::radar::{DataSources, Gate, Processing, Radar, Scan, ScanMetadata, SweepMode};
+use crate::session::Session;
+use crate::srv;
+use crate::volume::{VolumeLoaded, Volumes};
 use crate::uniform::InstanceUniforms;
+use crate::views::{self, ViewLayout};
 
-#[derive(Component, Debug, Eq, PartialEq)]
+#[derive(Component, Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum ScanType {
     Reflectivity,
     Velocity,
+    AzimuthalShear,
+    Divergence,
+    DifferentialReflectivity,
+    DifferentialPhase,
+    CrossCorrelation,
+    SpecificDifferentialPhase,
+    CorrectedReflectivity,
+    CorrectedDifferentialReflectivity,
+    HydrometeorClass,
+    StormRelativeVelocity,
 }
 
-#[derive(Resource, Debug)]
+impl ScanType {
+    pub const ALL: [ScanType; 12] = [
+        ScanType::Reflectivity,
+        ScanType::Velocity,
+        ScanType::AzimuthalShear,
+        ScanType::Divergence,
+        ScanType::DifferentialReflectivity,
+        ScanType::DifferentialPhase,
+        ScanType::CrossCorrelation,
+        ScanType::SpecificDifferentialPhase,
+        ScanType::CorrectedReflectivity,
+        ScanType::CorrectedDifferentialReflectivity,
+        ScanType::HydrometeorClass,
+        ScanType::StormRelativeVelocity,
+    ];
+
+    pub fn value(&self, gate: &Gate) -> f32 {
+        match self {
+            ScanType::Reflectivity => gate.reflectivity,
+            ScanType::Velocity => gate.doppler_velocity,
+            ScanType::AzimuthalShear => gate.azimuthal_shear,
+            ScanType::Divergence => gate.divergence,
+            ScanType::DifferentialReflectivity => gate.differential_reflectivity,
+            ScanType::DifferentialPhase => gate.differential_phase,
+            ScanType::CrossCorrelation => gate.cross_correlation,
+            ScanType::SpecificDifferentialPhase => gate.specific_differential_phase,
+            ScanType::CorrectedReflectivity => gate.corrected_reflectivity,
+            ScanType::CorrectedDifferentialReflectivity => gate.corrected_differential_reflectivity,
+            ScanType::HydrometeorClass => gate.hydrometeor_class,
+            // depends on the storm motion, see `relative_value`
+            ScanType::StormRelativeVelocity => f32::NAN,
+        }
+    }
+
+    /// `value`, taking storm-relative velocity against `storm`, the storm motion in m/s.
+    pub fn relative_value(&self, gate: &Gate, storm: Vec3) -> f32 {
+        match self {
+            ScanType::StormRelativeVelocity => srv::storm_relative(gate, storm),
+            _ => self.value(gate),
+        }
+    }
+
+    /// Smallest magnitude drawn as a gate. Polarimetric moments are drawn wherever reflectivity reaches its
+    /// threshold, and hydrometeor classes wherever reflectivity reaches theirs.
+    pub fn threshold(&self) -> f32 {
+        match self {
+            ScanType::Reflectivity | ScanType::CorrectedReflectivity => 35.0,
+            ScanType::HydrometeorClass => 10.0,
+            ScanType::Velocity => 20.0,
+            ScanType::StormRelativeVelocity => 10.0,
+            ScanType::AzimuthalShear | ScanType::Divergence => 0.004,
+            _ => 0.0,
+        }
+    }
+
+    /// The moment whose threshold decides where this one is drawn.
+    pub fn threshold_moment(&self) -> ScanType {
+        match self {
+            ScanType::Reflectivity | ScanType::CorrectedReflectivity | ScanType::HydrometeorClass
+            | ScanType::Velocity | ScanType::StormRelativeVelocity | ScanType::AzimuthalShear | ScanType::Divergence => *self,
+            _ => ScanType::Reflectivity,
+        }
+    }
+
+    /// Whether a gate is drawn for this moment, given the threshold of its `threshold_moment`.
+    pub fn visible(&self, gate: &Gate, threshold: f32) -> bool {
+        let value = self.value(gate);
+        match self {
+            ScanType::Reflectivity | ScanType::CorrectedReflectivity => value >= threshold,
+            ScanType::Velocity | ScanType::StormRelativeVelocity | ScanType::AzimuthalShear | ScanType::Divergence => value.abs() >= threshold,
+            _ => !value.is_nan() && gate.reflectivity >= threshold,
+        }
+    }
+
+    /// Categorical moments hold class indices that must not be interpolated.
+    pub fn categorical(&self) -> bool {
+        *self == ScanType::HydrometeorClass
+    }
+
+    /// Whether `value` has the moment on its own. Moments that also need the storm motion can't be gridded.
+    pub fn per_gate(&self) -> bool {
+        *self != ScanType::StormRelativeVelocity
+    }
+
+    /// The moment after this one in `ALL`, wrapping around. `back` steps the other way.
+    pub fn cycle(&self, back: bool) -> ScanType {
+        let i = ScanType::ALL.iter().position(|s| s == self).unwrap();
+        let len = ScanType::ALL.len();
+        ScanType::ALL[if back { (i + len - 1) % len } else { (i + 1) % len }]
+    }
+}
+
+/// Thresholds set from the control panel, for each moment that has its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds(pub HashMap<ScanType, f32>);
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self(ScanType::ALL.iter()
+            .filter(|scan_type| scan_type.threshold_moment() == **scan_type)
+            .map(|scan_type| (*scan_type, scan_type.threshold()))
+            .collect())
+    }
+}
+
+impl Thresholds {
+    pub fn get(&self, scan_type: ScanType) -> f32 {
+        let moment = scan_type.threshold_moment();
+        self.0.get(&moment).copied().unwrap_or(moment.threshold())
+    }
+}
+
+/// Whether gates are drawn as instanced boxes, ray-marched from a gridded volume, or as one flat sweep
+/// under an orthographic camera (see `plan`).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RenderMode {
+    Instanced,
+    Volume,
+    Plan,
+}
+
+/// Everything but the loading progress and held keys is kept in saved sessions.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ScanInfo {
-    time: Option<DateTime<Utc>>,
-    scan_type: ScanType,
-    filter: f32,
-    step_size: TimeDelta,
-    visible_window: TimeDelta,
-    time_ratio: f32,
-    paused: bool,
-    loaded_scans: usize,
+    pub time: Option<DateTime<Utc>>,
+    pub scan_type: ScanType,
+    pub filter: f32,
+    #[serde(with = "milliseconds")]
+    pub step_size: TimeDelta,
+    #[serde(with = "milliseconds")]
+    pub visible_window: TimeDelta,
+    /// Shrink the visible window to a single sweep while held.
+    #[serde(skip)]
+    pub short_window: bool,
+    pub time_ratio: f32,
+    pub paused: bool,
+    pub reverse: bool,
+    /// Wall-clock seconds the last frame of the loop is held before wrapping.
+    pub dwell_seconds: f32,
+    #[serde(skip)]
+    pub loaded_scans: usize,
+    pub render_mode: RenderMode,
+    /// Morph each sweep towards the next one along the estimated motion instead of switching between them.
+    pub interpolate: bool,
+    /// Playback wraps from the end of this range back to its start.
+    pub loop_range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    pub thresholds: Thresholds,
+    /// Sweeps unticked in the scan list, by volume start and sweep index.
+    pub hidden_scans: HashSet<(DateTime<Utc>, usize)>,
+    /// Loading progress shown in the HUD.
+    #[serde(skip)]
+    pub status: String,
+}
+
+/// `TimeDelta` as whole milliseconds.
+mod milliseconds {
+    use chrono::TimeDelta;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(delta: &TimeDelta, serializer: S) -> Result<S::Ok, S::Error> {
+        delta.num_milliseconds().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TimeDelta, D::Error> {
+        i64::deserialize(deserializer).map(TimeDelta::milliseconds)
+    }
 }
 
 impl Default for ScanInfo {
@@ -46,73 +219,162 @@ impl Default for ScanInfo {
             scan_type: ScanType::Reflectivity,
             step_size: TimeDelta::new(8, 0).unwrap(),
             visible_window: TimeDelta::new(8, 0).unwrap(),
+            short_window: false,
             time_ratio: 1.0,
             paused: true,
+            reverse: false,
+            dwell_seconds: 1.0,
             loaded_scans: 0,
+            render_mode: RenderMode::Instanced,
+            interpolate: false,
+            loop_range: None,
+            thresholds: Thresholds::default(),
+            hidden_scans: HashSet::new(),
+            status: String::new(),
         }
     }
 }
 
+impl ScanInfo {
+    /// How far back from `time` sweeps stay visible.
+    pub fn window(&self) -> TimeDelta {
+        if self.short_window {
+            TimeDelta::new(0, 177_777_777).unwrap()
+        } else {
+            self.visible_window
+        }
+    }
+
+    /// The HUD lines: time and playback, filter, and moment.
+    pub fn summary(&self) -> Option<String> {
+        let time = self.time?;
+        let playback = if self.paused {
+            String::from("paused")
+        } else {
+            format!("{}{}x{}", if self.reverse { "-" } else { "" }, self.time_ratio, if self.loop_range.is_some() { ", looping" } else { "" })
+        };
+        Some(format!(
+            "Time: {} ({})\nFilter: {}\nScan Type: {:?} ({:?}{})",
+            time, playback, self.filter, self.scan_type, self.render_mode, if self.interpolate { ", interpolated" } else { "" },
+        ))
+    }
+}
+
 #[derive(Resource)]
 pub struct InfoChanged(bool);
 
 pub fn move_time(
     time: Res<Time>,
+    mut dwelling: Local<f32>,
     mut info: ResMut<ScanInfo>,
 ) {
     if info.paused {
         return;
     }
 
-    let delta = time.delta_seconds() * info.time_ratio;
-    let delta = TimeDelta::new(delta.round() as i64, 0).unwrap();
+    let first = info.loop_range.map(|(start, end)| if info.reverse { end } else { start });
+    if *dwelling > 0.0 {
+        *dwelling -= time.delta_seconds();
+        if *dwelling <= 0.0 && first.is_some() {
+            info.time = first;
+        }
+        return;
+    }
+
+    let direction = if info.reverse { -1.0 } else { 1.0 };
+    let delta = time.delta_seconds_f64() * info.time_ratio as f64 * direction;
+    let delta = TimeDelta::nanoseconds((delta * 1e9).round() as i64);
 
     info.time.as_mut().map(|time| time.add_assign(delta));
+
+    // running off the end of the loop holds its last frame, starting outside it jumps to its first
+    if let (Some(time), Some((start, end))) = (info.time, info.loop_range) {
+        if time < start || time > end {
+            let overshot = if info.reverse { time < start } else { time > end };
+            if overshot {
+                info.time = Some(if info.reverse { start } else { end });
+                *dwelling = info.dwell_seconds.max(f32::EPSILON);
+            } else {
+                info.time = first;
+            }
+        }
+    }
 }
 
 pub fn keyboard_input(
     mut change_info: ResMut<InfoChanged>,
-    keys: Res<ButtonInput<KeyCode>>,
+    actions: Actions,
     mut info: ResMut<ScanInfo>,
 ) {
-    if keys.any_just_pressed([KeyCode::KeyV]) {
-        info.scan_type = ScanType::Velocity
+    let moments = [
+        (Action::Reflectivity, ScanType::Reflectivity),
+        (Action::Velocity, ScanType::Velocity),
+        (Action::StormRelativeVelocity, ScanType::StormRelativeVelocity),
+        (Action::AzimuthalShear, ScanType::AzimuthalShear),
+        (Action::Divergence, ScanType::Divergence),
+        (Action::HydrometeorClass, ScanType::HydrometeorClass),
+    ];
+    for (action, scan_type) in moments {
+        if actions.just_pressed(action) {
+            info.scan_type = scan_type
+        }
+    }
+
+    if actions.just_pressed(Action::NextMoment) {
+        info.scan_type = info.scan_type.cycle(false);
     }
 
-    if keys.any_just_pressed([KeyCode::KeyR]) {
-        info.scan_type = ScanType::Reflectivity
+    if actions.just_pressed(Action::PreviousMoment) {
+        info.scan_type = info.scan_type.cycle(true);
     }
 
-    if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
-        info.visible_window = TimeDelta::new(0, 177_777_777).unwrap();
-    } else {
-        info.visible_window = TimeDelta::new(8, 0).unwrap();
+    let short_window = actions.pressed(Action::ShortWindow);
+    if info.short_window != short_window {
+        info.short_window = short_window;
     }
-    let window = info.visible_window;
+    let step = if info.short_window { info.window() } else { info.step_size };
 
-    if keys.any_just_pressed([KeyCode::ArrowLeft]) {
+    if actions.just_pressed(Action::WindowBack) {
         if let Some(time) = info.time.as_mut() {
-            *time -= window;
+            *time -= step;
         }
     }
 
-    if keys.any_just_pressed([KeyCode::ArrowRight]) {
+    if actions.just_pressed(Action::WindowForward) {
         if let Some(time) = info.time.as_mut() {
-            *time += window;
+            *time += step;
         }
     }
 
-    if keys.any_just_pressed([KeyCode::Space]) {
+    if actions.just_pressed(Action::TogglePause) {
         info.paused = !info.paused;
     }
 
-    if keys.any_just_pressed([KeyCode::ArrowUp]) {
+    if actions.just_pressed(Action::ToggleRenderMode) {
+        info.render_mode = match info.render_mode {
+            RenderMode::Instanced => RenderMode::Volume,
+            RenderMode::Volume | RenderMode::Plan => RenderMode::Instanced,
+        };
+    }
+
+    if actions.just_pressed(Action::TogglePlanView) {
+        info.render_mode = match info.render_mode {
+            RenderMode::Plan => RenderMode::Instanced,
+            _ => RenderMode::Plan,
+        };
+    }
+
+    if actions.just_pressed(Action::ToggleInterpolation) {
+        info.interpolate = !info.interpolate;
+    }
+
+    if actions.just_pressed(Action::RaiseFilter) {
         info.filter += 1.0;
         info.filter = info.filter.min(40.0);
         change_info.0 = true;
     }
 
-    if keys.any_just_pressed([KeyCode::ArrowDown]) {
+    if actions.just_pressed(Action::LowerFilter) {
         info.filter -= 1.0;
         info.filter = info.filter.max(0.0);
         change_info.0 = true;
@@ -131,17 +393,22 @@ pub fn update_filter_system(
     }
 }
 
+/// Show the sweeps in the window for every panel's moment. Interpolation stands in for those of the first.
 pub fn visible_scans(
     info: Res<ScanInfo>,
+    layout: Res<ViewLayout>,
     mut query: Query<(&ScanMetadata, &ScanType, &mut Visibility)>,
 ){
     let Some(time) = info.time else {
         return;
     };
 
+    let moments = layout.moments(&info);
     for (scan, scan_type, mut visibillity) in query.iter_mut() {
-        let window_start = time - info.visible_window;
-        if scan.end_time > window_start && scan.end_time <= time && *scan_type == info.scan_type {
+        let window_start = time - info.window();
+        let shown = !info.hidden_scans.contains(&(scan.volume_start, scan.sweep_index));
+        let interpolated = info.interpolate && *scan_type == info.scan_type;
+        if info.render_mode == RenderMode::Instanced && !interpolated && shown && scan.end_time > window_start && scan.end_time <= time && moments.contains(scan_type) {
             *visibillity = Visibility::Visible
         } else {
             *visibillity = Visibility::Hidden
@@ -155,21 +422,6 @@ pub fn uniforms(
     dbg!(query.iter().len());
 }
 
-pub fn text_update_system(
-    info: Res<ScanInfo>,
-    mut query: Query<&mut Text, With<ScanIndexText>>,
-) {
-    for mut text in &mut query {
-        // Update the value of the second section
-
-        if let Some(time) = info.time.as_ref() {
-                text.sections[0].value = format!("Time: {} ({})\n", time, if info.paused { String::from("paused") } else { format!("{}x", info.time_ratio)});
-                text.sections[1].value = format!("Filter: {}\n", info.filter);
-                text.sections[2].value = format!("Scan Type: {:?}\n", info.scan_type);
-        };
-    }
-}
-
 /*
 pub fn update_filter_system(
     info: ResMut<ScanInfo>,
@@ -199,47 +451,11 @@ pub fn update_filter_system(
  */
 
 
-#[derive(Component)]
-pub struct ScanIndexText;
-
 pub fn setup_ui(
     mut commands: Commands,
 ) {
     commands.insert_resource(ScanInfo::default());
     commands.insert_resource(InfoChanged(true));
-
-    let font_size = 30.0;
-    // Text with multiple sections
-    commands.spawn((
-        // Create a TextBundle that has a Text with a list of sections.
-        TextBundle::from_sections([
-            TextSection::from_style(
-                TextStyle {
-                    font_size,
-                    ..default()
-                }
-            ),
-            TextSection::from_style(
-                TextStyle {
-                    font_size,
-                    ..default()
-                }
-            ),
-            TextSection::from_style(
-                TextStyle {
-                    font_size,
-                    ..default()
-                }
-            ),
-            TextSection::from_style(
-                TextStyle {
-                    font_size,
-                    ..default()
-                }
-            ),
-        ]),
-        ScanIndexText,
-    ));
 }
 
 #[derive(Component)]
@@ -248,10 +464,21 @@ pub struct ScanLoader {
     total_scans: usize,
 }
 
+/// Start reading the sources of the saved session, or the default ones.
 pub fn load_scans(
     mut commands: Commands,
+    mut sources: ResMut<DataSources>,
+    processing: Res<Processing>,
+    session: Res<Session>,
 ) {
-    let radar = radar::AIRRadar{};
+    if let Some(view) = session.view.as_ref().filter(|view| !view.sources.0.is_empty()) {
+        sources.0 = view.sources.0.clone();
+    }
+    let radar = radar::AIRRadar {
+        attenuation: processing.attenuation,
+        melting_layer: processing.melting_layer,
+        sources: sources.clone(),
+    };
     dbg!("Reading gates");
     let (scans, count) = radar.get_gates();
     commands.spawn(ScanLoader{rx: Arc::new(Mutex::new(scans)), total_scans: count});
@@ -263,21 +490,26 @@ pub fn scan_loaded(
     mut meshes: ResMut<Assets<Mesh>>,
     mut scan_loader: Query<&mut ScanLoader>,
     mut info: ResMut<ScanInfo>,
-    mut query: Query<&mut Text, With<ScanIndexText>>,
+    mut volumes: ResMut<Volumes>,
+    mut volume_loaded: EventWriter<VolumeLoaded>,
+    tables: Res<ColorTables>,
 ) {
     for loader in scan_loader.iter_mut() {
         if info.loaded_scans == 0 {
-            query.single_mut().sections[3].value = format!("loading scans {}/{}", info.loaded_scans, loader.total_scans);
+            info.status = format!("loading scans {}/{}", info.loaded_scans, loader.total_scans);
         }
         let Ok(scan) = loader.rx.lock().expect("WTF").try_recv() else {
             continue;
         };
 
         info.loaded_scans += 1;
+        let scan = Arc::new(scan);
+        volumes.insert(scan.clone());
         if info.loaded_scans == loader.total_scans {
-            query.single_mut().sections[3].value = String::from("all scans loaded");
+            info.status = String::from("all scans loaded");
+            volume_loaded.send_batch(volumes.0.keys().map(|start| VolumeLoaded(*start)));
         } else {
-            query.single_mut().sections[3].value = format!("loading scans {}/{} ({})", info.loaded_scans, loader.total_scans, scan.meta.name);
+            info.status = format!("loading scans {}/{} ({})", info.loaded_scans, loader.total_scans, scan.meta.name);
         }
 
         if info.time.is_none() {
@@ -285,140 +517,132 @@ pub fn scan_loaded(
         }
 
         let gate_mesh = meshes.add(Cuboid::new(1.0, 1.0, 1.0));
-        let instance = prepare_reflectivity(&mut commands, &scan);
-        commands.spawn((
-            gate_mesh.clone(),
-            SpatialBundle{
-                visibility: Visibility::Hidden,
-                transform: Transform::from_xyz(0.0, scan.meta.sweep_index as f32, 0.0),
-                ..SpatialBundle::INHERITED_IDENTITY
-            },
-            InstanceMaterialData(instance),
-            InstanceUniforms{
-                alpha_power: 5.0,
-            },
-            NoFrustumCulling,
-            ScanType::Reflectivity,
-            scan.meta.clone(),
-        ));
-
-        let instance = prepare_velocity(&mut commands, &scan);
-        commands.spawn((
-            gate_mesh.clone(),
-            SpatialBundle{
-                visibility: Visibility::Hidden,
-                ..SpatialBundle::INHERITED_IDENTITY
-            },
-            InstanceMaterialData(instance),
-            InstanceUniforms{
-                alpha_power: 5.0,
-            },
-            NoFrustumCulling,
-            ScanType::Velocity,
-            scan.meta.clone(),
-        ));
+        for scan_type in ScanType::ALL {
+            let instance = prepare_moment(&scan, scan_type, info.thresholds.get(scan_type), tables.get(scan_type));
+            if instance.is_empty() {
+                continue;
+            }
+
+            spawn_gates(&mut commands, gate_mesh.clone(), &scan.meta, scan_type, instance);
+        }
 
         drop(scan);
     }
 }
 
-/*
-fn color_vel(value: f32) -> Color {
-    let colors = [
-        ()
-    ];
-}
- */
+/// Sweeps `rebuild_moments` prepares per frame.
+const REBUILD_BATCH: usize = 4;
 
-fn color(value: f32) -> Color {
-    let colors = [
-        (Color::BLACK),
-        (Color::CYAN),
-        (Color::BLUE),
-        (Color::MIDNIGHT_BLUE),
-        (Color::DARK_GREEN),
-        (Color::GREEN),
-        (Color::YELLOW),
-        (Color::YELLOW_GREEN),
-        (Color::ORANGE),
-        (Color::ORANGE_RED),
-        (Color::RED),
-        //(Color::CRIMSON),
-        //(Color::MAROON),
-        //(Color::PINK),
-        //(Color::WHITE),
-    ];
+/// Rebuild the gates of a moment over every loaded sweep when its threshold or colour table changes, a few
+/// sweeps a frame, each replacing the old gates of its sweep. Vertically pointing sweeps aren't kept in
+/// `Volumes` and keep the gates they were loaded with.
+pub fn rebuild_moments(
+    mut commands: Commands,
+    mut built: Local<HashMap<ScanType, (f32, ColorTable)>>,
+    mut queued: Local<VecDeque<(ScanType, Arc<Scan>)>>,
+    mut gate_mesh: Local<Option<Handle<Mesh>>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    info: Res<ScanInfo>,
+    tables: Res<ColorTables>,
+    volumes: Res<Volumes>,
+    query: Query<(Entity, &ScanType, &ScanMetadata)>,
+) {
+    if info.is_changed() || tables.is_changed() {
+        for scan_type in ScanType::ALL.into_iter().filter(ScanType::per_gate) {
+            let (threshold, table) = (info.thresholds.get(scan_type), tables.get(scan_type));
+            match built.get(&scan_type) {
+                Some((old, old_table)) if *old == threshold && old_table == table => continue,
+                Some(_) => {}
+                None => {
+                    built.insert(scan_type, (threshold, table.clone()));
+                    continue;
+                }
+            }
 
-    let i = (value / 5.0).floor() as usize;
-    colors[i.min(colors.len() - 1)]
-}
+            // a rebuild still under way starts over with the new settings
+            queued.retain(|(queued_type, _)| *queued_type != scan_type);
+            queued.extend(volumes.0.values()
+                .flat_map(|volume| volume.scans.iter().chain(volume.rhis.iter()))
+                .map(|scan| (scan_type, scan.clone())));
+            built.insert(scan_type, (threshold, table.clone()));
+        }
+    }
+    if queued.is_empty() {
+        return;
+    }
 
-fn prepare_reflectivity(commands: &mut Commands, scan: &Scan) -> Vec<InstanceData> {
-    scan.gates.iter()
-        .filter_map(|gate| {
-            if gate.reflectivity < 35.0 {
-                return None;
-            }
+    let mesh = gate_mesh.get_or_insert_with(|| meshes.add(Cuboid::new(1.0, 1.0, 1.0))).clone();
+    let batch = queued.len().min(REBUILD_BATCH);
+    for (scan_type, scan) in queued.drain(..batch) {
+        let old = query.iter().filter(|(_, t, meta)| {
+            **t == scan_type && meta.sweep_mode != SweepMode::VerticalPointing
+                && meta.volume_start == scan.meta.volume_start && meta.sweep_index == scan.meta.sweep_index
+        });
+        for (entity, _, _) in old {
+            commands.entity(entity).despawn();
+        }
+        let instance = prepare_moment(&scan, scan_type, info.thresholds.get(scan_type), tables.get(scan_type));
+        if !instance.is_empty() {
+            spawn_gates(&mut commands, mesh.clone(), &scan.meta, scan_type, instance);
+        }
+    }
+}
 
-            if gate.range < 3000.0 {
-                return None;
-            }
+/// Spawn the instanced gates of one moment of a sweep, hidden until `visible_scans` shows them.
+pub fn spawn_gates(commands: &mut Commands, mesh: Handle<Mesh>, meta: &ScanMetadata, scan_type: ScanType, instance: Vec<InstanceData>) -> Entity {
+    commands.spawn((
+        mesh,
+        SpatialBundle{
+            visibility: Visibility::Hidden,
+            ..SpatialBundle::INHERITED_IDENTITY
+        },
+        InstanceMaterialData(instance),
+        InstanceUniforms{
+            alpha_power: 5.0,
+        },
+        NoFrustumCulling,
+        RenderLayers::layer(views::moment_layer(scan_type)),
+        scan_type,
+        meta.clone(),
+    )).id()
+}
 
-            let color = color(gate.reflectivity);
-            let alpha = (gate.reflectivity / 50.0).min(1.0);//scan.meta.max.reflectivity;
-            let size = Vec3::new(
-                scan.meta.angular_resolution * gate.range,
-                scan.meta.angular_resolution * gate.range,
-                scan.meta.range_resolution,
-            );
+/// A box one beam wide and one gate long, facing the radar. Using the elevation direction as up keeps
+/// RHI and vertically pointing gates from degenerating where the beam is close to vertical.
+fn gate_transform(scan: &Scan, gate: &Gate) -> Transform {
+    let size = Vec3::new(
+        scan.meta.angular_resolution.abs() * gate.range,
+        scan.meta.angular_resolution.abs() * gate.range,
+        scan.meta.range_resolution,
+    );
 
-            let t = Transform::from_translation(gate.as_cart()).looking_at(Vec3::ZERO, Vec3::Y).with_scale(size);
+    Transform::from_translation(gate.as_cart()).looking_at(Vec3::ZERO, gate.up()).with_scale(size)
+}
 
-            Some(InstanceData{
-                scale: gate.range * 0.004,
-                position: gate.as_cart(),
-                color: color.with_a(alpha).as_linear_rgba_f32(),
-                transform: t.compute_matrix().to_cols_array(),
-                alpha_pow: 0.0,
-            })
-        })
-        .collect()
+/// Colour any moment through its colour table, drawing the gates the moment considers visible.
+fn prepare_moment(scan: &Scan, scan_type: ScanType, threshold: f32, table: &ColorTable) -> Vec<InstanceData> {
+    prepare_values(scan, table, |gate| scan_type.visible(gate, threshold).then(|| scan_type.value(gate)))
 }
 
-fn prepare_velocity(commands: &mut Commands, scan: &Scan) -> Vec<InstanceData> {
+/// Colour every gate `value` returns a value for through `table`.
+pub fn prepare_values(scan: &Scan, table: &ColorTable, value: impl Fn(&Gate) -> Option<f32>) -> Vec<InstanceData> {
     scan.gates.iter()
         .filter_map(|gate| {
-            if gate.doppler_velocity.abs() < 20.0 {
-                return None;
-            }
+            let value = value(gate)?;
 
             if gate.range < 3000.0 {
                 return None;
             }
 
-            let max_speed = scan.meta.max.doppler_velocity.max(scan.meta.min.doppler_velocity.abs());
-            let alpha = gate.doppler_velocity.abs() / max_speed;
-            let color = if gate.doppler_velocity < 0.0 {
-                Color::rgba(alpha, 0.0, 0.0, alpha)
-            } else {
-                Color::rgba(0.0, alpha, 0.0, alpha)
-            };
-
-            let size = Vec3::new(
-                scan.meta.angular_resolution * gate.range,
-                scan.meta.angular_resolution * gate.range,
-                scan.meta.range_resolution,
-            );
-
-            let t = Transform::from_translation(gate.as_cart()).looking_at(Vec3::ZERO, Vec3::Y).with_scale(size);
+            let t = gate_transform(scan, gate);
 
             Some(InstanceData{
                 scale: gate.range * 0.004,
                 position: gate.as_cart(),
-                color: color.as_linear_rgba_f32(),
+                color: table.color(value).as_linear_rgba_f32(),
                 transform: t.compute_matrix().to_cols_array(),
                 alpha_pow: 0.0,
             })
         })
         .collect()
-}
+}
\ No newline at end of file
@@ -0,0 +1,210 @@
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::window::PrimaryWindow;
+use chrono::{DateTime, Utc};
+use crate::colortable::ColorTables;
+use crate::grid;
+use crate::input::{Action, Actions};
+use crate::panel::{self, Canvas};
+use crate::scan::{ScanInfo, ScanType};
+use crate::srv::StormMotionAt;
+use crate::volume::{Volume, Volumes};
+
+const PANEL_WIDTH: u32 = 400;
+const PANEL_HEIGHT: u32 = 200;
+
+/// Endpoints of the vertical cross-section on the ground plane, picked with shift + left click.
+#[derive(Resource, Debug, Clone)]
+pub struct CrossSection {
+    pub start: Option<Vec3>,
+    pub end: Option<Vec3>,
+    pub max_height: f32,
+}
+
+impl Default for CrossSection {
+    fn default() -> Self {
+        Self {
+            start: None,
+            end: None,
+            max_height: 15000.0,
+        }
+    }
+}
+
+impl CrossSection {
+    pub fn points(&self) -> Option<(Vec3, Vec3)> {
+        Some((self.start?, self.end?))
+    }
+}
+
+/// Sample `scan_type` on a `columns` by `rows` vertical slice from `start` to `end`, rows from the top down.
+/// Storm-relative velocity is taken against `storm`.
+pub fn slice(volume: &Volume, scan_type: ScanType, storm: Vec3, (start, end): (Vec3, Vec3), max_height: f32, columns: u32, rows: u32) -> Vec<f32> {
+    let sweeps = grid::sweeps(volume);
+    let mut data = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        let height = (1.0 - (row as f32 + 0.5) / rows as f32) * max_height;
+        for column in 0..columns {
+            let ground = start.lerp(end, (column as f32 + 0.5) / columns as f32);
+            data.push(grid::sample_sweeps(&sweeps, Vec3::new(ground.x, height, ground.z), scan_type, storm));
+        }
+    }
+    data
+}
+
+/// The point on the ground plane under the cursor, when it is over this camera's viewport.
+pub fn cursor_on_ground(window: &Window, camera: &Camera, transform: &GlobalTransform) -> Option<Vec3> {
+    let viewport = camera.logical_viewport_rect()?;
+    let cursor = window.cursor_position().filter(|cursor| viewport.contains(*cursor))?;
+    let ray = camera.viewport_to_world(transform, cursor - viewport.min)?;
+    let distance = ray.intersect_plane(Vec3::ZERO, Plane3d::new(Vec3::Y))?;
+    Some(ray.get_point(distance))
+}
+
+pub fn pick_points(
+    mut section: ResMut<CrossSection>,
+    actions: Actions,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+) {
+    if actions.just_pressed(Action::ClearPicks) {
+        section.start = None;
+        section.end = None;
+        return;
+    }
+    if !actions.just_pressed(Action::PickSectionPoint) {
+        return;
+    }
+
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let Some(point) = cameras.iter().find_map(|(camera, transform)| cursor_on_ground(window, camera, transform)) else {
+        return;
+    };
+
+    match (section.start, section.end) {
+        (Some(_), None) => section.end = Some(point),
+        _ => {
+            section.start = Some(point);
+            section.end = None;
+        }
+    }
+}
+
+pub fn draw_points(
+    mut gizmos: Gizmos,
+    section: Res<CrossSection>,
+) {
+    for point in [section.start, section.end].into_iter().flatten() {
+        gizmos.circle(point, Direction3d::Y, 500.0, Color::WHITE);
+    }
+    if let Some((start, end)) = section.points() {
+        gizmos.line(start, end, Color::WHITE);
+    }
+}
+
+#[derive(Component)]
+pub struct SectionPanel(Handle<Image>);
+
+#[derive(Component)]
+pub struct SlicePlane(Handle<Mesh>);
+
+pub fn setup_cross_section(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    let (entity, image) = panel::spawn_panel(&mut commands, &mut images, &canvas, "Cross-section", Style {
+        right: Val::Px(10.0),
+        bottom: Val::Px(280.0),
+        ..default()
+    });
+    commands.entity(entity).insert(SectionPanel(image));
+
+    let mesh = meshes.add(plane_mesh(Vec3::ZERO, Vec3::X, 1.0));
+    commands.spawn((
+        PbrBundle {
+            mesh: mesh.clone(),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgba(1.0, 1.0, 1.0, 0.15),
+                alpha_mode: AlphaMode::Blend,
+                unlit: true,
+                cull_mode: None,
+                double_sided: true,
+                ..default()
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        SlicePlane(mesh),
+    ));
+}
+
+/// Re-slice the current volume when the endpoints, the volume or the moment change.
+pub fn update_cross_section(
+    mut shown: Local<Option<(Vec3, Vec3, DateTime<Utc>, ScanType, Vec3)>>,
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut panels: Query<(&SectionPanel, &mut Visibility), Without<SlicePlane>>,
+    mut planes: Query<(&SlicePlane, &mut Visibility), Without<SectionPanel>>,
+    info: Res<ScanInfo>,
+    section: Res<CrossSection>,
+    volumes: Res<Volumes>,
+    tables: Res<ColorTables>,
+    storm_motion: StormMotionAt,
+) {
+    let (Ok((panel, mut panel_visibility)), Ok((plane, mut plane_visibility))) = (panels.get_single_mut(), planes.get_single_mut()) else {
+        return;
+    };
+    let current = info.time.and_then(|time| volumes.0.range(..=time).next_back());
+    let (Some((start, end)), Some((&volume_start, volume))) = (section.points(), current) else {
+        *panel_visibility = Visibility::Hidden;
+        *plane_visibility = Visibility::Hidden;
+        *shown = None;
+        return;
+    };
+    if *panel_visibility != Visibility::Inherited {
+        *panel_visibility = Visibility::Inherited;
+        *plane_visibility = Visibility::Visible;
+    }
+
+    // only storm-relative velocity moves with the storm motion
+    let storm = if info.scan_type.per_gate() { Vec3::ZERO } else { storm_motion.at(volume_start) };
+    let key = (start, end, volume_start, info.scan_type, storm);
+    if *shown == Some(key) && !tables.is_changed() {
+        return;
+    }
+    *shown = Some(key);
+
+    let table = tables.get(info.scan_type);
+    let data = slice(volume, info.scan_type, storm, (start, end), section.max_height, PANEL_WIDTH, PANEL_HEIGHT);
+    let mut canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    canvas.clear(Color::rgba(0.0, 0.0, 0.0, 0.0));
+    for (i, value) in data.iter().enumerate() {
+        if !value.is_nan() {
+            canvas.pixel(i as i32 % PANEL_WIDTH as i32, i as i32 / PANEL_WIDTH as i32, table.color(*value).with_a(1.0));
+        }
+    }
+    for km in 1..(section.max_height / 1000.0) as u32 {
+        let y = (1.0 - km as f32 * 1000.0 / section.max_height) * PANEL_HEIGHT as f32;
+        canvas.line(Vec2::new(0.0, y), Vec2::new(PANEL_WIDTH as f32, y), Color::rgba(1.0, 1.0, 1.0, 0.2));
+    }
+
+    if let Some(image) = images.get_mut(&panel.0) {
+        canvas.write(image);
+    }
+    meshes.insert(&plane.0, plane_mesh(start, end, section.max_height));
+}
+
+fn plane_mesh(start: Vec3, end: Vec3, height: f32) -> Mesh {
+    let up = Vec3::Y * height;
+    let normal = (end - start).cross(Vec3::Y).normalize_or_zero();
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::RENDER_WORLD)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vec![start.to_array(), end.to_array(), (end + up).to_array(), (start + up).to_array()])
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![normal.to_array(); 4])
+        .with_inserted_indices(Indices::U32(vec![0, 1, 2, 0, 2, 3]))
+}
@@ -0,0 +1,341 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_panorbit_camera::PanOrbitCamera;
+use serde::{Deserialize, Serialize};
+use crate::camera::{Bookmark, CameraPose, Keyframe};
+use crate::cells::CellSettings;
+use crate::clip::Clipping;
+use crate::colortable::ColorTables;
+use crate::controls::ControlPanel;
+use crate::input::{Action, Actions};
+use crate::isosurface::IsosurfaceSettings;
+use crate::nowcast::NowcastSettings;
+use crate::plan::PlanSettings;
+use crate::qvp::QvpSettings;
+use crate::radar::DataSources;
+use crate::rain::{Accumulation, RainSettings, ZrRelation};
+use crate::rotation::RotationSettings;
+use crate::scale::SceneScale;
+use crate::scan::{RenderMode, ScanInfo};
+use crate::section::CrossSection;
+use crate::srv::{MotionSource, StormMotion};
+use crate::stats::StatsSettings;
+use crate::vad::VadSettings;
+use crate::views::{ViewLayout, ViewPanel};
+
+pub const SESSION_PATH: &str = "session.ron";
+
+/// Viewer state kept between runs in `SESSION_PATH`.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub bookmarks: Vec<Bookmark>,
+    /// Keyframes played back by `Action::PlayCameraPath`.
+    pub camera_path: Vec<Keyframe>,
+    /// What was on screen when the session was last saved, restored at startup and on loading it.
+    pub view: Option<ViewState>,
+}
+
+/// Everything needed to put the same view on someone else's screen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewState {
+    pub sources: DataSources,
+    pub scan: ScanInfo,
+    /// Camera of each panel, in reading order.
+    pub cameras: Vec<CameraPose>,
+    pub overlays: Overlays,
+    pub color_tables: ColorTables,
+    pub layout: ViewLayout,
+    pub controls: bool,
+    pub exaggeration: f32,
+}
+
+/// The overlays switched on and the points picked for them. Their tuning stays with the viewer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Overlays {
+    pub isosurfaces: bool,
+    pub rotation: bool,
+    pub cells: bool,
+    pub wind_profile: bool,
+    pub qvp: bool,
+    pub nowcast: bool,
+    pub statistics: bool,
+    pub stats_region: (Option<Vec3>, Option<Vec3>),
+    pub section: (Option<Vec3>, Option<Vec3>),
+    pub accumulation: Accumulation,
+    pub zr_relation: ZrRelation,
+    pub storm_motion: MotionSource,
+    pub manual_motion: Vec3,
+    pub clipping: Clipping,
+    pub plan_sweep: usize,
+}
+
+impl Session {
+    /// The session at `path`, or an empty one when there is none or it can't be read.
+    pub fn load(path: &str) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|error| {
+                warn!("ignoring {path}: {error}");
+                Session::default()
+            }),
+            Err(_) => Session::default(),
+        }
+    }
+
+    /// The session at `path`, failing rather than starting afresh.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+        ron::from_str(&text).map_err(|error| error.to_string())
+    }
+
+    pub fn save(&self, path: &str) {
+        let text = match ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Ok(text) => text,
+            Err(error) => return warn!("couldn't write {path}: {error}"),
+        };
+        if let Err(error) = std::fs::write(path, text) {
+            warn!("couldn't write {path}: {error}");
+        }
+    }
+}
+
+/// Save the view with the bookmarks and camera path into the session file at the path, or put the view
+/// saved there on screen. Loading keeps the viewer's own bookmarks and camera path.
+#[derive(Event, Debug, Clone)]
+pub enum SessionRequest {
+    Save(String),
+    Load(String),
+}
+
+#[derive(SystemParam)]
+pub struct OverlayParams<'w> {
+    isosurfaces: ResMut<'w, IsosurfaceSettings>,
+    rotation: ResMut<'w, RotationSettings>,
+    cells: ResMut<'w, CellSettings>,
+    vad: ResMut<'w, VadSettings>,
+    qvp: ResMut<'w, QvpSettings>,
+    nowcast: ResMut<'w, NowcastSettings>,
+    stats: ResMut<'w, StatsSettings>,
+    section: ResMut<'w, CrossSection>,
+    rain: ResMut<'w, RainSettings>,
+    motion: ResMut<'w, StormMotion>,
+    clipping: ResMut<'w, Clipping>,
+    plan: ResMut<'w, PlanSettings>,
+}
+
+/// The resources a `ViewState` is taken from and put back into.
+#[derive(SystemParam)]
+pub struct ViewerState<'w> {
+    sources: Res<'w, DataSources>,
+    info: ResMut<'w, ScanInfo>,
+    tables: ResMut<'w, ColorTables>,
+    layout: ResMut<'w, ViewLayout>,
+    panel: ResMut<'w, ControlPanel>,
+    scale: ResMut<'w, SceneScale>,
+    overlays: OverlayParams<'w>,
+}
+
+/// Set a field of a resource, marking it changed only when the value differs so nothing is rebuilt
+/// for a value it already had.
+fn assign<R: Resource, T: PartialEq>(resource: &mut ResMut<R>, field: impl FnOnce(&mut R) -> &mut T, value: T) {
+    let slot = field(resource.bypass_change_detection());
+    if *slot != value {
+        *slot = value;
+        resource.set_changed();
+    }
+}
+
+impl ViewerState<'_> {
+    pub fn capture(&self, cameras: Vec<CameraPose>) -> ViewState {
+        let o = &self.overlays;
+        ViewState {
+            sources: self.sources.clone(),
+            scan: self.info.clone(),
+            cameras,
+            overlays: Overlays {
+                isosurfaces: o.isosurfaces.enabled,
+                rotation: o.rotation.enabled,
+                cells: o.cells.enabled,
+                wind_profile: o.vad.enabled,
+                qvp: o.qvp.enabled,
+                nowcast: o.nowcast.enabled,
+                statistics: o.stats.enabled,
+                stats_region: o.stats.region,
+                section: (o.section.start, o.section.end),
+                accumulation: o.rain.accumulation,
+                zr_relation: o.rain.relation,
+                storm_motion: o.motion.source,
+                manual_motion: o.motion.manual,
+                clipping: o.clipping.clone(),
+                plan_sweep: o.plan.sweep_index,
+            },
+            color_tables: self.tables.clone(),
+            layout: self.layout.clone(),
+            controls: self.panel.visible,
+            exaggeration: self.scale.exaggeration,
+        }
+    }
+
+    /// Put everything but the cameras back. Loading progress and the sweeps read so far are kept, as
+    /// sources can only be read at startup.
+    pub fn apply(&mut self, view: ViewState) {
+        let scan = ScanInfo {
+            short_window: self.info.short_window,
+            loaded_scans: self.info.loaded_scans,
+            status: self.info.status.clone(),
+            ..view.scan
+        };
+        self.info.set_if_neq(scan);
+        self.tables.set_if_neq(view.color_tables);
+        self.layout.set_if_neq(view.layout);
+        assign(&mut self.panel, |p| &mut p.visible, view.controls);
+        assign(&mut self.scale, |s| &mut s.exaggeration, view.exaggeration);
+
+        let (saved, o) = (view.overlays, &mut self.overlays);
+        assign(&mut o.isosurfaces, |s| &mut s.enabled, saved.isosurfaces);
+        assign(&mut o.rotation, |s| &mut s.enabled, saved.rotation);
+        assign(&mut o.cells, |s| &mut s.enabled, saved.cells);
+        assign(&mut o.vad, |s| &mut s.enabled, saved.wind_profile);
+        assign(&mut o.qvp, |s| &mut s.enabled, saved.qvp);
+        assign(&mut o.nowcast, |s| &mut s.enabled, saved.nowcast);
+        assign(&mut o.stats, |s| &mut s.enabled, saved.statistics);
+        assign(&mut o.stats, |s| &mut s.region, saved.stats_region);
+        assign(&mut o.section, |s| &mut s.start, saved.section.0);
+        assign(&mut o.section, |s| &mut s.end, saved.section.1);
+        assign(&mut o.rain, |s| &mut s.accumulation, saved.accumulation);
+        assign(&mut o.rain, |s| &mut s.relation, saved.zr_relation);
+        assign(&mut o.motion, |s| &mut s.source, saved.storm_motion);
+        assign(&mut o.motion, |s| &mut s.manual, saved.manual_motion);
+        o.clipping.set_if_neq(saved.clipping);
+        assign(&mut o.plan, |s| &mut s.sweep_index, saved.plan_sweep);
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut requests: EventWriter<SessionRequest>,
+) {
+    if actions.just_pressed(Action::SaveSession) {
+        requests.send(SessionRequest::Save(SESSION_PATH.to_string()));
+    }
+    if actions.just_pressed(Action::LoadSession) {
+        requests.send(SessionRequest::Load(SESSION_PATH.to_string()));
+    }
+}
+
+/// Restore the view of the session the viewer started with, then save and load on request. Panel
+/// cameras that don't exist yet are posed once the layout has spawned them. Neither request counts as an
+/// edit, so `save_session` leaves `SESSION_PATH` alone.
+pub fn handle_session_requests(
+    mut started: Local<bool>,
+    mut poses: Local<Vec<Option<CameraPose>>>,
+    mut requests: EventReader<SessionRequest>,
+    mut session: ResMut<Session>,
+    mut viewer: ViewerState,
+    mut cameras: Query<(&ViewPanel, &mut PanOrbitCamera)>,
+) {
+    let mut restore = None;
+    if !*started {
+        *started = true;
+        restore = session.view.clone();
+    }
+    for request in requests.read() {
+        match request {
+            SessionRequest::Save(path) => {
+                let mut current: Vec<_> = cameras.iter().map(|(panel, camera)| (panel.0, CameraPose::of(camera))).collect();
+                current.sort_by_key(|(panel, _)| *panel);
+                let saved = Session {
+                    view: Some(viewer.capture(current.into_iter().map(|(_, pose)| pose).collect())),
+                    ..session.clone()
+                };
+                saved.save(path);
+                // the view restored at startup only follows saves into the session kept between runs
+                if path == SESSION_PATH {
+                    session.bypass_change_detection().view = saved.view;
+                }
+            }
+            SessionRequest::Load(path) => match Session::read(path) {
+                Ok(loaded) => restore = loaded.view,
+                Err(error) => warn!("couldn't read {path}: {error}"),
+            },
+        }
+    }
+
+    if let Some(view) = restore {
+        if view.sources != *viewer.sources {
+            let status = format!("restart to read {}", view.sources.0.join(", "));
+            warn!("{status}");
+            viewer.info.status = status;
+        }
+        *poses = view.cameras.iter().copied().map(Some).collect();
+        viewer.apply(view);
+    }
+    for (panel, mut camera) in cameras.iter_mut() {
+        if let Some(pose) = poses.get_mut(panel.0).and_then(Option::take) {
+            pose.apply(&mut camera);
+        }
+    }
+    // plan view has no orbit cameras and the layout won't spawn panels past its own, so those poses are
+    // dropped rather than applied whenever such a camera next turns up
+    let panels = if viewer.info.render_mode == RenderMode::Plan { 0 } else { viewer.layout.moments(&viewer.info).len() };
+    poses.truncate(panels);
+}
+
+/// Write the session back whenever it is edited.
+pub fn save_session(
+    session: Res<Session>,
+) {
+    if session.is_changed() && !session.is_added() {
+        session.save(SESSION_PATH);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::math::Vec3;
+    use crate::camera::CameraPose;
+    use crate::clip::Clipping;
+    use crate::colortable::ColorTables;
+    use crate::radar::DataSources;
+    use crate::rain::{Accumulation, ZrRelation};
+    use crate::scan::{ScanInfo, ScanType};
+    use crate::session::{Overlays, Session, ViewState};
+    use crate::srv::MotionSource;
+    use crate::views::ViewLayout;
+
+    #[test]
+    fn test_view_state_survives_a_round_trip() {
+        let mut scan = ScanInfo { scan_type: ScanType::Velocity, filter: 12.5, ..Default::default() };
+        scan.visible_window = chrono::TimeDelta::milliseconds(90_500);
+        scan.time = Some(chrono::DateTime::from_timestamp(1_370_041_916, 0).unwrap());
+        let view = ViewState {
+            sources: DataSources(vec![String::from("case/*.nc")]),
+            scan,
+            cameras: vec![CameraPose { focus: Vec3::new(1.0, 0.0, 2.0), yaw: 0.5, pitch: 0.7, radius: 90_000.0 }],
+            overlays: Overlays {
+                isosurfaces: true,
+                rotation: false,
+                cells: true,
+                wind_profile: false,
+                qvp: false,
+                nowcast: true,
+                statistics: false,
+                stats_region: (Some(Vec3::X), None),
+                section: (Some(Vec3::Z), Some(Vec3::new(5.0, 0.0, 5.0))),
+                accumulation: Accumulation::StormTotal,
+                zr_relation: ZrRelation::Tropical,
+                storm_motion: MotionSource::Manual,
+                manual_motion: Vec3::new(10.0, 0.0, -3.0),
+                clipping: Clipping { enabled: true, ..Default::default() },
+                plan_sweep: 2,
+            },
+            color_tables: ColorTables::default(),
+            layout: ViewLayout { panels: 4, ..Default::default() },
+            controls: false,
+            exaggeration: 2.5,
+        };
+        let session = Session { view: Some(view), ..Default::default() };
+        let text = ron::ser::to_string_pretty(&session, ron::ser::PrettyConfig::default()).unwrap();
+        assert_eq!(ron::from_str::<Session>(&text).unwrap(), session);
+    }
+}
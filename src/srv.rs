@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::cells::StormCells;
+use crate::colortable::ColorTables;
+use crate::input::{Action, Actions};
+use crate::instance::InstanceMaterialData;
+use crate::radar::Gate;
+use crate::scan::{self, ScanInfo, ScanType};
+use crate::vad::WindProfiles;
+use crate::volume::Volumes;
+
+/// Storm motions closer than this, in m/s, don't rebuild the storm-relative gates.
+pub const MOTION_TOLERANCE: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum MotionSource {
+    Manual,
+    Cells,
+    Vad,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct StormMotion {
+    pub source: MotionSource,
+    /// Motion used with `MotionSource::Manual`, as a scene vector (x north, z east) in m/s.
+    pub manual: Vec3,
+    /// Top of the layer averaged for the VAD mean wind, in m.
+    pub mean_wind_depth: f32,
+}
+
+impl Default for StormMotion {
+    fn default() -> Self {
+        Self {
+            source: MotionSource::Cells,
+            manual: Vec3::ZERO,
+            mean_wind_depth: 6000.0,
+        }
+    }
+}
+
+impl StormMotion {
+    /// The storm motion for the volume at `time`, zero when the chosen source has no estimate.
+    pub fn at(&self, time: DateTime<Utc>, cells: &StormCells, profiles: &WindProfiles) -> Vec3 {
+        let estimate = match self.source {
+            MotionSource::Manual => Some(self.manual),
+            MotionSource::Cells => cells.mean_motion(time),
+            MotionSource::Vad => profiles.at(time).and_then(|(_, profile)| {
+                let layer: Vec<_> = profile.iter().filter(|wind| wind.height <= self.mean_wind_depth).collect();
+                (!layer.is_empty()).then(|| layer.iter().map(|wind| wind.as_cart()).sum::<Vec3>() / layer.len() as f32)
+            }),
+        };
+        estimate.unwrap_or(Vec3::ZERO)
+    }
+}
+
+/// The storm motion with the estimates it is taken from, for systems that sample storm-relative velocity.
+#[derive(SystemParam)]
+pub struct StormMotionAt<'w> {
+    motion: Res<'w, StormMotion>,
+    cells: Res<'w, StormCells>,
+    profiles: Res<'w, WindProfiles>,
+}
+
+impl StormMotionAt<'_> {
+    pub fn at(&self, time: DateTime<Utc>) -> Vec3 {
+        self.motion.at(time, &self.cells, &self.profiles)
+    }
+
+    pub fn is_changed(&self) -> bool {
+        self.motion.is_changed() || self.cells.is_changed() || self.profiles.is_changed()
+    }
+}
+
+/// Doppler velocity less the component of `motion` along the beam.
+pub fn storm_relative(gate: &Gate, motion: Vec3) -> f32 {
+    let beam = Vec3::new(
+        gate.elevation.cos() * gate.azimuth.cos(),
+        gate.elevation.sin(),
+        gate.elevation.cos() * gate.azimuth.sin(),
+    );
+    gate.doppler_velocity - motion.dot(beam)
+}
+
+#[derive(Component)]
+pub struct StormRelative;
+
+/// Keep a storm-relative velocity entity for every sweep, rebuilding its gates whenever the storm motion
+/// for its volume changes.
+pub fn update_storm_relative(
+    mut commands: Commands,
+    mut applied: Local<HashMap<(DateTime<Utc>, usize), (Option<Entity>, Vec3, f32)>>,
+    mut gate_mesh: Local<Option<Handle<Mesh>>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut query: Query<&mut InstanceMaterialData, With<StormRelative>>,
+    volumes: Res<Volumes>,
+    motion: Res<StormMotion>,
+    cells: Res<StormCells>,
+    profiles: Res<WindProfiles>,
+    tables: Res<ColorTables>,
+    info: Res<ScanInfo>,
+) {
+    if !volumes.is_changed() && !motion.is_changed() && !cells.is_changed() && !profiles.is_changed() && !tables.is_changed() && !info.is_changed() {
+        return;
+    }
+
+    let table = tables.get(ScanType::StormRelativeVelocity);
+    let threshold = info.thresholds.get(ScanType::StormRelativeVelocity);
+    for (start, volume) in volumes.0.iter() {
+        let storm = motion.at(*start, &cells, &profiles);
+        for scan in volume.scans.iter() {
+            let key = (*start, scan.meta.sweep_index);
+            let entity = match applied.get(&key) {
+                Some((_, old, built)) if old.distance(storm) < MOTION_TOLERANCE && *built == threshold && !tables.is_changed() => continue,
+                Some((entity, _, _)) => *entity,
+                None => None,
+            };
+
+            let instance = scan::prepare_values(scan, table, |gate| {
+                let value = storm_relative(gate, storm);
+                (value.abs() >= threshold).then_some(value)
+            });
+            let entity = match entity {
+                Some(entity) => {
+                    if let Ok(mut data) = query.get_mut(entity) {
+                        data.0 = instance;
+                    }
+                    Some(entity)
+                }
+                None if instance.is_empty() => None,
+                None => {
+                    let mesh = gate_mesh.get_or_insert_with(|| meshes.add(Cuboid::new(1.0, 1.0, 1.0))).clone();
+                    let entity = scan::spawn_gates(&mut commands, mesh, &scan.meta, ScanType::StormRelativeVelocity, instance);
+                    commands.entity(entity).insert(StormRelative);
+                    Some(entity)
+                }
+            };
+            applied.insert(key, (entity, storm, threshold));
+        }
+    }
+}
+
+/// Show the storm motion subtracted from the current volume as an arrow from the radar, ten minutes long.
+pub fn draw_storm_motion(
+    mut gizmos: Gizmos,
+    info: Res<ScanInfo>,
+    volumes: Res<Volumes>,
+    motion: Res<StormMotion>,
+    cells: Res<StormCells>,
+    profiles: Res<WindProfiles>,
+) {
+    if info.scan_type != ScanType::StormRelativeVelocity {
+        return;
+    }
+    let Some((start, _)) = info.time.and_then(|time| volumes.0.range(..=time).next_back()) else {
+        return;
+    };
+    let storm = motion.at(*start, &cells, &profiles);
+    let base = Vec3::Y * 100.0;
+    gizmos.arrow(base, base + storm * 600.0, Color::YELLOW);
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut motion: ResMut<StormMotion>,
+) {
+    if actions.just_pressed(Action::CycleStormMotionSource) {
+        motion.source = match motion.source {
+            MotionSource::Cells => MotionSource::Vad,
+            MotionSource::Vad => MotionSource::Manual,
+            MotionSource::Manual => MotionSource::Cells,
+        };
+    }
+
+    if motion.source != MotionSource::Manual {
+        return;
+    }
+    if actions.just_pressed(Action::TurnStormMotionLeft) {
+        motion.manual = Quat::from_rotation_y(5f32.to_radians()) * motion.manual;
+    }
+    if actions.just_pressed(Action::TurnStormMotionRight) {
+        motion.manual = Quat::from_rotation_y(-5f32.to_radians()) * motion.manual;
+    }
+    if actions.just_pressed(Action::StormMotionFaster) {
+        let direction = motion.manual.try_normalize().unwrap_or(Vec3::X);
+        motion.manual += direction;
+    }
+    if actions.just_pressed(Action::StormMotionSlower) {
+        let speed = (motion.manual.length() - 1.0).max(0.0);
+        motion.manual = motion.manual.normalize_or_zero() * speed;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::math::Vec3;
+    use crate::radar::Gate;
+    use crate::srv::storm_relative;
+
+    #[test]
+    fn test_motion_along_the_beam_is_removed() {
+        let gate = |azimuth: f32, doppler_velocity: f32| Gate {
+            reflectivity: 40.0,
+            doppler_velocity,
+            azimuth: azimuth.to_radians(),
+            elevation: 0.0,
+            range: 20_000.0,
+            ..Default::default()
+        };
+        // storm moving east at 15 m/s
+        let motion = Vec3::new(0.0, 0.0, 15.0);
+        assert!((storm_relative(&gate(90.0, 20.0), motion) - 5.0).abs() < 1e-4);
+        assert!((storm_relative(&gate(270.0, -20.0), motion) + 5.0).abs() < 1e-4);
+        assert!((storm_relative(&gate(0.0, 20.0), motion) - 20.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,329 @@
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use chrono::{DateTime, Utc};
+use crate::colortable::{ColorTable, ColorTables};
+use crate::input::{Action, Actions};
+use crate::panel::{self, Canvas};
+use crate::radar::{Gate, Scan};
+use crate::scan::{ScanInfo, ScanType};
+use crate::section;
+use crate::srv::StormMotionAt;
+use crate::volume::Volumes;
+
+const PANEL_WIDTH: u32 = 400;
+const PANEL_HEIGHT: u32 = 200;
+const PERCENTILES: [f32; 4] = [10.0, 50.0, 90.0, 99.0];
+
+#[derive(Resource, Debug, Clone)]
+pub struct StatsSettings {
+    pub enabled: bool,
+    pub bins: usize,
+    pub height_step: f32,
+    pub max_height: f32,
+    /// Opposite ground corners of the region gates are taken from, picked with alt + left click.
+    /// The whole sweep is used until both are set.
+    pub region: (Option<Vec3>, Option<Vec3>),
+}
+
+impl Default for StatsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bins: 40,
+            height_step: 500.0,
+            max_height: 15000.0,
+            region: (None, None),
+        }
+    }
+}
+
+impl StatsSettings {
+    /// Whether a gate falls within the picked region.
+    pub fn contains(&self, gate: &Gate) -> bool {
+        let (Some(a), Some(b)) = self.region else {
+            return true;
+        };
+        let p = gate.as_cart();
+        p.x >= a.x.min(b.x) && p.x <= a.x.max(b.x) && p.z >= a.z.min(b.z) && p.z <= a.z.max(b.z)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    pub count: usize,
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+    /// Values at each of `PERCENTILES`.
+    pub percentiles: Vec<f32>,
+    pub above_threshold: usize,
+    /// Counts in `bins` equal bins over the requested range, with values outside the range in the end bins.
+    pub histogram: Vec<usize>,
+}
+
+/// Summarise the non-NaN `values`. Values count as above `threshold` by magnitude, like `ScanType::visible`.
+pub fn statistics(values: impl Iterator<Item = f32>, range: (f32, f32), bins: usize, threshold: f32) -> Statistics {
+    let mut values: Vec<f32> = values.filter(|value| !value.is_nan()).collect();
+    values.sort_by(f32::total_cmp);
+
+    let mut histogram = vec![0; bins];
+    for value in values.iter().filter(|_| bins > 0) {
+        let bin = ((value - range.0) / (range.1 - range.0) * bins as f32).floor();
+        histogram[(bin.max(0.0) as usize).min(bins - 1)] += 1;
+    }
+    let percentile = |p: f32| {
+        if values.is_empty() {
+            return f32::NAN;
+        }
+        values[((p / 100.0 * (values.len() - 1) as f32).round() as usize).min(values.len() - 1)]
+    };
+
+    Statistics {
+        count: values.len(),
+        mean: values.iter().sum::<f32>() / values.len() as f32,
+        min: values.first().copied().unwrap_or(f32::NAN),
+        max: values.last().copied().unwrap_or(f32::NAN),
+        percentiles: PERCENTILES.iter().map(|p| percentile(*p)).collect(),
+        above_threshold: values.iter().filter(|value| value.abs() >= threshold).count(),
+        histogram,
+    }
+}
+
+/// Contoured frequency by altitude diagram: a histogram of `scan_type` in each height layer, as a fraction
+/// of the gates in that layer. Rows run from the ground up. Storm-relative velocity is taken against `storm`.
+pub fn cfad(scans: &[&Scan], scan_type: ScanType, storm: Vec3, range: (f32, f32), settings: &StatsSettings) -> Vec<Vec<f32>> {
+    let levels = (settings.max_height / settings.height_step).ceil() as usize;
+    let mut counts = vec![vec![0.0; settings.bins]; levels];
+    for gate in scans.iter().flat_map(|scan| scan.gates.iter()).filter(|gate| settings.contains(gate)) {
+        let value = scan_type.relative_value(gate, storm);
+        let level = (gate.as_cart().y / settings.height_step) as usize;
+        if value.is_nan() || level >= levels || settings.bins == 0 {
+            continue;
+        }
+        let bin = ((value - range.0) / (range.1 - range.0) * settings.bins as f32).floor();
+        counts[level][(bin.max(0.0) as usize).min(settings.bins - 1)] += 1.0;
+    }
+    for row in counts.iter_mut() {
+        let total: f32 = row.iter().sum();
+        if total > 0.0 {
+            row.iter_mut().for_each(|count| *count /= total);
+        }
+    }
+    counts
+}
+
+/// Range of `scan_type` over `scans` from their min/max aggregates, or the colour table's when those
+/// don't hold the moment.
+fn value_range(scans: &[&Scan], scan_type: ScanType, table: &ColorTable) -> (f32, f32) {
+    let min = scans.iter().map(|scan| scan_type.value(&scan.meta.min)).filter(|v| !v.is_nan()).reduce(f32::min);
+    let max = scans.iter().map(|scan| scan_type.value(&scan.meta.max)).filter(|v| !v.is_nan()).reduce(f32::max);
+    match (min, max) {
+        (Some(min), Some(max)) if max > min => (min, max),
+        _ => table.range(),
+    }
+}
+
+pub fn pick_region(
+    mut settings: ResMut<StatsSettings>,
+    actions: Actions,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+) {
+    if actions.just_pressed(Action::ClearPicks) && settings.region != (None, None) {
+        settings.region = (None, None);
+        return;
+    }
+    if !actions.just_pressed(Action::PickStatsRegion) {
+        return;
+    }
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let Some(point) = cameras.iter().find_map(|(camera, transform)| section::cursor_on_ground(window, camera, transform)) else {
+        return;
+    };
+
+    settings.region = match settings.region {
+        (Some(a), None) => (Some(a), Some(point)),
+        _ => (Some(point), None),
+    };
+}
+
+pub fn draw_region(
+    mut gizmos: Gizmos,
+    settings: Res<StatsSettings>,
+) {
+    match settings.region {
+        (Some(a), Some(b)) => {
+            let corners = [a, Vec3::new(a.x, 0.0, b.z), b, Vec3::new(b.x, 0.0, a.z), a];
+            gizmos.linestrip(corners, Color::ORANGE);
+        }
+        (Some(a), None) => {
+            gizmos.circle(a, Direction3d::Y, 500.0, Color::ORANGE);
+        }
+        _ => {}
+    }
+}
+
+#[derive(Component)]
+pub struct StatsPanel(Handle<Image>);
+
+#[derive(Component)]
+pub struct StatsText;
+
+pub fn setup_stats_panel(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    let (entity, image) = panel::spawn_panel(&mut commands, &mut images, &canvas, "Histogram (sweep, volume) | CFAD (volume)", Style {
+        left: Val::Px(420.0),
+        top: Val::Px(10.0),
+        ..default()
+    });
+    commands.entity(entity).insert(StatsPanel(image)).with_children(|parent| {
+        parent.spawn((TextBundle::from_section("", TextStyle { font_size: 14.0, ..default() }), StatsText));
+    });
+}
+
+fn summary(label: &str, stats: &Statistics, threshold: f32) -> String {
+    let percentiles: Vec<_> = PERCENTILES.iter().zip(stats.percentiles.iter())
+        .map(|(p, value)| format!("p{p}={value:.1}"))
+        .collect();
+    format!(
+        "{label}: n={} mean={:.1} min={:.1} max={:.1}\n  {} >={threshold}: {}\n",
+        stats.count, stats.mean, stats.min, stats.max, percentiles.join(" "), stats.above_threshold,
+    )
+}
+
+/// Recompute the statistics of the current sweep and volume whenever either, the moment or the region changes.
+pub fn update_stats_panel(
+    mut shown: Local<Option<(DateTime<Utc>, String, ScanType, Vec3)>>,
+    mut images: ResMut<Assets<Image>>,
+    mut panels: Query<(&StatsPanel, &mut Visibility)>,
+    mut texts: Query<&mut Text, With<StatsText>>,
+    info: Res<ScanInfo>,
+    settings: Res<StatsSettings>,
+    volumes: Res<Volumes>,
+    tables: Res<ColorTables>,
+    storm_motion: StormMotionAt,
+) {
+    let (Ok((panel, mut visibility)), Ok(mut text)) = (panels.get_single_mut(), texts.get_single_mut()) else {
+        return;
+    };
+    let current = info.time.and_then(|time| {
+        let (start, volume) = volumes.0.range(..=time).next_back()?;
+        let scan = volume.scans.iter().filter(|scan| scan.meta.end_time <= time).last().or(volume.scans.first())?;
+        Some((*start, volume, scan))
+    });
+    let (Some((start, volume, scan)), true) = (current, settings.enabled) else {
+        *visibility = Visibility::Hidden;
+        *shown = None;
+        return;
+    };
+    if *visibility != Visibility::Inherited {
+        *visibility = Visibility::Inherited;
+    }
+
+    // only storm-relative velocity moves with the storm motion
+    let storm = if info.scan_type.per_gate() { Vec3::ZERO } else { storm_motion.at(start) };
+    let key = (start, scan.meta.name.clone(), info.scan_type, storm);
+    if shown.as_ref() == Some(&key) && !settings.is_changed() && !tables.is_changed() {
+        return;
+    }
+    *shown = Some(key);
+
+    let table = tables.get(info.scan_type);
+    let scans: Vec<_> = volume.scans.iter().map(|scan| scan.as_ref()).collect();
+    let range = value_range(&scans, info.scan_type, table);
+    let threshold = info.scan_type.threshold();
+    let values = |scan: &Scan| -> Vec<f32> {
+        scan.gates.iter().filter(|gate| settings.contains(gate)).map(|gate| info.scan_type.relative_value(gate, storm)).collect()
+    };
+    let sweep = statistics(values(scan).into_iter(), range, settings.bins, threshold);
+    let whole = statistics(scans.iter().flat_map(|scan| values(scan)), range, settings.bins, threshold);
+    text.sections[0].value = summary(&scan.meta.name, &sweep, threshold) + &summary("Volume", &whole, threshold);
+
+    let mut canvas = Canvas::new(PANEL_WIDTH, PANEL_HEIGHT);
+    canvas.clear(Color::rgba(0.0, 0.0, 0.0, 0.0));
+    let half = PANEL_WIDTH / 2;
+    let bin_width = half as f32 / settings.bins as f32;
+    let value_at = |bin: usize| range.0 + (bin as f32 + 0.5) / settings.bins as f32 * (range.1 - range.0);
+
+    // histograms on the left, each with its tallest bin at full height: the volume as bars and the
+    // current sweep as an outline over them
+    let height = |histogram: &[usize], bin: usize| {
+        let tallest = histogram.iter().copied().max().unwrap_or(0).max(1);
+        PANEL_HEIGHT as f32 * (1.0 - histogram[bin] as f32 / tallest as f32)
+    };
+    for bin in 0..whole.histogram.len() {
+        let color = table.color(value_at(bin)).with_a(1.0);
+        for x in (bin as f32 * bin_width) as i32..((bin + 1) as f32 * bin_width) as i32 {
+            canvas.line(Vec2::new(x as f32, PANEL_HEIGHT as f32), Vec2::new(x as f32, height(&whole.histogram, bin)), color);
+        }
+    }
+    for bin in 0..sweep.histogram.len() {
+        let (left, right, top) = (bin as f32 * bin_width, (bin + 1) as f32 * bin_width, height(&sweep.histogram, bin));
+        canvas.line(Vec2::new(left, top), Vec2::new(right, top), Color::WHITE);
+        if bin + 1 < sweep.histogram.len() {
+            canvas.line(Vec2::new(right, top), Vec2::new(right, height(&sweep.histogram, bin + 1)), Color::WHITE);
+        }
+    }
+
+    // CFAD on the right, frequency from dark to bright
+    let frequencies = cfad(&scans, info.scan_type, storm, range, &settings);
+    let densest = frequencies.iter().flatten().copied().fold(0.0, f32::max).max(f32::EPSILON);
+    let levels = frequencies.len();
+    for (level, row) in frequencies.iter().enumerate() {
+        let (top, bottom) = (PANEL_HEIGHT as usize * (levels - level - 1) / levels, PANEL_HEIGHT as usize * (levels - level) / levels);
+        for (bin, frequency) in row.iter().enumerate() {
+            if *frequency <= 0.0 {
+                continue;
+            }
+            let brightness = (frequency / densest).sqrt();
+            for y in top..bottom {
+                for x in (bin as f32 * bin_width) as u32..((bin + 1) as f32 * bin_width) as u32 {
+                    canvas.pixel((half + x) as i32, y as i32, Color::rgb(brightness, brightness * 0.8, 0.2));
+                }
+            }
+        }
+    }
+    canvas.line(Vec2::new(half as f32, 0.0), Vec2::new(half as f32, PANEL_HEIGHT as f32), Color::WHITE);
+
+    if let Some(image) = images.get_mut(&panel.0) {
+        canvas.write(image);
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<StatsSettings>,
+) {
+    if actions.just_pressed(Action::ToggleStatistics) {
+        settings.enabled = !settings.enabled;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stats::statistics;
+
+    #[test]
+    fn test_statistics_of_a_ramp() {
+        let values = (0..=100).map(|i| i as f32).chain([f32::NAN, f32::NAN]);
+        let stats = statistics(values, (0.0, 100.0), 10, 35.0);
+        assert_eq!(stats.count, 101);
+        assert_eq!((stats.min, stats.max, stats.mean), (0.0, 100.0, 50.0));
+        assert_eq!(stats.percentiles, vec![10.0, 50.0, 90.0, 99.0]);
+        assert_eq!(stats.above_threshold, 66);
+        // 100 lands in the last bin rather than off the end
+        assert_eq!(stats.histogram, vec![10, 10, 10, 10, 10, 10, 10, 10, 10, 11]);
+    }
+
+    #[test]
+    fn test_statistics_without_bins() {
+        let stats = statistics([1.0, 2.0, 3.0].into_iter(), (0.0, 10.0), 0, 35.0);
+        assert_eq!(stats.count, 3);
+        assert!(stats.histogram.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::BTreeSet;
+use bevy::prelude::*;
+use bevy::ui::RelativeCursorPosition;
+use bevy_panorbit_camera::PanOrbitCamera;
+use chrono::{DateTime, TimeDelta, Utc};
+use crate::panel::{Canvas, LeftInset};
+use crate::scan::ScanInfo;
+use crate::volume::{VolumeLoaded, Volumes};
+
+const TIMELINE_WIDTH: u32 = 1200;
+const TIMELINE_HEIGHT: u32 = 40;
+
+/// Volumes whose scans have all been delivered, so their ticks can be drawn as complete.
+#[derive(Resource, Default)]
+pub struct Timeline {
+    pub complete: BTreeSet<DateTime<Utc>>,
+}
+
+/// First scan start to last scan end over everything loaded.
+pub fn span(volumes: &Volumes) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = volumes.0.values().next()?.start_time;
+    let end = volumes.0.values().map(|volume| volume.end_time).max()?;
+    Some((start, end.max(start + TimeDelta::seconds(1))))
+}
+
+fn to_x(time: DateTime<Utc>, (start, end): (DateTime<Utc>, DateTime<Utc>)) -> f32 {
+    (time - start).num_milliseconds() as f32 / (end - start).num_milliseconds() as f32 * TIMELINE_WIDTH as f32
+}
+
+fn to_time(fraction: f32, (start, end): (DateTime<Utc>, DateTime<Utc>)) -> DateTime<Utc> {
+    start + TimeDelta::milliseconds(((end - start).num_milliseconds() as f32 * fraction.clamp(0.0, 1.0)) as i64)
+}
+
+#[derive(Component)]
+pub struct TimelineBar(Handle<Image>);
+
+pub fn setup_timeline(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let image = images.add(Canvas::new(TIMELINE_WIDTH, TIMELINE_HEIGHT).to_image());
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(0.0),
+                right: Val::Px(0.0),
+                bottom: Val::Px(0.0),
+                height: Val::Px(TIMELINE_HEIGHT as f32),
+                ..default()
+            },
+            background_color: Color::rgba(0.0, 0.0, 0.0, 0.6).into(),
+            ..default()
+        },
+        LeftInset(0.0),
+    )).with_children(|parent| {
+        parent.spawn((
+            ImageBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    ..default()
+                },
+                image: UiImage::new(image.clone()),
+                ..default()
+            },
+            RelativeCursorPosition::default(),
+            TimelineBar(image),
+        ));
+    });
+}
+
+pub fn update_timeline(
+    mut events: EventReader<VolumeLoaded>,
+    mut timeline: ResMut<Timeline>,
+) {
+    for VolumeLoaded(start) in events.read() {
+        timeline.complete.insert(*start);
+    }
+}
+
+/// Left click or drag on the bar to jump, right drag to select the loop range and right click to clear it.
+/// Jumping outside the loop clears it. The camera ignores the mouse while it is over the bar.
+pub fn timeline_input(
+    mut dragging: Local<Option<MouseButton>>,
+    mut selection_start: Local<Option<DateTime<Utc>>>,
+    mut info: ResMut<ScanInfo>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+    bars: Query<&RelativeCursorPosition, With<TimelineBar>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    volumes: Res<Volumes>,
+) {
+    let Ok(cursor) = bars.get_single() else {
+        return;
+    };
+    let over = cursor.mouse_over();
+    for mut camera in cameras.iter_mut() {
+        let enabled = !over && dragging.is_none();
+        if camera.enabled != enabled {
+            camera.enabled = enabled;
+        }
+    }
+    let (Some(span), Some(position)) = (span(&volumes), cursor.normalized) else {
+        return;
+    };
+    let time = to_time(position.x, span);
+
+    if over {
+        if buttons.just_pressed(MouseButton::Left) {
+            *dragging = Some(MouseButton::Left);
+        } else if buttons.just_pressed(MouseButton::Right) {
+            *dragging = Some(MouseButton::Right);
+            *selection_start = Some(time);
+        }
+    }
+
+    match *dragging {
+        Some(MouseButton::Left) if buttons.pressed(MouseButton::Left) => {
+            // playback would send the time straight back to the start of the loop
+            if info.loop_range.is_some_and(|(start, end)| time < start || time > end) {
+                info.loop_range = None;
+            }
+            info.time = Some(time);
+        }
+        Some(MouseButton::Right) if buttons.pressed(MouseButton::Right) => {
+            if let Some(start) = *selection_start {
+                if start != time {
+                    info.loop_range = Some((start.min(time), start.max(time)));
+                }
+            }
+        }
+        Some(MouseButton::Right) => {
+            // a right click that never moved clears the loop
+            if *selection_start == Some(time) {
+                info.loop_range = None;
+            }
+            *dragging = None;
+            *selection_start = None;
+        }
+        Some(_) => *dragging = None,
+        None => {}
+    }
+}
+
+/// Tick every loaded scan, coloured by sweep index with alternate volumes shaded and incomplete volumes grey,
+/// under the loop range and the current time.
+pub fn draw_timeline(
+    mut shown: Local<Option<(i32, Option<(i32, i32)>, usize, usize)>>,
+    mut images: ResMut<Assets<Image>>,
+    bars: Query<&TimelineBar>,
+    info: Res<ScanInfo>,
+    volumes: Res<Volumes>,
+    timeline: Res<Timeline>,
+) {
+    let (Ok(bar), Some(span)) = (bars.get_single(), span(&volumes)) else {
+        return;
+    };
+    let cursor = info.time.map_or(-1, |time| to_x(time, span) as i32);
+    let selection = info.loop_range.map(|(start, end)| (to_x(start, span) as i32, to_x(end, span) as i32));
+    let key = (cursor, selection, info.loaded_scans, timeline.complete.len());
+    if *shown == Some(key) {
+        return;
+    }
+    *shown = Some(key);
+
+    let mut canvas = Canvas::new(TIMELINE_WIDTH, TIMELINE_HEIGHT);
+    canvas.clear(Color::rgba(0.0, 0.0, 0.0, 0.0));
+    if let Some((start, end)) = selection {
+        for x in start..=end {
+            canvas.line(Vec2::new(x as f32, 0.0), Vec2::new(x as f32, TIMELINE_HEIGHT as f32), Color::rgba(0.3, 0.5, 1.0, 0.3));
+        }
+    }
+
+    for (i, (start, volume)) in volumes.0.iter().enumerate() {
+        let lightness = if i % 2 == 0 { 0.6 } else { 0.4 };
+        for scan in volume.scans.iter().chain(volume.rhis.iter()) {
+            let color = if timeline.complete.contains(start) {
+                Color::hsl(scan.meta.sweep_index as f32 * 30.0 % 360.0, 0.8, lightness)
+            } else {
+                Color::rgb(0.4, 0.4, 0.4)
+            };
+            let x = to_x(scan.meta.start_time, span);
+            canvas.line(Vec2::new(x, 8.0), Vec2::new(x, TIMELINE_HEIGHT as f32 - 8.0), color);
+        }
+    }
+    canvas.line(Vec2::new(cursor as f32, 0.0), Vec2::new(cursor as f32, TIMELINE_HEIGHT as f32), Color::WHITE);
+
+    if let Some(image) = images.get_mut(&bar.0) {
+        canvas.write(image);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use chrono::{TimeDelta, TimeZone, Utc};
+    use crate::timeline::{span, to_time, to_x, TIMELINE_WIDTH};
+    use crate::volume::{Volume, Volumes};
+
+    #[test]
+    fn test_span_maps_times_onto_the_bar_and_back() {
+        let at = |minutes: i64| Utc.with_ymd_and_hms(2024, 5, 6, 20, 0, 0).unwrap() + TimeDelta::minutes(minutes);
+        let volume = |start: i64, end: i64| (at(start), Volume { start_time: at(start), end_time: at(end), scans: vec![], rhis: vec![] });
+        assert!(span(&Volumes::default()).is_none());
+
+        // the last volume to end needn't be the last to start
+        let volumes = Volumes(BTreeMap::from([volume(0, 12), volume(5, 10)]));
+        let bar = span(&volumes).unwrap();
+        assert_eq!(bar, (at(0), at(12)));
+        assert_eq!(to_x(at(3), bar), TIMELINE_WIDTH as f32 / 4.0);
+        assert_eq!(to_time(0.25, bar), at(3));
+        assert_eq!((to_time(-1.0, bar), to_time(2.0, bar)), bar);
+
+        // a single instant still spans a second, so positions stay finite
+        let instant = span(&Volumes(BTreeMap::from([volume(0, 0)]))).unwrap();
+        assert_eq!(instant.1 - instant.0, TimeDelta::seconds(1));
+        assert_eq!(to_x(at(0), instant), 0.0);
+    }
+}
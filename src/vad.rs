@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use crate::input::{Action, Actions};
+use crate::panel::{self, Canvas};
+use crate::radar::Scan;
+use crate::llsd::solve3;
+use crate::scan::ScanInfo;
+use crate::volume::{Volume, VolumeLoaded, Volumes};
+
+const METRES_PER_SECOND_TO_KNOTS: f32 = 1.943_844;
+const MAST_HEIGHT_MARGIN: f32 = 500.0;
+const BARB_LENGTH: f32 = 2000.0;
+const FEATHER_LENGTH: f32 = 800.0;
+const FEATHER_SPACING: f32 = 250.0;
+const HODOGRAPH_SIZE: u32 = 256;
+/// Hodograph radius in m/s.
+const HODOGRAPH_SPEED: f32 = 40.0;
+
+#[derive(Resource, Debug, Clone)]
+pub struct VadSettings {
+    pub enabled: bool,
+    pub min_range: f32,
+    pub max_range: f32,
+    pub ring_spacing: f32,
+    /// Sweeps above this elevation see too little of the horizontal wind, in radians.
+    pub max_elevation: f32,
+    /// Fraction of rays on a ring that must have a velocity.
+    pub min_coverage: f32,
+    /// Rings fitting worse than this RMS residual are rejected, in m/s.
+    pub max_residual: f32,
+    pub layer_depth: f32,
+}
+
+impl Default for VadSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_range: 2000.0,
+            max_range: 20000.0,
+            ring_spacing: 1000.0,
+            max_elevation: 45f32.to_radians(),
+            min_coverage: 0.5,
+            max_residual: 5.0,
+            layer_depth: 250.0,
+        }
+    }
+}
+
+/// Horizontal wind at one height; `u` is towards the east and `v` towards the north, in m/s.
+#[derive(Debug, Clone, Copy)]
+pub struct WindEstimate {
+    pub height: f32,
+    pub u: f32,
+    pub v: f32,
+}
+
+impl WindEstimate {
+    pub fn speed(&self) -> f32 {
+        self.u.hypot(self.v)
+    }
+
+    /// Direction the wind blows from, in degrees clockwise from north.
+    pub fn direction(&self) -> f32 {
+        (-self.u).atan2(-self.v).to_degrees().rem_euclid(360.0)
+    }
+
+    /// The wind as a scene vector (x north, z east).
+    pub fn as_cart(&self) -> Vec3 {
+        Vec3::new(self.v, 0.0, self.u)
+    }
+}
+
+/// Fit `v_r = a + b cos(az) + c sin(az)` around one range ring of a sweep.
+pub fn fit_ring(scan: &Scan, bin: usize, settings: &VadSettings) -> Option<WindEstimate> {
+    let rays = scan.meta.ray_count;
+    let mut m = [[0.0f64; 3]; 3];
+    let mut y = [0.0f64; 3];
+    let mut samples = Vec::new();
+    for ray in 0..rays {
+        let gate = scan.gate(ray, bin);
+        if gate.doppler_velocity.is_nan() {
+            continue;
+        }
+        let row = [1.0, (gate.azimuth as f64).cos(), (gate.azimuth as f64).sin()];
+        for a in 0..3 {
+            for b in 0..3 {
+                m[a][b] += row[a] * row[b];
+            }
+            y[a] += row[a] * gate.doppler_velocity as f64;
+        }
+        samples.push((row, gate.doppler_velocity as f64));
+    }
+    if (samples.len() as f32) < settings.min_coverage * rays as f32 {
+        return None;
+    }
+
+    let coefficients = solve3(m, y)?;
+    let residual = samples.iter()
+        .map(|(row, v)| {
+            let fitted: f64 = row.iter().zip(coefficients).map(|(r, c)| r * c).sum();
+            (fitted - v).powi(2)
+        })
+        .sum::<f64>() / samples.len() as f64;
+    if residual.sqrt() as f32 > settings.max_residual {
+        return None;
+    }
+
+    let gate = scan.gate(0, bin);
+    let cos_elevation = scan.elevation().cos();
+    Some(WindEstimate {
+        height: gate.range * scan.elevation().sin(),
+        u: coefficients[2] as f32 / cos_elevation,
+        v: coefficients[1] as f32 / cos_elevation,
+    })
+}
+
+/// Fit every ring of every usable sweep and average the estimates into layers.
+pub fn profile(volume: &Volume, settings: &VadSettings) -> Vec<WindEstimate> {
+    let mut layers: BTreeMap<i32, (WindEstimate, usize)> = BTreeMap::new();
+    for scan in volume.scans.iter() {
+        if scan.elevation() > settings.max_elevation || scan.meta.bin_count < 2 {
+            continue;
+        }
+        let first = scan.gate(0, 0).range;
+        let step = (scan.gate(0, scan.meta.bin_count - 1).range - first) / (scan.meta.bin_count - 1) as f32;
+        let mut range = settings.min_range;
+        while range <= settings.max_range {
+            let bin = ((range - first) / step).round();
+            range += settings.ring_spacing;
+            if bin < 0.0 || bin as usize >= scan.meta.bin_count {
+                continue;
+            }
+            let Some(estimate) = fit_ring(scan, bin as usize, settings) else {
+                continue;
+            };
+
+            let layer = (estimate.height / settings.layer_depth).floor() as i32;
+            let (sum, count) = layers.entry(layer).or_insert((WindEstimate { height: 0.0, u: 0.0, v: 0.0 }, 0));
+            sum.u += estimate.u;
+            sum.v += estimate.v;
+            *count += 1;
+        }
+    }
+
+    layers.into_iter()
+        .map(|(layer, (sum, count))| WindEstimate {
+            height: (layer as f32 + 0.5) * settings.layer_depth,
+            u: sum.u / count as f32,
+            v: sum.v / count as f32,
+        })
+        .collect()
+}
+
+/// Wind profile for each volume, keyed by volume start.
+#[derive(Resource, Default)]
+pub struct WindProfiles(pub BTreeMap<DateTime<Utc>, Vec<WindEstimate>>);
+
+impl WindProfiles {
+    /// The latest profile at or before `time`.
+    pub fn at(&self, time: DateTime<Utc>) -> Option<(&DateTime<Utc>, &Vec<WindEstimate>)> {
+        self.0.range(..=time).next_back()
+    }
+}
+
+pub fn update_profiles(
+    mut events: EventReader<VolumeLoaded>,
+    mut profiles: ResMut<WindProfiles>,
+    volumes: Res<Volumes>,
+    settings: Res<VadSettings>,
+) {
+    let starts: Vec<_> = if settings.is_changed() && !settings.is_added() {
+        events.clear();
+        volumes.0.keys().copied().collect()
+    } else {
+        events.read().map(|event| event.0).collect()
+    };
+
+    for start in starts {
+        if let Some(volume) = volumes.0.get(&start) {
+            profiles.0.insert(start, profile(volume, &settings));
+        }
+    }
+}
+
+/// Conventional colours for hodograph segments: 0-1 km, 1-3 km, 3-6 km and above.
+fn height_color(height: f32) -> Color {
+    match height {
+        h if h < 1000.0 => Color::RED,
+        h if h < 3000.0 => Color::GREEN,
+        h if h < 6000.0 => Color::YELLOW,
+        _ => Color::CYAN,
+    }
+}
+
+/// Wind barbs on a mast above the radar for the profile at the current time.
+pub fn draw_wind_barbs(
+    mut gizmos: Gizmos,
+    info: Res<ScanInfo>,
+    settings: Res<VadSettings>,
+    profiles: Res<WindProfiles>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+    if !settings.enabled {
+        return;
+    }
+    let Some((_, profile)) = profiles.at(time) else {
+        return;
+    };
+    let Some(top) = profile.last() else {
+        return;
+    };
+
+    gizmos.line(Vec3::ZERO, Vec3::Y * (top.height + MAST_HEIGHT_MARGIN), Color::WHITE);
+    for estimate in profile.iter() {
+        draw_barb(&mut gizmos, Vec3::Y * estimate.height, estimate, height_color(estimate.height));
+    }
+}
+
+fn draw_barb(gizmos: &mut Gizmos, base: Vec3, estimate: &WindEstimate, color: Color) {
+    let knots = (estimate.speed() * METRES_PER_SECOND_TO_KNOTS / 5.0).round() as u32 * 5;
+    if knots == 0 {
+        gizmos.circle(base, Direction3d::Y, FEATHER_LENGTH / 2.0, color);
+        return;
+    }
+
+    // the shaft points into the wind, feathers on its clockwise side
+    let from = -estimate.as_cart().normalize();
+    let side = Vec3::new(from.z, 0.0, -from.x);
+    let tip = base + from * BARB_LENGTH;
+    gizmos.line(base, tip, color);
+
+    let mut remaining = knots;
+    let mut position = tip;
+    while remaining >= 50 {
+        let next = position - from * FEATHER_SPACING;
+        gizmos.linestrip([position, position + side * FEATHER_LENGTH, next], color);
+        position = next - from * FEATHER_SPACING;
+        remaining -= 50;
+    }
+    while remaining >= 10 {
+        gizmos.line(position, position + side * FEATHER_LENGTH + from * FEATHER_SPACING, color);
+        position -= from * FEATHER_SPACING;
+        remaining -= 10;
+    }
+    if remaining >= 5 {
+        if position == tip {
+            position -= from * FEATHER_SPACING;
+        }
+        gizmos.line(position, position + (side * FEATHER_LENGTH + from * FEATHER_SPACING) / 2.0, color);
+    }
+}
+
+#[derive(Component)]
+pub struct Hodograph(Handle<Image>);
+
+pub fn setup_hodograph(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let canvas = Canvas::new(HODOGRAPH_SIZE, HODOGRAPH_SIZE);
+    let (entity, image) = panel::spawn_panel(&mut commands, &mut images, &canvas, "Hodograph (10 m/s rings)", Style {
+        left: Val::Px(10.0),
+        bottom: Val::Px(50.0),
+        ..default()
+    });
+    commands.entity(entity).insert(Hodograph(image));
+}
+
+/// Redraw the hodograph when the profile at the current time changes.
+pub fn update_hodograph(
+    mut shown: Local<Option<DateTime<Utc>>>,
+    mut images: ResMut<Assets<Image>>,
+    mut query: Query<(&Hodograph, &mut Visibility)>,
+    info: Res<ScanInfo>,
+    settings: Res<VadSettings>,
+    profiles: Res<WindProfiles>,
+) {
+    let Ok((hodograph, mut visibility)) = query.get_single_mut() else {
+        return;
+    };
+    let current = info.time.and_then(|time| profiles.at(time));
+    let wanted = if settings.enabled && current.is_some() { Visibility::Inherited } else { Visibility::Hidden };
+    if *visibility != wanted {
+        *visibility = wanted;
+    }
+    let Some((start, profile)) = current else {
+        return;
+    };
+    if *shown == Some(*start) && !profiles.is_changed() {
+        return;
+    }
+    *shown = Some(*start);
+
+    let size = HODOGRAPH_SIZE as f32;
+    let centre = Vec2::splat(size / 2.0);
+    let scale = size / 2.0 / HODOGRAPH_SPEED;
+    let mut canvas = Canvas::new(HODOGRAPH_SIZE, HODOGRAPH_SIZE);
+    canvas.clear(Color::rgba(0.0, 0.0, 0.0, 0.0));
+    for speed in (10..=HODOGRAPH_SPEED as u32).step_by(10) {
+        canvas.circle(centre, speed as f32 * scale, Color::DARK_GRAY);
+    }
+    canvas.line(Vec2::new(0.0, centre.y), Vec2::new(size, centre.y), Color::GRAY);
+    canvas.line(Vec2::new(centre.x, 0.0), Vec2::new(centre.x, size), Color::GRAY);
+
+    let point = |e: &WindEstimate| centre + Vec2::new(e.u, -e.v) * scale;
+    for pair in profile.windows(2) {
+        canvas.line(point(&pair[0]), point(&pair[1]), height_color(pair[0].height));
+    }
+
+    if let Some(image) = images.get_mut(&hodograph.0) {
+        canvas.write(image);
+    }
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut settings: ResMut<VadSettings>,
+) {
+    if actions.just_pressed(Action::ToggleWindProfile) {
+        let settings = settings.bypass_change_detection();
+        settings.enabled = !settings.enabled;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use crate::radar::{Gate, Scan};
+    use crate::vad::{fit_ring, VadSettings};
+
+    #[test]
+    fn test_fit_ring_recovers_uniform_wind() {
+        // 10 m/s from the south west: u = v = 7.07
+        let (u, v, elevation) = (7.07f32, 7.07f32, 0.05f32);
+        let gates: Vec<Gate> = (0..360)
+            .map(|ray| {
+                let azimuth = (ray as f32).to_radians();
+                Gate {
+                    reflectivity: 20.0,
+                    doppler_velocity: (u * azimuth.sin() + v * azimuth.cos()) * elevation.cos(),
+                    azimuth,
+                    elevation,
+                    range: 10_000.0,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let scan = Scan::ppi(gates, 1, Utc::now());
+
+        let estimate = fit_ring(&scan, 0, &VadSettings::default()).unwrap();
+        assert!((estimate.u - u).abs() < 0.01 && (estimate.v - v).abs() < 0.01, "{:?}", estimate);
+        assert!((estimate.direction() - 225.0).abs() < 0.5, "direction {}", estimate.direction());
+    }
+}
@@ -0,0 +1,231 @@
+use bevy::prelude::*;
+use bevy::render::camera::{ClearColorConfig, Viewport};
+use bevy::render::view::RenderLayers;
+use bevy::ui::IsDefaultUiCamera;
+use bevy::window::PrimaryWindow;
+use bevy_panorbit_camera::PanOrbitCamera;
+use serde::{Deserialize, Serialize};
+use crate::camera::CameraPose;
+use crate::input::{Action, Actions};
+use crate::scale::CameraRig;
+use crate::scan::{RenderMode, ScanInfo, ScanType};
+
+/// How the window is split. The first panel always shows `ScanInfo::scan_type`; time is shared by all of them.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewLayout {
+    /// 1, 2 side by side or 4 in a 2x2 grid.
+    pub panels: usize,
+    /// Moments of the second to fourth panels.
+    pub moments: [ScanType; 3],
+    /// Moving one panel's camera moves the others with it.
+    pub link_cameras: bool,
+}
+
+impl Default for ViewLayout {
+    fn default() -> Self {
+        Self {
+            panels: 1,
+            moments: [ScanType::Velocity, ScanType::DifferentialReflectivity, ScanType::CrossCorrelation],
+            link_cameras: true,
+        }
+    }
+}
+
+impl ViewLayout {
+    /// The moment of each panel on screen. Plan view only has the one.
+    pub fn moments(&self, info: &ScanInfo) -> Vec<ScanType> {
+        let panels = if info.render_mode == RenderMode::Plan { 1 } else { self.panels };
+        std::iter::once(info.scan_type).chain(self.moments).take(panels).collect()
+    }
+}
+
+/// Camera of the panel at this index, in reading order.
+#[derive(Component)]
+pub struct ViewPanel(pub usize);
+
+#[derive(Component)]
+pub struct PanelLabel(usize);
+
+/// Layer the gates, volumes and isosurfaces of a moment are drawn on, so each panel only sees its own.
+/// Everything shared between panels stays on layer 0.
+pub fn moment_layer(scan_type: ScanType) -> u8 {
+    1 + ScanType::ALL.iter().position(|s| *s == scan_type).unwrap() as u8
+}
+
+/// Physical position and size of panel `index` of `panels` in a window `size` pixels across.
+pub fn panel_rect(index: usize, panels: usize, size: UVec2) -> (UVec2, UVec2) {
+    let (columns, rows) = match panels {
+        1 => (1, 1),
+        2 => (2, 1),
+        _ => (2, 2),
+    };
+    let cell = (size / UVec2::new(columns, rows)).max(UVec2::ONE);
+    (UVec2::new(index as u32 % columns, index as u32 / columns) * cell, cell)
+}
+
+/// A full window camera over the panels for the UI, which would otherwise be laid out inside one of them.
+pub fn setup_views(
+    mut commands: Commands,
+) {
+    commands.spawn((
+        Camera2dBundle {
+            camera: Camera {
+                order: 10,
+                clear_color: ClearColorConfig::None,
+                ..default()
+            },
+            ..default()
+        },
+        IsDefaultUiCamera,
+        RenderLayers::none(),
+    ));
+
+    for index in 0..4 {
+        commands.spawn((
+            TextBundle::from_section("", TextStyle { font_size: 18.0, ..default() })
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    ..default()
+                }),
+            PanelLabel(index),
+        )).insert(Visibility::Hidden);
+    }
+}
+
+/// Give each panel a camera with its viewport and moment, adding cameras posed like the first on the same
+/// rig and dropping those no longer needed.
+pub fn arrange_views(
+    mut commands: Commands,
+    layout: Res<ViewLayout>,
+    info: Res<ScanInfo>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mut views: Query<(Entity, &ViewPanel, &mut Camera, &mut RenderLayers)>,
+    main: Query<(&ViewPanel, &Transform, &PanOrbitCamera)>,
+    rigs: Query<Entity, With<CameraRig>>,
+    mut labels: Query<(&PanelLabel, &mut Text, &mut Style, &mut Visibility)>,
+) {
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let moments = layout.moments(&info);
+    let size = UVec2::new(window.physical_width(), window.physical_height());
+    let viewport = |index: usize| (moments.len() > 1).then(|| {
+        let (physical_position, physical_size) = panel_rect(index, moments.len(), size);
+        Viewport { physical_position, physical_size, ..default() }
+    });
+
+    let mut present = vec![false; moments.len()];
+    for (entity, panel, mut camera, mut layers) in views.iter_mut() {
+        let Some(moment) = moments.get(panel.0) else {
+            commands.entity(entity).despawn();
+            continue;
+        };
+        present[panel.0] = true;
+        let wanted = viewport(panel.0);
+        let rect = |viewport: &Option<Viewport>| viewport.as_ref().map(|v| (v.physical_position, v.physical_size));
+        if rect(&camera.viewport) != rect(&wanted) {
+            camera.viewport = wanted;
+        }
+        let wanted = RenderLayers::layer(0).with(moment_layer(*moment));
+        if *layers != wanted {
+            *layers = wanted;
+        }
+    }
+
+    let main = main.iter().find(|(panel, _, _)| panel.0 == 0);
+    if let (Some((_, transform, orbit)), Ok(rig)) = (main, rigs.get_single()) {
+        for index in (1..moments.len()).filter(|index| !present[*index]) {
+            let mut orbit = orbit.clone();
+            orbit.force_update = true;
+            commands.spawn((
+                Camera3dBundle {
+                    camera: Camera {
+                        order: index as isize,
+                        viewport: viewport(index),
+                        ..default()
+                    },
+                    transform: *transform,
+                    ..default()
+                },
+                orbit,
+                ViewPanel(index),
+                RenderLayers::layer(0),
+            )).set_parent(rig);
+        }
+    }
+
+    let scale = window.scale_factor();
+    for (label, mut text, mut style, mut visibility) in labels.iter_mut() {
+        let Some(moment) = moments.get(label.0).filter(|_| moments.len() > 1) else {
+            if *visibility != Visibility::Hidden {
+                *visibility = Visibility::Hidden;
+            }
+            continue;
+        };
+        let (position, cell) = panel_rect(label.0, moments.len(), size);
+        let (right, top) = (Val::Px(window.width() - (position.x + cell.x) as f32 / scale + 10.0), Val::Px(position.y as f32 / scale + 10.0));
+        if (style.right, style.top) != (right, top) {
+            style.right = right;
+            style.top = top;
+        }
+        let value = format!("{moment:?}");
+        if text.sections[0].value != value {
+            text.sections[0].value = value;
+        }
+        if *visibility != Visibility::Inherited {
+            *visibility = Visibility::Inherited;
+        }
+    }
+}
+
+/// While linked, whichever panel's camera was moved brings the others along.
+pub fn link_cameras(
+    mut last: Local<Option<CameraPose>>,
+    layout: Res<ViewLayout>,
+    mut cameras: Query<&mut PanOrbitCamera, With<ViewPanel>>,
+) {
+    if !layout.link_cameras {
+        *last = None;
+        return;
+    }
+    let Some(pose) = cameras.iter().map(CameraPose::of).find(|pose| Some(*pose) != *last) else {
+        return;
+    };
+    for mut camera in cameras.iter_mut() {
+        if CameraPose::of(&camera) != pose {
+            pose.aim(&mut camera);
+        }
+    }
+    *last = Some(pose);
+}
+
+pub fn keyboard_input(
+    actions: Actions,
+    mut layout: ResMut<ViewLayout>,
+) {
+    if actions.just_pressed(Action::CycleLayout) {
+        layout.panels = match layout.panels {
+            1 => 2,
+            2 => 4,
+            _ => 1,
+        };
+    }
+    if actions.just_pressed(Action::LinkCameras) {
+        layout.link_cameras = !layout.link_cameras;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bevy::math::UVec2;
+    use crate::views::panel_rect;
+
+    #[test]
+    fn test_panels_tile_the_window() {
+        let size = UVec2::new(1600, 900);
+        assert_eq!(panel_rect(0, 1, size), (UVec2::ZERO, size));
+        assert_eq!(panel_rect(1, 2, size), (UVec2::new(800, 0), UVec2::new(800, 900)));
+        assert_eq!(panel_rect(3, 4, size), (UVec2::new(800, 450), UVec2::new(800, 450)));
+        assert_eq!(panel_rect(2, 4, UVec2::ZERO).1, UVec2::ONE);
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use crate::radar::{Scan, SweepMode};
+
+/// Every sweep that shares a volume start time, ordered by sweep index. `scans` only holds sweeps that
+/// scan in azimuth, which is what the gridded products expect; RHIs are kept apart in `rhis`.
+#[derive(Clone)]
+pub struct Volume {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub scans: Vec<Arc<Scan>>,
+    pub rhis: Vec<Arc<Scan>>,
+}
+
+impl Volume {
+    /// Sweep indices of this volume in `hidden`, which is keyed like `ScanInfo::hidden_scans`.
+    pub fn hidden_sweeps(&self, hidden: &HashSet<(DateTime<Utc>, usize)>) -> Vec<usize> {
+        self.scans.iter()
+            .map(|scan| scan.meta.sweep_index)
+            .filter(|index| hidden.contains(&(self.start_time, *index)))
+            .collect()
+    }
+
+    /// This volume without the sweeps in `sweeps`.
+    pub fn without(&self, sweeps: &[usize]) -> Volume {
+        Volume {
+            scans: self.scans.iter().filter(|scan| !sweeps.contains(&scan.meta.sweep_index)).cloned().collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Loaded scans grouped into volumes, keyed by volume start time.
+#[derive(Resource, Default)]
+pub struct Volumes(pub BTreeMap<DateTime<Utc>, Volume>);
+
+impl Volumes {
+    pub fn insert(&mut self, scan: Arc<Scan>) {
+        let start = scan.meta.volume_start;
+        let volume = self.0.entry(start).or_insert_with(|| Volume {
+            start_time: start,
+            end_time: scan.meta.end_time,
+            scans: Vec::new(),
+            rhis: Vec::new(),
+        });
+
+        volume.end_time = volume.end_time.max(scan.meta.end_time);
+        let scans = match scan.meta.sweep_mode {
+            SweepMode::Rhi => &mut volume.rhis,
+            SweepMode::VerticalPointing => return,
+            _ => &mut volume.scans,
+        };
+        let index = scans.partition_point(|s| s.meta.sweep_index < scan.meta.sweep_index);
+        scans.insert(index, scan);
+    }
+}
+
+/// Sent once per volume when the loader has delivered all of its scans.
+#[derive(Event)]
+pub struct VolumeLoaded(pub DateTime<Utc>);
@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use bevy::pbr::{MaterialPipeline, MaterialPipelineKey};
+use bevy::prelude::*;
+use bevy::render::mesh::MeshVertexBufferLayout;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{AsBindGroup, CompareFunction, Extent3d, Face, RenderPipelineDescriptor, ShaderRef, ShaderType, SpecializedMeshPipelineError, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy::render::view::RenderLayers;
+use bevy::tasks::{block_on, poll_once, AsyncComputeTaskPool, Task};
+use chrono::{DateTime, Utc};
+use crate::colortable::{ColorTable, ColorTables};
+use crate::grid::{Grid, GridCache, GridSettings};
+use crate::scan::{RenderMode, ScanInfo, ScanType};
+use crate::views::{self, ViewLayout};
+use crate::volume::Volumes;
+
+const TRANSFER_SIZE: usize = 256;
+const STEPS: u32 = 256;
+
+#[derive(Debug, Clone, ShaderType)]
+pub struct VolumeSettings {
+    pub bounds_min: Vec3,
+    pub alpha_power: f32,
+    pub bounds_max: Vec3,
+    pub steps: u32,
+    /// Step length the transfer function opacity is defined for, usually one grid cell.
+    pub reference_step: f32,
+}
+
+/// Ray-marches a gridded volume uploaded as a 3-D texture. The red channel holds the moment
+/// normalised across the colour table range and the green channel holds radar coverage.
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+pub struct VolumeMaterial {
+    #[uniform(0)]
+    pub settings: VolumeSettings,
+    #[texture(1, dimension = "3d")]
+    #[sampler(2)]
+    pub volume: Handle<Image>,
+    #[texture(3)]
+    #[sampler(4)]
+    pub transfer: Handle<Image>,
+}
+
+impl Material for VolumeMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "shaders/volume.wgsl".into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Blend
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // Draw the far faces so the ray still starts when the camera is inside the box, and ignore
+        // depth so the bottom face doesn't fight with the ground.
+        descriptor.primitive.cull_mode = Some(Face::Front);
+        if let Some(depth) = descriptor.depth_stencil.as_mut() {
+            depth.depth_compare = CompareFunction::Always;
+            depth.depth_write_enabled = false;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct VolumeRender {
+    pub scan_type: ScanType,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// Sweep indices left out of the grid.
+    pub hidden: Vec<usize>,
+}
+
+/// Gridding of one moment of a volume for rendering, running on the async compute pool.
+#[derive(Component)]
+pub struct GriddingVolume {
+    scan_type: ScanType,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    hidden: Vec<usize>,
+    task: Task<Arc<Grid>>,
+}
+
+/// One transfer function texture per moment, shared by every volume of that moment, along with the
+/// value range the volume textures were normalised over.
+#[derive(Resource, Default)]
+pub struct TransferFunctions(HashMap<ScanType, (Handle<Image>, (f32, f32))>);
+
+/// Start gridding each moment on screen for the volumes in the visible window, and only in
+/// `RenderMode::Volume`. Volumes and their gridding tasks are dropped again once they leave the window.
+pub fn build_volumes(
+    mut commands: Commands,
+    mut grids: ResMut<GridCache>,
+    volumes: Res<Volumes>,
+    grid_settings: Res<GridSettings>,
+    info: Res<ScanInfo>,
+    layout: Res<ViewLayout>,
+    existing: Query<(Entity, AnyOf<(&VolumeRender, &GriddingVolume)>)>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+    let window_start = time - info.window();
+
+    // hiding or showing a sweep rebuilds its volume without or with it
+    let hidden = |start: &DateTime<Utc>| volumes.0.get(start).map(|volume| volume.hidden_sweeps(&info.hidden_scans));
+    let mut built = HashSet::new();
+    for (entity, (render, gridding)) in existing.iter() {
+        let (scan_type, start_time, end_time, built_hidden) = match (render, gridding) {
+            (Some(render), _) => (render.scan_type, render.start_time, render.end_time, &render.hidden),
+            (None, Some(gridding)) => (gridding.scan_type, gridding.start_time, gridding.end_time, &gridding.hidden),
+            (None, None) => continue,
+        };
+        let current = start_time <= time && end_time > window_start;
+        if current && hidden(&start_time).as_ref() == Some(built_hidden) {
+            built.insert((start_time, scan_type));
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
+    if info.render_mode != RenderMode::Volume {
+        return;
+    }
+
+    let pool = AsyncComputeTaskPool::get();
+    let moments: Vec<_> = layout.moments(&info).into_iter().filter(|s| !s.categorical() && s.per_gate()).collect();
+    for volume in volumes.0.range(..=time).map(|(_, volume)| volume).filter(|volume| volume.end_time > window_start) {
+        let hidden = volume.hidden_sweeps(&info.hidden_scans);
+        for scan_type in moments.iter().copied().filter(|s| !built.contains(&(volume.start_time, *s))) {
+            let cached = grids.cached(volume.start_time, scan_type, &hidden);
+            let (sweeps, grid_settings) = (volume.without(&hidden), grid_settings.clone());
+            let task = pool.spawn(async move {
+                cached.unwrap_or_else(|| Arc::new(Grid::from_volume(&sweeps, scan_type, &grid_settings)))
+            });
+            commands.spawn(GriddingVolume {
+                scan_type,
+                start_time: volume.start_time,
+                end_time: volume.end_time,
+                hidden: hidden.clone(),
+                task,
+            });
+        }
+    }
+}
+
+/// Add the volume of each finished gridding task, keeping its grid for the other gridded products.
+pub fn finish_volumes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<VolumeMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    mut grids: ResMut<GridCache>,
+    mut transfers: ResMut<TransferFunctions>,
+    tables: Res<ColorTables>,
+    info: Res<ScanInfo>,
+    mut tasks: Query<(Entity, &mut GriddingVolume)>,
+) {
+    for (entity, mut gridding) in tasks.iter_mut() {
+        let Some(grid) = block_on(poll_once(&mut gridding.task)) else {
+            continue;
+        };
+        commands.entity(entity).despawn();
+        grids.insert(gridding.start_time, gridding.scan_type, &gridding.hidden, grid.clone());
+
+        let scan_type = gridding.scan_type;
+        let table = tables.get(scan_type);
+        let (transfer, range) = transfers.0
+            .entry(scan_type)
+            .or_insert_with(|| (images.add(transfer_image(table, table.range())), table.range()))
+            .clone();
+
+        let size = grid.spacing * (grid.dims - UVec3::ONE).as_vec3();
+        commands.spawn((
+            MaterialMeshBundle {
+                mesh: meshes.add(Cuboid::from_size(size)),
+                material: materials.add(VolumeMaterial {
+                    settings: VolumeSettings {
+                        bounds_min: grid.origin,
+                        alpha_power: info.filter,
+                        bounds_max: grid.origin + size,
+                        steps: STEPS,
+                        reference_step: grid.spacing.min_element(),
+                    },
+                    volume: images.add(volume_image(&grid, range)),
+                    transfer,
+                }),
+                transform: Transform::from_translation(grid.origin + size / 2.0),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            VolumeRender {
+                scan_type,
+                start_time: gridding.start_time,
+                end_time: gridding.end_time,
+                hidden: std::mem::take(&mut gridding.hidden),
+            },
+            RenderLayers::layer(views::moment_layer(scan_type)),
+        ));
+    }
+}
+
+/// Keep the shared transfer functions in step with the active colour tables.
+pub fn update_transfer_functions(
+    tables: Res<ColorTables>,
+    transfers: Res<TransferFunctions>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    if !tables.is_changed() {
+        return;
+    }
+
+    for (scan_type, (handle, range)) in transfers.0.iter() {
+        if let Some(image) = images.get_mut(handle) {
+            *image = transfer_image(tables.get(*scan_type), *range);
+        }
+    }
+}
+
+pub fn update_alpha_power(
+    info: Res<ScanInfo>,
+    mut materials: ResMut<Assets<VolumeMaterial>>,
+) {
+    if materials.iter().all(|(_, material)| material.settings.alpha_power == info.filter) {
+        return;
+    }
+
+    for (_, material) in materials.iter_mut() {
+        material.settings.alpha_power = info.filter;
+    }
+}
+
+pub fn visible_volumes(
+    info: Res<ScanInfo>,
+    layout: Res<ViewLayout>,
+    mut query: Query<(&VolumeRender, &mut Visibility)>,
+) {
+    let Some(time) = info.time else {
+        return;
+    };
+
+    let window_start = time - info.window();
+    let moments = layout.moments(&info);
+    for (volume, mut visibility) in query.iter_mut() {
+        let visible = info.render_mode == RenderMode::Volume
+            && moments.contains(&volume.scan_type)
+            && volume.start_time <= time
+            && volume.end_time > window_start;
+        let wanted = if visible { Visibility::Visible } else { Visibility::Hidden };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
+
+fn volume_image(grid: &Grid, (min, max): (f32, f32)) -> Image {
+    let data = grid.data.iter()
+        .flat_map(|v| {
+            if v.is_nan() {
+                [0, 0]
+            } else {
+                [(((v - min) / (max - min)).clamp(0.0, 1.0) * 255.0) as u8, 255]
+            }
+        })
+        .collect();
+
+    let mut image = Image::new(
+        Extent3d {
+            width: grid.dims.x,
+            height: grid.dims.y,
+            depth_or_array_layers: grid.dims.z,
+        },
+        TextureDimension::D3,
+        data,
+        TextureFormat::Rg8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::linear();
+    image
+}
+
+fn transfer_image(table: &ColorTable, range: (f32, f32)) -> Image {
+    let data = table.sample(range, TRANSFER_SIZE).iter().flat_map(|c| c.as_rgba_u8()).collect();
+    let mut image = Image::new(
+        Extent3d {
+            width: TRANSFER_SIZE as u32,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+    );
+    image.sampler = ImageSampler::linear();
+    image
+}